@@ -0,0 +1,7 @@
+fn main() {
+  capnpc::CompilerCommand::new()
+    .src_prefix("schema")
+    .file("schema/user_details.capnp")
+    .run()
+    .expect("Failed to compile schema/user_details.capnp, is the capnp tool installed?");
+}
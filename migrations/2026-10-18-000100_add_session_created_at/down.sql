@@ -0,0 +1,3 @@
+DROP INDEX sessions_created_at_idx;
+DROP INDEX sessions_last_used_idx;
+ALTER TABLE sessions DROP COLUMN created_at;
@@ -0,0 +1,3 @@
+ALTER TABLE sessions ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
+CREATE INDEX sessions_last_used_idx ON sessions (last_used);
+CREATE INDEX sessions_created_at_idx ON sessions (created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE sessions ADD COLUMN id UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
@@ -0,0 +1,3 @@
+ALTER TABLE sessions DROP COLUMN device_label;
+ALTER TABLE sessions DROP COLUMN user_agent;
+ALTER TABLE sessions DROP COLUMN ip;
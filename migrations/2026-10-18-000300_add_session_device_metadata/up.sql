@@ -0,0 +1,3 @@
+ALTER TABLE sessions ADD COLUMN ip VARCHAR(45);
+ALTER TABLE sessions ADD COLUMN user_agent VARCHAR(512);
+ALTER TABLE sessions ADD COLUMN device_label VARCHAR(100);
@@ -0,0 +1,10 @@
+-- Raw tokens cannot be recovered from their hashes, so every session is dropped.
+DELETE FROM sessions;
+
+ALTER TABLE sessions ADD COLUMN token UUID NOT NULL;
+ALTER TABLE sessions DROP CONSTRAINT sessions_pkey;
+ALTER TABLE sessions ADD PRIMARY KEY (token);
+ALTER TABLE sessions ADD CONSTRAINT sessions_id_key UNIQUE (id);
+
+DROP INDEX sessions_token_hash_idx;
+ALTER TABLE sessions DROP COLUMN token_hash;
@@ -0,0 +1,14 @@
+-- Session tokens are stored as a hex encoded HMAC-SHA256 keyed with SESSION_TOKEN_KEY.
+-- Existing rows are rehashed in place, so the key must be passed to the migration, e.g.
+-- PGOPTIONS="-c ipv8.session_token_key=$SESSION_TOKEN_KEY" diesel migration run
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE sessions ADD COLUMN token_hash VARCHAR(64);
+UPDATE sessions SET token_hash = encode(hmac(token::text, current_setting('ipv8.session_token_key'), 'sha256'), 'hex');
+ALTER TABLE sessions ALTER COLUMN token_hash SET NOT NULL;
+CREATE UNIQUE INDEX sessions_token_hash_idx ON sessions (token_hash);
+
+ALTER TABLE sessions DROP CONSTRAINT sessions_pkey;
+ALTER TABLE sessions DROP CONSTRAINT sessions_id_key;
+ALTER TABLE sessions ADD PRIMARY KEY (id);
+ALTER TABLE sessions DROP COLUMN token;
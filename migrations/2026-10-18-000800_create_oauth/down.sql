@@ -0,0 +1,5 @@
+DELETE FROM sessions WHERE oauth_client_id IS NOT NULL;
+ALTER TABLE sessions DROP COLUMN oauth_client_id;
+ALTER TABLE sessions DROP COLUMN scopes;
+DROP TABLE oauth_authorization_codes;
+DROP TABLE oauth_clients;
@@ -0,0 +1,27 @@
+CREATE TABLE oauth_clients (
+  id UUID PRIMARY KEY,
+  owner_id UUID NOT NULL,
+  name VARCHAR(100) NOT NULL,
+  secret_hash VARCHAR(64),
+  redirect_uris TEXT[] NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (owner_id) REFERENCES users (id)
+);
+CREATE INDEX oauth_clients_owner_id_idx ON oauth_clients (owner_id);
+
+CREATE TABLE oauth_authorization_codes (
+  code_hash VARCHAR(64) PRIMARY KEY,
+  client_id UUID NOT NULL,
+  user_id UUID NOT NULL,
+  redirect_uri TEXT NOT NULL,
+  scopes TEXT[] NOT NULL,
+  code_challenge VARCHAR(128) NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (client_id) REFERENCES oauth_clients (id) ON DELETE CASCADE,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+-- Tokens issued to OAuth clients are sessions limited to the granted scopes.
+ALTER TABLE sessions ADD COLUMN scopes TEXT[];
+ALTER TABLE sessions ADD COLUMN oauth_client_id UUID REFERENCES oauth_clients (id) ON DELETE CASCADE;
@@ -0,0 +1,2 @@
+-- OpenID Connect clients bind an id_token to their request with a nonce.
+ALTER TABLE oauth_authorization_codes ADD COLUMN nonce VARCHAR(255);
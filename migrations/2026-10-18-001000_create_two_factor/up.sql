@@ -0,0 +1,29 @@
+-- The TOTP secret has to be readable to check codes, so unlike tokens it is
+-- stored as is. A row without confirmed_at is an enrollment in progress.
+CREATE TABLE totp_credentials (
+  user_id UUID PRIMARY KEY,
+  secret VARCHAR(64) NOT NULL,
+  confirmed_at TIMESTAMP,
+  last_used_step BIGINT,
+  created_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_codes (
+  code_hash VARCHAR(64) PRIMARY KEY,
+  user_id UUID NOT NULL,
+  used_at TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+-- Issued by /login when a second factor is still required.
+CREATE TABLE login_challenges (
+  token_hash VARCHAR(64) PRIMARY KEY,
+  user_id UUID NOT NULL,
+  device_label VARCHAR(100),
+  attempts INTEGER NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
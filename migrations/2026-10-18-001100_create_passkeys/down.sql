@@ -0,0 +1,5 @@
+DROP TABLE webauthn_challenges;
+DROP TABLE passkeys;
+-- Passwordless accounts cannot be represented without passkeys.
+DELETE FROM users WHERE password_digest IS NULL;
+ALTER TABLE users ALTER COLUMN password_digest SET NOT NULL;
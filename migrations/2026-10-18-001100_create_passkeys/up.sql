@@ -0,0 +1,26 @@
+-- Accounts that only use passkeys have no password.
+ALTER TABLE users ALTER COLUMN password_digest DROP NOT NULL;
+
+CREATE TABLE passkeys (
+  id UUID PRIMARY KEY,
+  user_id UUID NOT NULL,
+  credential_id BYTEA NOT NULL UNIQUE,
+  public_key BYTEA NOT NULL,
+  sign_count BIGINT NOT NULL,
+  name VARCHAR(100) NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  last_used TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
+
+-- Pending registration and authentication ceremonies. Authentication without
+-- a username has no user until the passkey is presented.
+CREATE TABLE webauthn_challenges (
+  id UUID PRIMARY KEY,
+  user_id UUID,
+  challenge VARCHAR(64) NOT NULL,
+  purpose VARCHAR(16) NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
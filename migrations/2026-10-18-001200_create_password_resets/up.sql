@@ -0,0 +1,9 @@
+-- Outstanding reset tokens, deleted once used.
+CREATE TABLE password_resets (
+  token_hash VARCHAR(64) PRIMARY KEY,
+  user_id UUID NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX password_resets_user_id_idx ON password_resets (user_id);
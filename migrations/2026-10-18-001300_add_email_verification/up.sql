@@ -0,0 +1,16 @@
+ALTER TABLE users ADD COLUMN email VARCHAR(254);
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMP;
+-- Emails double as login names, so they have to be unique
+CREATE UNIQUE INDEX users_email_idx ON users (LOWER(email));
+
+-- Tokens mailed to prove ownership of an address. The address is kept so
+-- a token cannot verify an email the account has since changed to.
+CREATE TABLE email_verifications (
+  token_hash VARCHAR(64) PRIMARY KEY,
+  user_id UUID NOT NULL,
+  email VARCHAR(254) NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+CREATE INDEX email_verifications_user_id_idx ON email_verifications (user_id);
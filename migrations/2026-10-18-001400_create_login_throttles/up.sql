@@ -0,0 +1,9 @@
+-- Failed logins per login name (`login:<hashed name>`), account (`user:<id>`),
+-- second factor (`2fa:<id>`) and client address (`ip:<addr>`).
+-- Rows with `locked_until` in the future are current lockouts.
+CREATE TABLE login_throttles (
+  subject VARCHAR(64) PRIMARY KEY,
+  failed_attempts INTEGER NOT NULL,
+  last_failed_at TIMESTAMP NOT NULL,
+  locked_until TIMESTAMP
+);
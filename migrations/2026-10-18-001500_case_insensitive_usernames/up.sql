@@ -0,0 +1,19 @@
+-- Usernames differing only in case would be indistinguishable to players.
+-- Refuse to migrate while such accounts exist, as picking which of them keeps
+-- its name is up to whoever runs the server.
+DO $$
+DECLARE
+  duplicates TEXT;
+BEGIN
+  SELECT string_agg(names, '; ') INTO duplicates FROM (
+    SELECT string_agg(username, ', ' ORDER BY username) AS names
+    FROM users
+    GROUP BY LOWER(username)
+    HAVING COUNT(*) > 1
+  ) AS groups;
+  IF duplicates IS NOT NULL THEN
+    RAISE EXCEPTION 'Usernames must be unique regardless of case. Rename all but one account in each of these groups before migrating: %', duplicates;
+  END IF;
+END $$;
+
+CREATE UNIQUE INDEX users_username_lower_idx ON users (LOWER(username));
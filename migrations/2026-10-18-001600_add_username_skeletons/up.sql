@@ -0,0 +1,8 @@
+ALTER TABLE users ADD COLUMN username_skeleton VARCHAR;
+-- Existing usernames are ASCII letters and digits, so only the ASCII part of
+-- username_skeleton in src/policy.rs applies to them
+UPDATE users SET username_skeleton =
+  REPLACE(REPLACE(REPLACE(TRANSLATE(LOWER(username), '01i25', 'ollzs'), 'rn', 'm'), 'vv', 'w'), 'cl', 'd');
+ALTER TABLE users ALTER COLUMN username_skeleton SET NOT NULL;
+-- Not unique, earlier accounts may already look alike
+CREATE INDEX users_username_skeleton_idx ON users (username_skeleton);
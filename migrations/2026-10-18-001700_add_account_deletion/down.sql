@@ -0,0 +1,13 @@
+ALTER TABLE oauth_clients DROP CONSTRAINT oauth_clients_owner_id_fkey,
+  ADD CONSTRAINT oauth_clients_owner_id_fkey FOREIGN KEY (owner_id) REFERENCES users (id);
+ALTER TABLE api_keys DROP CONSTRAINT api_keys_user_id_fkey,
+  ADD CONSTRAINT api_keys_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+ALTER TABLE refresh_tokens DROP CONSTRAINT refresh_tokens_user_id_fkey,
+  ADD CONSTRAINT refresh_tokens_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+ALTER TABLE games DROP CONSTRAINT games_user_id_fkey,
+  ADD CONSTRAINT games_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+ALTER TABLE sessions DROP CONSTRAINT sessions_user_id_fkey,
+  ADD CONSTRAINT sessions_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+
+DROP INDEX users_deletion_due_at_idx;
+ALTER TABLE users DROP COLUMN deletion_due_at;
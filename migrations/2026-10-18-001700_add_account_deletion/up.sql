@@ -0,0 +1,15 @@
+-- Accounts are deleted once this passes, until then the owner may cancel
+ALTER TABLE users ADD COLUMN deletion_due_at TIMESTAMP;
+CREATE INDEX users_deletion_due_at_idx ON users (deletion_due_at) WHERE deletion_due_at IS NOT NULL;
+
+-- Deleting an account removes everything that belongs to it
+ALTER TABLE sessions DROP CONSTRAINT sessions_user_id_fkey,
+  ADD CONSTRAINT sessions_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE games DROP CONSTRAINT games_user_id_fkey,
+  ADD CONSTRAINT games_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE refresh_tokens DROP CONSTRAINT refresh_tokens_user_id_fkey,
+  ADD CONSTRAINT refresh_tokens_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE api_keys DROP CONSTRAINT api_keys_user_id_fkey,
+  ADD CONSTRAINT api_keys_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE oauth_clients DROP CONSTRAINT oauth_clients_owner_id_fkey,
+  ADD CONSTRAINT oauth_clients_owner_id_fkey FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE;
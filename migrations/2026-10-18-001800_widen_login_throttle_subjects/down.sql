@@ -0,0 +1,2 @@
+DELETE FROM login_throttles WHERE length(subject) > 64;
+ALTER TABLE login_throttles ALTER COLUMN subject TYPE VARCHAR(64);
@@ -0,0 +1,2 @@
+-- Login name subjects hold a 64 character hash after their prefix
+ALTER TABLE login_throttles ALTER COLUMN subject TYPE VARCHAR(80);
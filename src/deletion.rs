@@ -0,0 +1,59 @@
+use chrono::{Duration, NaiveDateTime};
+use diesel::result::Error;
+use diesel::{Connection, PgConnection};
+use tokio::sync::broadcast;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::lockout::{account_subject, second_factor_subject, LOCKOUT_POLICY};
+use crate::models::delete_due_users;
+use crate::util::parse_env;
+
+const DEFAULT_GRACE_PERIOD_SECS: i64 = 60 * 60 * 24 * 30;
+
+lazy_static::lazy_static! {
+  pub static ref DELETION_POLICY: DeletionPolicy = DeletionPolicy::from_env();
+  /// Ids of purged accounts, passed on to `UserDetails` RPC subscribers.
+  pub static ref DELETED_ACCOUNTS: broadcast::Sender<Uuid> = broadcast::channel(64).0;
+}
+
+/// Accounts whose owner asked to delete them are kept for `grace_period`, so
+/// the owner can change their mind, then purged by the session reaper along
+/// with their sessions, game and everything else that belongs to them.
+pub struct DeletionPolicy {
+  pub grace_period: Duration,
+}
+
+impl DeletionPolicy {
+  /// Reads `ACCOUNT_DELETION_GRACE_PERIOD` in seconds.
+  pub fn from_env() -> Self {
+    Self {
+      grace_period: Duration::seconds(parse_env("ACCOUNT_DELETION_GRACE_PERIOD", DEFAULT_GRACE_PERIOD_SECS)),
+    }
+  }
+
+  /// When an account asked to be deleted at `now` goes away.
+  pub fn due_at(&self, now: NaiveDateTime) -> NaiveDateTime {
+    now + self.grace_period
+  }
+
+  /// Deletes every account whose grace period is over, returning how many.
+  pub fn purge_due_accounts(&self, conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+    let uids = conn.transaction::<_, Error, _>(|| {
+      let uids = delete_due_users(conn, now)?;
+      for uid in &uids {
+        // Throttles name their account instead of referencing it
+        LOCKOUT_POLICY.clear_failures(conn, &account_subject(*uid))?;
+        LOCKOUT_POLICY.clear_failures(conn, &second_factor_subject(*uid))?;
+      }
+      Ok(uids)
+    })?;
+
+    for uid in &uids {
+      info!("Deleted account {}", uid);
+      // Fails only when nobody is subscribed
+      let _ = DELETED_ACCOUNTS.send(*uid);
+    }
+    Ok(uids.len())
+  }
+}
@@ -0,0 +1,375 @@
+use std::env;
+use std::fmt::Display;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+use url::Url;
+
+use crate::models::{Session, User};
+
+const DEFAULT_ACCESS_TOKEN_TTL_SECS: i64 = 60 * 15;
+const DEFAULT_ID_TOKEN_TTL_SECS: i64 = 60 * 60;
+/// Signs tokens in tests when no key is configured. Public, so never use it
+/// anywhere else.
+#[cfg(test)]
+const TEST_SIGNING_KEY: &str = "7e577e577e577e577e577e577e577e577e577e577e577e577e577e577e577e57";
+/// Issuer in tests when `EXTERNAL_URL` is not configured.
+#[cfg(test)]
+const TEST_ISSUER: &str = "https://auth.test";
+
+lazy_static::lazy_static! {
+  /// Present when `ACCESS_TOKEN_SIGNING_KEY` is configured. Without it no
+  /// signed tokens are issued and the JWKS document is empty.
+  pub static ref ACCESS_TOKENS: Option<TokenSigner> = TokenSigner::from_env();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JwtError {
+  Malformed,
+  BadSignature,
+  WrongIssuer,
+  Expired,
+}
+
+impl Display for JwtError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      JwtError::Malformed => write!(f, "Malformed token"),
+      JwtError::BadSignature => write!(f, "Invalid token signature"),
+      JwtError::WrongIssuer => write!(f, "Token was issued by another server"),
+      JwtError::Expired => write!(f, "Token expired"),
+    }
+  }
+}
+
+/// Claims carried by a signed access token. Game servers can trust these
+/// without calling back into ipv8-auth until `exp`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccessClaims {
+  pub iss: String,
+  pub sub: String,
+  pub username: String,
+  pub licensed: bool,
+  pub sid: String,
+  pub iat: i64,
+  pub exp: i64,
+}
+
+/// Claims of an OpenID Connect `id_token`, addressed to the client in `aud`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdClaims {
+  pub iss: String,
+  pub sub: String,
+  pub aud: String,
+  pub iat: i64,
+  pub exp: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub nonce: Option<String>,
+  pub name: String,
+  pub preferred_username: String,
+  pub licensed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+  alg: String,
+  typ: String,
+  kid: String,
+}
+
+#[derive(Deserialize)]
+struct RegisteredClaims {
+  iss: String,
+  exp: i64,
+}
+
+/// Public half of the signing key in RFC 8037 form.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Jwk {
+  pub kty: String,
+  pub crv: String,
+  pub alg: String,
+  #[serde(rename = "use")]
+  pub key_use: String,
+  pub kid: String,
+  pub x: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JwkSet {
+  pub keys: Vec<Jwk>,
+}
+
+pub struct TokenSigner {
+  key: SigningKey,
+  kid: String,
+  pub issuer: String,
+  pub ttl: chrono::Duration,
+  /// Lifetime of `id_token`s, which are checked once by the client when
+  /// signing a user in rather than presented on every request.
+  pub id_token_ttl: chrono::Duration,
+}
+
+fn b64(data: &[u8]) -> String {
+  base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn b64_decode(data: &str) -> Result<Vec<u8>, JwtError> {
+  base64::decode_config(data, base64::URL_SAFE_NO_PAD).map_err(|_| JwtError::Malformed)
+}
+
+/// OpenID Connect issuers must be https URLs without a query or fragment,
+/// clients compare them to the `iss` of every token.
+pub fn check_issuer(issuer: &str) -> Result<(), String> {
+  let url = Url::parse(issuer).map_err(|err| format!("must be a URL: {}", err))?;
+  if url.scheme() != "https" {
+    return Err("must be an https URL".to_string());
+  }
+  if url.host().is_none() || url.query().is_some() || url.fragment().is_some() {
+    return Err("must have a host and no query or fragment".to_string());
+  }
+  Ok(())
+}
+
+impl TokenSigner {
+  /// Reads a hex encoded 32 byte Ed25519 seed from `ACCESS_TOKEN_SIGNING_KEY`
+  /// and the token lifetimes in seconds from `ACCESS_TOKEN_TTL` and
+  /// `ID_TOKEN_TTL`. An empty key counts as unset. With a key, `EXTERNAL_URL`
+  /// must be set to the https URL tokens are issued from.
+  fn from_env() -> Option<Self> {
+    let seed = env::var("ACCESS_TOKEN_SIGNING_KEY")
+      .ok()
+      .filter(|seed| !seed.trim().is_empty());
+    #[cfg(test)]
+    let seed = seed.or_else(|| Some(TEST_SIGNING_KEY.to_string()));
+    let seed = seed?;
+    let seed: [u8; 32] = hex::decode(seed.trim())
+      .ok()
+      .and_then(|bytes| bytes.try_into().ok())
+      .expect("ACCESS_TOKEN_SIGNING_KEY must be 32 hex encoded bytes");
+    let ttl = env::var("ACCESS_TOKEN_TTL")
+      .ok()
+      .map(|secs| secs.parse().expect("Failed to parse ACCESS_TOKEN_TTL as seconds"))
+      .unwrap_or(DEFAULT_ACCESS_TOKEN_TTL_SECS);
+    let id_token_ttl = env::var("ID_TOKEN_TTL")
+      .ok()
+      .map(|secs| secs.parse().expect("Failed to parse ID_TOKEN_TTL as seconds"))
+      .unwrap_or(DEFAULT_ID_TOKEN_TTL_SECS);
+    let issuer = env::var("EXTERNAL_URL").ok().filter(|url| !url.trim().is_empty());
+    #[cfg(test)]
+    let issuer = issuer.or_else(|| Some(TEST_ISSUER.to_string()));
+    let issuer = issuer.expect("EXTERNAL_URL must be set when ACCESS_TOKEN_SIGNING_KEY is set");
+    if let Err(err) = check_issuer(&issuer) {
+      panic!("EXTERNAL_URL {}", err);
+    }
+
+    let mut signer = Self::new(
+      SigningKey::from_bytes(&seed),
+      issuer,
+      chrono::Duration::seconds(ttl),
+    );
+    signer.id_token_ttl = chrono::Duration::seconds(id_token_ttl);
+    info!("Issuing signed access tokens with key {}", signer.kid);
+    Some(signer)
+  }
+
+  pub fn new(key: SigningKey, issuer: String, ttl: chrono::Duration) -> Self {
+    let kid = Self::thumbprint(&key.verifying_key());
+    Self {
+      key,
+      kid,
+      issuer,
+      ttl,
+      id_token_ttl: chrono::Duration::seconds(DEFAULT_ID_TOKEN_TTL_SECS),
+    }
+  }
+
+  /// RFC 7638 thumbprint of the public key, used as the `kid`.
+  fn thumbprint(key: &VerifyingKey) -> String {
+    let canonical = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, b64(key.as_bytes()));
+    b64(&Sha256::digest(canonical.as_bytes()))
+  }
+
+  pub fn jwk(&self) -> Jwk {
+    Jwk {
+      kty: "OKP".to_string(),
+      crv: "Ed25519".to_string(),
+      alg: "EdDSA".to_string(),
+      key_use: "sig".to_string(),
+      kid: self.kid.clone(),
+      x: b64(self.key.verifying_key().as_bytes()),
+    }
+  }
+
+  pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+    let header = Header {
+      alg: "EdDSA".to_string(),
+      typ: "JWT".to_string(),
+      kid: self.kid.clone(),
+    };
+    let signing_input = format!(
+      "{}.{}",
+      b64(&serde_json::to_vec(&header).unwrap()),
+      b64(&serde_json::to_vec(claims).unwrap())
+    );
+    let signature = self.key.sign(signing_input.as_bytes());
+    format!("{}.{}", signing_input, b64(&signature.to_bytes()))
+  }
+
+  /// Checks the signature, issuer and expiry of `token` before decoding its
+  /// claims.
+  pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, JwtError> {
+    let mut parts = token.split('.');
+    let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+      (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+      _ => return Err(JwtError::Malformed),
+    };
+
+    let parsed_header: Header = serde_json::from_slice(&b64_decode(header)?).map_err(|_| JwtError::Malformed)?;
+    if parsed_header.alg != "EdDSA" || parsed_header.kid != self.kid {
+      return Err(JwtError::BadSignature);
+    }
+
+    let signature = Signature::from_slice(&b64_decode(signature)?).map_err(|_| JwtError::Malformed)?;
+    self
+      .key
+      .verifying_key()
+      .verify_strict(format!("{}.{}", header, claims).as_bytes(), &signature)
+      .map_err(|_| JwtError::BadSignature)?;
+
+    let claims = b64_decode(claims)?;
+    let registered: RegisteredClaims = serde_json::from_slice(&claims).map_err(|_| JwtError::Malformed)?;
+    if registered.iss != self.issuer {
+      return Err(JwtError::WrongIssuer);
+    }
+    if registered.exp <= chrono::Utc::now().timestamp() {
+      return Err(JwtError::Expired);
+    }
+
+    serde_json::from_slice(&claims).map_err(|_| JwtError::Malformed)
+  }
+
+  /// Issues an access token for `session`, returning it with its lifetime in
+  /// seconds.
+  pub fn issue_access_token(&self, user: &User, session: &Session) -> (String, i64) {
+    let now = chrono::Utc::now().timestamp();
+    let claims = AccessClaims {
+      iss: self.issuer.clone(),
+      sub: user.id.to_string(),
+      username: user.username.clone(),
+      licensed: user.is_licensed(),
+      sid: session.id.to_string(),
+      iat: now,
+      exp: now + self.ttl.num_seconds(),
+    };
+    (self.sign(&claims), self.ttl.num_seconds())
+  }
+
+  /// Issues an `id_token` asserting `user`'s identity to an OpenID Connect
+  /// client.
+  pub fn issue_id_token(&self, user: &User, client_id: &str, nonce: Option<String>) -> String {
+    let now = chrono::Utc::now().timestamp();
+    self.sign(&IdClaims {
+      iss: self.issuer.clone(),
+      sub: user.id.to_string(),
+      aud: client_id.to_string(),
+      iat: now,
+      exp: now + self.id_token_ttl.num_seconds(),
+      nonce,
+      name: user.name.clone(),
+      preferred_username: user.username.clone(),
+      licensed: user.is_licensed(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use ed25519_dalek::SigningKey;
+  use serde::Serialize;
+
+  use super::{check_issuer, AccessClaims, JwtError, TokenSigner};
+
+  fn signer(seed: u8) -> TokenSigner {
+    TokenSigner::new(
+      SigningKey::from_bytes(&[seed; 32]),
+      "https://auth.example".to_string(),
+      chrono::Duration::minutes(5),
+    )
+  }
+
+  fn claims(exp_offset: i64) -> AccessClaims {
+    let now = chrono::Utc::now().timestamp();
+    AccessClaims {
+      iss: "https://auth.example".to_string(),
+      sub: "user".to_string(),
+      username: "tester".to_string(),
+      licensed: false,
+      sid: "session".to_string(),
+      iat: now,
+      exp: now + exp_offset,
+    }
+  }
+
+  #[test]
+  fn round_trips_claims() {
+    let signer = signer(1);
+    let token = signer.sign(&claims(60));
+    let decoded: AccessClaims = signer.verify(&token).unwrap();
+    assert_eq!(decoded.username, "tester");
+  }
+
+  #[test]
+  fn rejects_expired_token() {
+    let signer = signer(1);
+    let token = signer.sign(&claims(-1));
+    assert_eq!(signer.verify::<AccessClaims>(&token).unwrap_err(), JwtError::Expired);
+  }
+
+  #[test]
+  fn rejects_other_key_and_tampering() {
+    let token = signer(1).sign(&claims(60));
+    assert_eq!(
+      signer(2).verify::<AccessClaims>(&token).unwrap_err(),
+      JwtError::BadSignature
+    );
+
+    #[derive(Serialize)]
+    struct Forged {
+      iss: String,
+      exp: i64,
+      username: &'static str,
+    }
+    let forged = signer(2).sign(&Forged {
+      iss: "https://auth.example".to_string(),
+      exp: claims(60).exp,
+      username: "admin",
+    });
+    let mut parts: Vec<&str> = token.split('.').collect();
+    parts[1] = forged.split('.').nth(1).unwrap();
+    assert_eq!(
+      signer(1).verify::<AccessClaims>(&parts.join(".")).unwrap_err(),
+      JwtError::BadSignature
+    );
+  }
+
+  #[test]
+  fn rejects_garbage() {
+    assert_eq!(
+      signer(1).verify::<AccessClaims>("not.a.jwt.at.all").unwrap_err(),
+      JwtError::Malformed
+    );
+  }
+
+  #[test]
+  fn issuer_must_be_an_https_url() {
+    assert!(check_issuer("https://auth.example").is_ok());
+    assert!(check_issuer("https://auth.example/ipv8/").is_ok());
+    assert!(check_issuer("127.0.0.1:3000").is_err());
+    assert!(check_issuer("http://auth.example").is_err());
+    assert!(check_issuer("https://auth.example/?tenant=1").is_err());
+  }
+}
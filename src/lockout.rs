@@ -0,0 +1,174 @@
+use std::net::IpAddr;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::result::Error;
+use diesel::PgConnection;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::models::{delete_login_throttle, find_login_throttle, save_login_throttle, LoginThrottle};
+use crate::tokens::hash_token;
+use crate::util::parse_env;
+
+const DEFAULT_MAX_FAILURES: i32 = 5;
+const DEFAULT_MAX_FAILURES_PER_IP: i32 = 20;
+const DEFAULT_LOCKOUT_SECS: i64 = 30;
+const DEFAULT_MAX_LOCKOUT_SECS: i64 = 60 * 60;
+const DEFAULT_FAILURE_WINDOW_SECS: i64 = 60 * 60;
+
+lazy_static::lazy_static! {
+  pub static ref LOCKOUT_POLICY: LockoutPolicy = LockoutPolicy::from_env();
+}
+
+/// Temporary lockouts after repeated failed logins, tracked per login name,
+/// account and client address in the `login_throttles` table.
+///
+/// Once a subject reaches its failure limit it is locked for `lockout`, and
+/// every further failure doubles that up to `max_lockout`. Failures are
+/// forgotten after a successful login to the account, or once none happened
+/// for `failure_window`.
+pub struct LockoutPolicy {
+  pub max_failures: i32,
+  pub max_failures_per_ip: i32,
+  pub lockout: Duration,
+  pub max_lockout: Duration,
+  pub failure_window: Duration,
+}
+
+impl LockoutPolicy {
+  /// Reads `LOGIN_MAX_FAILURES`, `LOGIN_MAX_FAILURES_PER_IP`,
+  /// `LOGIN_LOCKOUT`, `LOGIN_MAX_LOCKOUT` and `LOGIN_FAILURE_WINDOW`, the
+  /// latter three in seconds.
+  pub fn from_env() -> Self {
+    Self {
+      max_failures: parse_env("LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILURES),
+      max_failures_per_ip: parse_env("LOGIN_MAX_FAILURES_PER_IP", DEFAULT_MAX_FAILURES_PER_IP),
+      lockout: Duration::seconds(parse_env("LOGIN_LOCKOUT", DEFAULT_LOCKOUT_SECS)),
+      max_lockout: Duration::seconds(parse_env("LOGIN_MAX_LOCKOUT", DEFAULT_MAX_LOCKOUT_SECS)),
+      failure_window: Duration::seconds(parse_env("LOGIN_FAILURE_WINDOW", DEFAULT_FAILURE_WINDOW_SECS)),
+    }
+  }
+
+  /// How long to lock a subject out after `failures` failures in a row.
+  pub fn lockout_after(&self, failures: i32, max_failures: i32) -> Option<Duration> {
+    if failures < max_failures {
+      return None;
+    }
+
+    // Cap the exponent, the lockout reaches its maximum long before this
+    let doublings = (failures - max_failures).min(30) as u32;
+    let lockout = self
+      .lockout
+      .num_seconds()
+      .checked_mul(2_i64.pow(doublings))
+      .map(Duration::seconds)
+      .unwrap_or(self.max_lockout);
+    Some(lockout.min(self.max_lockout))
+  }
+
+  /// Time left on the longest lockout among `subjects`.
+  pub fn locked_for(
+    &self,
+    conn: &PgConnection,
+    subjects: &[String],
+    now: NaiveDateTime,
+  ) -> Result<Option<Duration>, Error> {
+    let mut longest = None;
+    for subject in subjects {
+      if let Some(LoginThrottle {
+        locked_until: Some(locked_until),
+        ..
+      }) = find_login_throttle(conn, subject)?
+      {
+        if locked_until > now {
+          longest = longest.max(Some(locked_until - now));
+        }
+      }
+    }
+    Ok(longest)
+  }
+
+  /// Counts a failed login against `subject`, locking it out once it reaches
+  /// `max_failures`.
+  pub fn record_failure(
+    &self,
+    conn: &PgConnection,
+    subject: &str,
+    max_failures: i32,
+    now: NaiveDateTime,
+  ) -> Result<(), Error> {
+    let failed_attempts = match find_login_throttle(conn, subject)? {
+      Some(throttle) if throttle.last_failed_at > now - self.failure_window => throttle.failed_attempts + 1,
+      _ => 1,
+    };
+    let lockout = self.lockout_after(failed_attempts, max_failures);
+    if let Some(lockout) = lockout {
+      warn!(
+        "Locked {} out of login for {}s after {} failed attempts",
+        subject,
+        lockout.num_seconds(),
+        failed_attempts
+      );
+    }
+
+    save_login_throttle(
+      conn,
+      &LoginThrottle {
+        subject: subject.to_string(),
+        failed_attempts,
+        last_failed_at: now,
+        locked_until: lockout.map(|lockout| now + lockout),
+      },
+    )
+  }
+
+  pub fn clear_failures(&self, conn: &PgConnection, subject: &str) -> Result<(), Error> {
+    delete_login_throttle(conn, subject)
+  }
+}
+
+/// Password failures are counted per login name, whether or not an account
+/// goes by it, so a lockout does not reveal which names exist. The name is
+/// hashed so logins of any length fit the subject column.
+pub fn login_subject(login: &str) -> String {
+  format!("login:{}", hash_token(&login.to_lowercase()))
+}
+
+pub fn account_subject(uid: Uuid) -> String {
+  format!("user:{}", uid)
+}
+
+/// Failed second factors are counted apart from failed passwords, since the
+/// right password clears those.
+pub fn second_factor_subject(uid: Uuid) -> String {
+  format!("2fa:{}", uid)
+}
+
+pub fn ip_subject(ip: IpAddr) -> String {
+  format!("ip:{}", ip)
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::Duration;
+
+  use super::LockoutPolicy;
+
+  #[test]
+  fn lockout_doubles_up_to_the_maximum() {
+    let policy = LockoutPolicy {
+      max_failures: 3,
+      max_failures_per_ip: 10,
+      lockout: Duration::seconds(30),
+      max_lockout: Duration::seconds(100),
+      failure_window: Duration::hours(1),
+    };
+
+    assert_eq!(policy.lockout_after(2, 3), None);
+    assert_eq!(policy.lockout_after(3, 3), Some(Duration::seconds(30)));
+    assert_eq!(policy.lockout_after(4, 3), Some(Duration::seconds(60)));
+    assert_eq!(policy.lockout_after(5, 3), Some(Duration::seconds(100)));
+    assert_eq!(policy.lockout_after(500, 3), Some(Duration::seconds(100)));
+    assert_eq!(policy.lockout_after(9, 10), None);
+  }
+}
@@ -6,22 +6,42 @@ use std::env;
 use std::net::SocketAddr;
 
 use dotenv::dotenv;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::Server;
+use hyper::{Body, Request, Server};
 use tracing::info;
 
 use crate::routes::handle_requests;
+use crate::routes::util::ClientAddr;
 use crate::rpc::start_rpc_server;
+use crate::session::start_session_reaper;
 use crate::util::get_server_url;
 
+pub mod deletion;
 pub mod game;
+pub mod jwt;
+pub mod lockout;
+pub mod mailer;
 pub mod models;
+pub mod password;
+pub mod policy;
+pub mod rate_limit;
 pub mod router;
 pub mod routes;
 pub mod schema;
+pub mod scope;
+pub mod session;
+pub mod tokens;
+pub mod totp;
 pub mod util;
+pub mod webauthn;
 pub mod rpc;
 
+#[allow(clippy::all, dead_code)]
+pub mod user_details_capnp {
+  include!(concat!(env!("OUT_DIR"), "/user_details_capnp.rs"));
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
 async fn main() {
   tracing_subscriber::fmt::init();
@@ -29,13 +49,26 @@ async fn main() {
   if dotenv().is_ok() {
     info!("Loaded variables from .env");
   }
+  // Check the signing configuration now rather than on the first login
+  lazy_static::initialize(&jwt::ACCESS_TOKENS);
   let addr: SocketAddr = get_server_url()
     .parse()
     .unwrap_or_else(|_| panic!("Failed to parse server address. Found {}", get_server_url()));
 
   let rpc_addr = env::var("RPC_ADDR").expect("Failed to get variable RPC_ADDR");
+  let details_rpc_addr = env::var("DETAILS_RPC_ADDR")
+    .ok()
+    .map(|addr| addr.parse().expect("Failed to parse DETAILS_RPC_ADDR"));
 
-  let svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_requests)) });
+  let svc = make_service_fn(|conn: &AddrStream| {
+    let client_addr = ClientAddr(conn.remote_addr());
+    async move {
+      Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+        req.extensions_mut().insert(client_addr);
+        handle_requests(req)
+      }))
+    }
+  });
   let server = Server::bind(&addr).serve(svc);
 
   let graceful =
@@ -43,9 +76,11 @@ async fn main() {
 
   info!("Listening on http://{}", addr);
 
+  tokio::spawn(start_session_reaper());
+
   tokio::select! {
     _ = graceful => {},
-    _ = start_rpc_server(rpc_addr.parse().unwrap()) => {}
+    _ = start_rpc_server(rpc_addr.parse().unwrap(), details_rpc_addr) => {}
   }
   
   info!("Exiting...");
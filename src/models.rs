@@ -1,13 +1,17 @@
-use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHasher};
 use chrono::NaiveDateTime;
 use diesel::result::Error;
-use diesel::{ExpressionMethods, Insertable, PgConnection, QueryDsl, RunQueryDsl};
-use rand_core::OsRng;
+use diesel::{
+  BoolExpressionMethods, ExpressionMethods, Insertable, NullableExpressionMethods, OptionalExtension, PgConnection,
+  QueryDsl, RunQueryDsl,
+};
 use tracing::debug;
 use uuid::Uuid;
 
 use super::schema::*;
+use crate::password::{hash_password, verify_dummy_password, verify_password};
+use crate::policy::username_skeleton;
+use crate::tokens::{generate_api_key, generate_recovery_code, generate_secret, hash_token, normalize_recovery_code};
+use crate::webauthn::{generate_challenge, RegisteredCredential};
 
 #[derive(Identifiable, Insertable, Queryable, Clone)]
 #[table_name = "users"]
@@ -15,37 +19,198 @@ pub struct User {
   pub id: Uuid,
   pub name: String,
   pub username: String,
-  pub password_digest: String,
+  /// Unset for accounts that only sign in with passkeys.
+  pub password_digest: Option<String>,
   pub license_game_stage: i32,
+  pub email: Option<String>,
+  pub email_verified_at: Option<NaiveDateTime>,
+  /// See [`username_skeleton`].
+  pub username_skeleton: String,
+  /// When the account is deleted, set while its owner may still cancel.
+  pub deletion_due_at: Option<NaiveDateTime>,
 }
 
 impl User {
   pub fn is_licensed(&self) -> bool {
     self.license_game_stage >= 150
   }
+
+  pub fn is_email_verified(&self) -> bool {
+    self.email.is_some() && self.email_verified_at.is_some()
+  }
+
+  pub fn is_pending_deletion(&self) -> bool {
+    self.deletion_due_at.is_some()
+  }
+
+  pub fn verify_password(&self, password: &str) -> bool {
+    match &self.password_digest {
+      Some(password_digest) => verify_password(password_digest, password),
+      None => verify_dummy_password(password),
+    }
+  }
 }
 
 pub fn create_user<'a>(
   conn: &PgConnection,
   name: String,
   username: String,
+  email: Option<String>,
   password: String,
   license_game_stage: i32,
 ) -> Result<User, Error> {
-  let salt = SaltString::generate(&mut OsRng);
-  let argon2 = Argon2::default();
-  let password_digest = argon2.hash_password(password.as_bytes(), &salt).unwrap();
   let new_user = User {
     id: Uuid::new_v4(),
     name,
+    username_skeleton: username_skeleton(&username),
     username,
-    password_digest: password_digest.to_string(),
+    password_digest: Some(hash_password(&password)),
     license_game_stage,
+    email,
+    email_verified_at: None,
+    deletion_due_at: None,
   };
 
   diesel::insert_into(users::table).values(&new_user).get_result(conn)
 }
 
+/// Schedules the deletion of `uid` at `due_at`, or cancels it with `None`.
+pub fn set_user_deletion(conn: &PgConnection, uid: Uuid, due_at: Option<NaiveDateTime>) -> Result<(), Error> {
+  diesel::update(users::table.filter(users::id.eq(uid)))
+    .set(users::deletion_due_at.eq(due_at))
+    .execute(conn)
+    .map(|_| ())
+}
+
+/// Deletes every account due for deletion by `now`, along with everything
+/// referencing it, returning their ids.
+pub fn delete_due_users(conn: &PgConnection, now: NaiveDateTime) -> Result<Vec<Uuid>, Error> {
+  diesel::delete(users::table.filter(users::deletion_due_at.le(now)))
+    .returning(users::id)
+    .get_results(conn)
+}
+
+pub fn update_user_password(conn: &PgConnection, uid: Uuid, password: &str) -> Result<(), Error> {
+  diesel::update(users::table.filter(users::id.eq(uid)))
+    .set(users::password_digest.eq(hash_password(password)))
+    .execute(conn)
+    .map(|_| ())
+}
+
+sql_function!(fn lower(x: diesel::sql_types::Nullable<diesel::sql_types::Text>) -> diesel::sql_types::Nullable<diesel::sql_types::Text>);
+
+/// Finds a user by username, or by email address when `login` contains an
+/// `@`, which usernames cannot. Both ignore case.
+pub fn find_user_by_login(conn: &PgConnection, login: &str) -> Result<User, Error> {
+  if login.contains('@') {
+    users::table
+      .filter(lower(users::email).eq(login.to_lowercase()))
+      .first(conn)
+  } else {
+    users::table
+      .filter(lower(users::username.nullable()).eq(login.to_lowercase()))
+      .first(conn)
+  }
+}
+
+/// Usernames of accounts that look like `username`, itself included in any
+/// case.
+pub fn find_similar_usernames(conn: &PgConnection, username: &str) -> Result<Vec<String>, Error> {
+  users::table
+    .filter(users::username_skeleton.eq(username_skeleton(username)))
+    .select(users::username)
+    .load(conn)
+}
+
+pub fn is_email_in_use(conn: &PgConnection, email: &str) -> Result<bool, Error> {
+  users::table
+    .filter(lower(users::email).eq(email.to_lowercase()))
+    .count()
+    .get_result(conn)
+    .map(|count: i64| count > 0)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "email_verifications"]
+pub struct EmailVerification {
+  pub token_hash: String,
+  pub user_id: Uuid,
+  pub email: String,
+  pub created_at: NaiveDateTime,
+  pub expires_at: NaiveDateTime,
+}
+
+/// Creates a token proving `uid` owns `email`, returning the token to mail.
+pub fn create_email_verification(
+  conn: &PgConnection,
+  uid: Uuid,
+  email: String,
+  ttl: chrono::Duration,
+) -> Result<String, Error> {
+  let token = generate_secret();
+  let now = chrono::Utc::now().naive_utc();
+  let verification = EmailVerification {
+    token_hash: hash_token(&token),
+    user_id: uid,
+    email,
+    created_at: now,
+    expires_at: now + ttl,
+  };
+
+  diesel::insert_into(email_verifications::table)
+    .values(&verification)
+    .execute(conn)
+    .map(|_| token)
+}
+
+/// Spends a verification token, marking the address as verified. Returns
+/// `false` if the token is unknown, expired, or for an address the user no
+/// longer has.
+pub fn verify_email(conn: &PgConnection, token: &str) -> Result<bool, Error> {
+  let verification: EmailVerification = match diesel::delete(
+    email_verifications::table
+      .filter(email_verifications::token_hash.eq(hash_token(token)))
+      .filter(email_verifications::expires_at.gt(chrono::Utc::now().naive_utc())),
+  )
+  .get_result(conn)
+  {
+    Ok(verification) => verification,
+    Err(Error::NotFound) => return Ok(false),
+    Err(err) => return Err(err),
+  };
+
+  diesel::update(
+    users::table
+      .filter(users::id.eq(verification.user_id))
+      .filter(users::email.eq(verification.email)),
+  )
+  .set(users::email_verified_at.eq(chrono::Utc::now().naive_utc()))
+  .execute(conn)
+  .map(|updated| updated == 1)
+}
+
+pub fn delete_expired_email_verifications(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+  diesel::delete(email_verifications::table.filter(email_verifications::expires_at.lt(now))).execute(conn)
+}
+
+/// Verification mails sent to `uid` whose token is still live.
+pub fn get_user_email_verifications(conn: &PgConnection, uid: Uuid) -> Result<Vec<EmailVerification>, Error> {
+  email_verifications::table
+    .filter(email_verifications::user_id.eq(uid))
+    .filter(email_verifications::expires_at.gt(chrono::Utc::now().naive_utc()))
+    .order(email_verifications::created_at.asc())
+    .load(conn)
+}
+
+/// Makes the account passwordless, leaving passkeys as the only way in.
+pub fn remove_user_password(conn: &PgConnection, uid: Uuid) -> Result<(), Error> {
+  diesel::update(users::table.filter(users::id.eq(uid)))
+    .set(users::password_digest.eq(None::<String>))
+    .execute(conn)
+    .map(|_| ())
+}
+
 pub fn reset_license_game_stage(conn: &PgConnection, user: User) -> Result<User, Error> {
   diesel::update(users::table.filter(users::id.eq(user.id)))
     .set(users::license_game_stage.eq(0))
@@ -59,22 +224,817 @@ pub fn update_game_stage(conn: &PgConnection, user: User, new_stage: i32) -> Use
     .unwrap()
 }
 
-#[derive(Associations, Insertable, Queryable)]
+#[derive(Identifiable, Associations, Insertable, Queryable)]
 #[belongs_to(User)]
 #[table_name = "sessions"]
 pub struct Session {
-  pub token: Uuid,
+  pub id: Uuid,
+  pub token_hash: String,
   pub user_id: Uuid,
   pub last_used: NaiveDateTime,
+  pub created_at: NaiveDateTime,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub device_label: Option<String>,
+  /// Scopes granted to an OAuth client, `None` for a full login session.
+  pub scopes: Option<Vec<String>>,
+  pub oauth_client_id: Option<Uuid>,
+}
+
+/// Client details captured when a session is created.
+#[derive(Default)]
+pub struct SessionMetadata {
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub device_label: Option<String>,
+  pub scopes: Option<Vec<String>>,
+  pub oauth_client_id: Option<Uuid>,
 }
 
-pub fn update_session_last_used(conn: &PgConnection, token: Uuid) -> Result<(), Error> {
-  diesel::update(sessions::table.filter(sessions::token.eq(token)))
+/// Creates a session for `uid`, returning the bearer token alongside the
+/// stored row. The token itself is never persisted, only its hash.
+pub fn create_session(conn: &PgConnection, uid: Uuid, metadata: SessionMetadata) -> Result<(Uuid, Session), Error> {
+  let token = Uuid::new_v4();
+  let now = chrono::Utc::now().naive_utc();
+  let session = Session {
+    id: Uuid::new_v4(),
+    token_hash: hash_token(&token.to_string()),
+    user_id: uid,
+    last_used: now,
+    created_at: now,
+    ip: metadata.ip,
+    user_agent: metadata.user_agent,
+    device_label: metadata.device_label,
+    scopes: metadata.scopes,
+    oauth_client_id: metadata.oauth_client_id,
+  };
+
+  diesel::insert_into(sessions::table)
+    .values(&session)
+    .get_result(conn)
+    .map(|session| (token, session))
+}
+
+pub fn get_session(conn: &PgConnection, session_id: Uuid) -> Result<Session, Error> {
+  sessions::table.filter(sessions::id.eq(session_id)).first(conn)
+}
+
+pub fn update_session_last_used(conn: &PgConnection, session_id: Uuid) -> Result<(), Error> {
+  diesel::update(sessions::table.filter(sessions::id.eq(session_id)))
     .set(sessions::last_used.eq(chrono::Utc::now().naive_utc()))
     .get_result::<Session>(conn)
     .map(|_| ())
 }
 
+pub fn delete_session(conn: &PgConnection, session_id: Uuid) -> Result<(), Error> {
+  diesel::delete(sessions::table.filter(sessions::id.eq(session_id)))
+    .execute(conn)
+    .map(|_| ())
+}
+
+pub fn get_user_sessions(conn: &PgConnection, uid: Uuid) -> Result<Vec<Session>, Error> {
+  sessions::table
+    .filter(sessions::user_id.eq(uid))
+    .order(sessions::created_at.desc())
+    .load(conn)
+}
+
+pub fn delete_user_session(conn: &PgConnection, uid: Uuid, session_id: Uuid) -> Result<usize, Error> {
+  diesel::delete(
+    sessions::table
+      .filter(sessions::user_id.eq(uid))
+      .filter(sessions::id.eq(session_id)),
+  )
+  .execute(conn)
+}
+
+/// Deletes every session belonging to `uid` except the session `keep`.
+pub fn delete_other_sessions(conn: &PgConnection, uid: Uuid, keep: Uuid) -> Result<usize, Error> {
+  diesel::delete(
+    sessions::table
+      .filter(sessions::user_id.eq(uid))
+      .filter(sessions::id.ne(keep)),
+  )
+  .execute(conn)
+}
+
+pub fn delete_user_sessions(conn: &PgConnection, uid: Uuid) -> Result<usize, Error> {
+  diesel::delete(sessions::table.filter(sessions::user_id.eq(uid))).execute(conn)
+}
+
+/// Deletes every session last used before `idle_cutoff` or created before
+/// `lifetime_cutoff`. A `None` cutoff disables that policy.
+pub fn delete_expired_sessions(
+  conn: &PgConnection,
+  idle_cutoff: Option<NaiveDateTime>,
+  lifetime_cutoff: Option<NaiveDateTime>,
+) -> Result<usize, Error> {
+  let mut deleted = 0;
+  if let Some(cutoff) = idle_cutoff {
+    deleted += diesel::delete(sessions::table.filter(sessions::last_used.lt(cutoff))).execute(conn)?;
+  }
+  if let Some(cutoff) = lifetime_cutoff {
+    deleted += diesel::delete(sessions::table.filter(sessions::created_at.lt(cutoff))).execute(conn)?;
+  }
+  Ok(deleted)
+}
+
+/// Single use token exchanged for a new access token. Every token minted from
+/// one login shares its `session_id`, which acts as the token family.
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(Session)]
+#[table_name = "refresh_tokens"]
+pub struct RefreshToken {
+  pub id: Uuid,
+  pub session_id: Uuid,
+  pub user_id: Uuid,
+  pub token_hash: String,
+  pub created_at: NaiveDateTime,
+  pub expires_at: NaiveDateTime,
+  pub used_at: Option<NaiveDateTime>,
+}
+
+pub fn create_refresh_token(
+  conn: &PgConnection,
+  session: &Session,
+  ttl: chrono::Duration,
+) -> Result<(Uuid, RefreshToken), Error> {
+  let token = Uuid::new_v4();
+  let now = chrono::Utc::now().naive_utc();
+  let refresh_token = RefreshToken {
+    id: Uuid::new_v4(),
+    session_id: session.id,
+    user_id: session.user_id,
+    token_hash: hash_token(&token.to_string()),
+    created_at: now,
+    expires_at: now + ttl,
+    used_at: None,
+  };
+
+  diesel::insert_into(refresh_tokens::table)
+    .values(&refresh_token)
+    .get_result(conn)
+    .map(|refresh_token| (token, refresh_token))
+}
+
+pub fn find_refresh_token(conn: &PgConnection, token: &str) -> Result<RefreshToken, Error> {
+  refresh_tokens::table
+    .filter(refresh_tokens::token_hash.eq(hash_token(token)))
+    .first(conn)
+}
+
+/// Marks a refresh token as spent. Returns `false` if it had already been used,
+/// which means the token was replayed.
+pub fn use_refresh_token(conn: &PgConnection, refresh_token_id: Uuid) -> Result<bool, Error> {
+  diesel::update(
+    refresh_tokens::table
+      .filter(refresh_tokens::id.eq(refresh_token_id))
+      .filter(refresh_tokens::used_at.is_null()),
+  )
+  .set(refresh_tokens::used_at.eq(chrono::Utc::now().naive_utc()))
+  .execute(conn)
+  .map(|updated| updated == 1)
+}
+
+pub fn delete_expired_refresh_tokens(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+  diesel::delete(refresh_tokens::table.filter(refresh_tokens::expires_at.lt(now))).execute(conn)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "api_keys"]
+pub struct ApiKey {
+  pub id: Uuid,
+  pub user_id: Uuid,
+  pub name: String,
+  pub prefix: String,
+  pub key_hash: String,
+  pub scopes: Vec<String>,
+  pub created_at: NaiveDateTime,
+  pub last_used: Option<NaiveDateTime>,
+  pub expires_at: Option<NaiveDateTime>,
+}
+
+impl ApiKey {
+  pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+    matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+  }
+}
+
+/// Creates an API key for `uid`, returning the key itself alongside the stored
+/// row. Like session tokens, only a hash of the key is persisted.
+pub fn create_api_key(
+  conn: &PgConnection,
+  uid: Uuid,
+  name: String,
+  scopes: Vec<String>,
+  expires_at: Option<NaiveDateTime>,
+) -> Result<(String, ApiKey), Error> {
+  let (prefix, key) = generate_api_key();
+  let api_key = ApiKey {
+    id: Uuid::new_v4(),
+    user_id: uid,
+    name,
+    prefix,
+    key_hash: hash_token(&key),
+    scopes,
+    created_at: chrono::Utc::now().naive_utc(),
+    last_used: None,
+    expires_at,
+  };
+
+  diesel::insert_into(api_keys::table)
+    .values(&api_key)
+    .get_result(conn)
+    .map(|api_key| (key, api_key))
+}
+
+pub fn find_api_key(conn: &PgConnection, key: &str) -> Result<ApiKey, Error> {
+  api_keys::table.filter(api_keys::key_hash.eq(hash_token(key))).first(conn)
+}
+
+pub fn get_user_api_keys(conn: &PgConnection, uid: Uuid) -> Result<Vec<ApiKey>, Error> {
+  api_keys::table
+    .filter(api_keys::user_id.eq(uid))
+    .order(api_keys::created_at.desc())
+    .load(conn)
+}
+
+pub fn delete_user_api_key(conn: &PgConnection, uid: Uuid, api_key_id: Uuid) -> Result<usize, Error> {
+  diesel::delete(
+    api_keys::table
+      .filter(api_keys::user_id.eq(uid))
+      .filter(api_keys::id.eq(api_key_id)),
+  )
+  .execute(conn)
+}
+
+pub fn update_api_key_last_used(conn: &PgConnection, api_key_id: Uuid) -> Result<(), Error> {
+  diesel::update(api_keys::table.filter(api_keys::id.eq(api_key_id)))
+    .set(api_keys::last_used.eq(chrono::Utc::now().naive_utc()))
+    .execute(conn)
+    .map(|_| ())
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User, foreign_key = "owner_id")]
+#[table_name = "oauth_clients"]
+pub struct OAuthClient {
+  pub id: Uuid,
+  pub owner_id: Uuid,
+  pub name: String,
+  /// Confidential clients authenticate with a secret, public clients rely on
+  /// PKCE alone.
+  pub secret_hash: Option<String>,
+  pub redirect_uris: Vec<String>,
+  pub created_at: NaiveDateTime,
+}
+
+/// Registers an OAuth client, returning the client secret when `confidential`.
+pub fn create_oauth_client(
+  conn: &PgConnection,
+  owner_id: Uuid,
+  name: String,
+  redirect_uris: Vec<String>,
+  confidential: bool,
+) -> Result<(Option<String>, OAuthClient), Error> {
+  let secret = if confidential { Some(generate_secret()) } else { None };
+  let client = OAuthClient {
+    id: Uuid::new_v4(),
+    owner_id,
+    name,
+    secret_hash: secret.as_deref().map(hash_token),
+    redirect_uris,
+    created_at: chrono::Utc::now().naive_utc(),
+  };
+
+  diesel::insert_into(oauth_clients::table)
+    .values(&client)
+    .get_result(conn)
+    .map(|client| (secret, client))
+}
+
+pub fn get_oauth_client(conn: &PgConnection, client_id: Uuid) -> Result<OAuthClient, Error> {
+  oauth_clients::table.filter(oauth_clients::id.eq(client_id)).first(conn)
+}
+
+pub fn get_user_oauth_clients(conn: &PgConnection, uid: Uuid) -> Result<Vec<OAuthClient>, Error> {
+  oauth_clients::table
+    .filter(oauth_clients::owner_id.eq(uid))
+    .order(oauth_clients::created_at.desc())
+    .load(conn)
+}
+
+pub fn delete_user_oauth_client(conn: &PgConnection, uid: Uuid, client_id: Uuid) -> Result<usize, Error> {
+  diesel::delete(
+    oauth_clients::table
+      .filter(oauth_clients::owner_id.eq(uid))
+      .filter(oauth_clients::id.eq(client_id)),
+  )
+  .execute(conn)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[belongs_to(OAuthClient, foreign_key = "client_id")]
+#[table_name = "oauth_authorization_codes"]
+pub struct OAuthAuthorizationCode {
+  pub code_hash: String,
+  pub client_id: Uuid,
+  pub user_id: Uuid,
+  pub redirect_uri: String,
+  pub scopes: Vec<String>,
+  pub code_challenge: String,
+  pub created_at: NaiveDateTime,
+  pub expires_at: NaiveDateTime,
+  /// Echoed into the `id_token` for OpenID Connect requests.
+  pub nonce: Option<String>,
+}
+
+/// An approved authorization request waiting to be turned into a code.
+pub struct AuthorizationGrant {
+  pub client_id: Uuid,
+  pub user_id: Uuid,
+  pub redirect_uri: String,
+  pub scopes: Vec<String>,
+  pub code_challenge: String,
+  pub nonce: Option<String>,
+}
+
+pub fn create_oauth_authorization_code(
+  conn: &PgConnection,
+  grant: AuthorizationGrant,
+  ttl: chrono::Duration,
+) -> Result<String, Error> {
+  let code = generate_secret();
+  let now = chrono::Utc::now().naive_utc();
+  let authorization_code = OAuthAuthorizationCode {
+    code_hash: hash_token(&code),
+    client_id: grant.client_id,
+    user_id: grant.user_id,
+    redirect_uri: grant.redirect_uri,
+    scopes: grant.scopes,
+    code_challenge: grant.code_challenge,
+    created_at: now,
+    expires_at: now + ttl,
+    nonce: grant.nonce,
+  };
+
+  diesel::insert_into(oauth_authorization_codes::table)
+    .values(&authorization_code)
+    .execute(conn)
+    .map(|_| code)
+}
+
+/// Removes and returns an authorization code so it can only be exchanged once.
+pub fn take_oauth_authorization_code(conn: &PgConnection, code: &str) -> Result<OAuthAuthorizationCode, Error> {
+  diesel::delete(oauth_authorization_codes::table.filter(oauth_authorization_codes::code_hash.eq(hash_token(code))))
+    .get_result(conn)
+}
+
+pub fn delete_expired_oauth_authorization_codes(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+  diesel::delete(oauth_authorization_codes::table.filter(oauth_authorization_codes::expires_at.lt(now))).execute(conn)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "totp_credentials"]
+pub struct TotpCredential {
+  pub user_id: Uuid,
+  pub secret: String,
+  /// Unset until the user proves their authenticator works.
+  pub confirmed_at: Option<NaiveDateTime>,
+  /// Last accepted time step, codes from this step or earlier are replays.
+  pub last_used_step: Option<i64>,
+  pub created_at: NaiveDateTime,
+}
+
+impl TotpCredential {
+  pub fn is_confirmed(&self) -> bool {
+    self.confirmed_at.is_some()
+  }
+}
+
+pub fn get_totp_credential(conn: &PgConnection, uid: Uuid) -> Result<TotpCredential, Error> {
+  totp_credentials::table.filter(totp_credentials::user_id.eq(uid)).first(conn)
+}
+
+/// Starts a new enrollment for `uid`, replacing any unconfirmed one.
+pub fn create_totp_credential(conn: &PgConnection, uid: Uuid, secret: String) -> Result<TotpCredential, Error> {
+  diesel::delete(
+    totp_credentials::table
+      .filter(totp_credentials::user_id.eq(uid))
+      .filter(totp_credentials::confirmed_at.is_null()),
+  )
+  .execute(conn)?;
+
+  let credential = TotpCredential {
+    user_id: uid,
+    secret,
+    confirmed_at: None,
+    last_used_step: None,
+    created_at: chrono::Utc::now().naive_utc(),
+  };
+  diesel::insert_into(totp_credentials::table)
+    .values(&credential)
+    .get_result(conn)
+}
+
+/// Records `step` as used. Returns `false` if a code from the same or a later
+/// step was already accepted.
+pub fn use_totp_step(conn: &PgConnection, uid: Uuid, step: i64) -> Result<bool, Error> {
+  diesel::update(
+    totp_credentials::table
+      .filter(totp_credentials::user_id.eq(uid))
+      .filter(
+        totp_credentials::last_used_step
+          .is_null()
+          .or(totp_credentials::last_used_step.lt(step)),
+      ),
+  )
+  .set(totp_credentials::last_used_step.eq(step))
+  .execute(conn)
+  .map(|updated| updated == 1)
+}
+
+pub fn confirm_totp_credential(conn: &PgConnection, uid: Uuid) -> Result<(), Error> {
+  diesel::update(totp_credentials::table.filter(totp_credentials::user_id.eq(uid)))
+    .set(totp_credentials::confirmed_at.eq(chrono::Utc::now().naive_utc()))
+    .execute(conn)
+    .map(|_| ())
+}
+
+/// Turns off two factor authentication, removing the secret and every
+/// recovery code. Returns `false` if it was not enabled.
+pub fn delete_two_factor(conn: &PgConnection, uid: Uuid) -> Result<bool, Error> {
+  diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(uid))).execute(conn)?;
+  diesel::delete(totp_credentials::table.filter(totp_credentials::user_id.eq(uid)))
+    .execute(conn)
+    .map(|deleted| deleted == 1)
+}
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "recovery_codes"]
+pub struct RecoveryCode {
+  pub code_hash: String,
+  pub user_id: Uuid,
+  pub used_at: Option<NaiveDateTime>,
+}
+
+/// Replaces the recovery codes of `uid` with a fresh set, returning the codes
+/// so they can be shown to the user once.
+pub fn create_recovery_codes(conn: &PgConnection, uid: Uuid) -> Result<Vec<String>, Error> {
+  let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+  let rows: Vec<RecoveryCode> = codes
+    .iter()
+    .map(|code| RecoveryCode {
+      code_hash: hash_token(&normalize_recovery_code(code)),
+      user_id: uid,
+      used_at: None,
+    })
+    .collect();
+
+  diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(uid))).execute(conn)?;
+  diesel::insert_into(recovery_codes::table)
+    .values(&rows)
+    .execute(conn)
+    .map(|_| codes)
+}
+
+/// Spends a recovery code. Returns `false` if it does not exist or was already
+/// used.
+pub fn use_recovery_code(conn: &PgConnection, uid: Uuid, code: &str) -> Result<bool, Error> {
+  diesel::update(
+    recovery_codes::table
+      .filter(recovery_codes::user_id.eq(uid))
+      .filter(recovery_codes::code_hash.eq(hash_token(&normalize_recovery_code(code))))
+      .filter(recovery_codes::used_at.is_null()),
+  )
+  .set(recovery_codes::used_at.eq(chrono::Utc::now().naive_utc()))
+  .execute(conn)
+  .map(|updated| updated == 1)
+}
+
+pub fn count_unused_recovery_codes(conn: &PgConnection, uid: Uuid) -> Result<i64, Error> {
+  recovery_codes::table
+    .filter(recovery_codes::user_id.eq(uid))
+    .filter(recovery_codes::used_at.is_null())
+    .count()
+    .get_result(conn)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "login_challenges"]
+pub struct LoginChallenge {
+  pub token_hash: String,
+  pub user_id: Uuid,
+  pub device_label: Option<String>,
+  pub attempts: i32,
+  pub created_at: NaiveDateTime,
+  pub expires_at: NaiveDateTime,
+}
+
+/// Creates a challenge for a login that still needs a second factor,
+/// returning the token the client answers it with.
+pub fn create_login_challenge(
+  conn: &PgConnection,
+  uid: Uuid,
+  device_label: Option<String>,
+  ttl: chrono::Duration,
+) -> Result<String, Error> {
+  let token = generate_secret();
+  let now = chrono::Utc::now().naive_utc();
+  let challenge = LoginChallenge {
+    token_hash: hash_token(&token),
+    user_id: uid,
+    device_label,
+    attempts: 0,
+    created_at: now,
+    expires_at: now + ttl,
+  };
+
+  diesel::insert_into(login_challenges::table)
+    .values(&challenge)
+    .execute(conn)
+    .map(|_| token)
+}
+
+pub fn find_login_challenge(conn: &PgConnection, token: &str) -> Result<LoginChallenge, Error> {
+  login_challenges::table
+    .filter(login_challenges::token_hash.eq(hash_token(token)))
+    .first(conn)
+}
+
+/// Counts a wrong answer against a challenge, returning the updated count.
+pub fn record_login_challenge_failure(conn: &PgConnection, token_hash: &str) -> Result<i32, Error> {
+  diesel::update(login_challenges::table.filter(login_challenges::token_hash.eq(token_hash)))
+    .set(login_challenges::attempts.eq(login_challenges::attempts + 1))
+    .returning(login_challenges::attempts)
+    .get_result(conn)
+}
+
+pub fn delete_login_challenge(conn: &PgConnection, token_hash: &str) -> Result<(), Error> {
+  diesel::delete(login_challenges::table.filter(login_challenges::token_hash.eq(token_hash)))
+    .execute(conn)
+    .map(|_| ())
+}
+
+pub fn delete_expired_login_challenges(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+  diesel::delete(login_challenges::table.filter(login_challenges::expires_at.lt(now))).execute(conn)
+}
+
+/// Failed logins against an account or client address, see
+/// [`crate::lockout`].
+#[derive(AsChangeset, Insertable, Queryable)]
+#[table_name = "login_throttles"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct LoginThrottle {
+  pub subject: String,
+  pub failed_attempts: i32,
+  pub last_failed_at: NaiveDateTime,
+  pub locked_until: Option<NaiveDateTime>,
+}
+
+pub fn find_login_throttle(conn: &PgConnection, subject: &str) -> Result<Option<LoginThrottle>, Error> {
+  login_throttles::table
+    .filter(login_throttles::subject.eq(subject))
+    .first(conn)
+    .optional()
+}
+
+pub fn find_login_throttles(conn: &PgConnection, subjects: &[String]) -> Result<Vec<LoginThrottle>, Error> {
+  login_throttles::table
+    .filter(login_throttles::subject.eq_any(subjects))
+    .order(login_throttles::subject.asc())
+    .load(conn)
+}
+
+pub fn save_login_throttle(conn: &PgConnection, throttle: &LoginThrottle) -> Result<(), Error> {
+  diesel::insert_into(login_throttles::table)
+    .values(throttle)
+    .on_conflict(login_throttles::subject)
+    .do_update()
+    .set(throttle)
+    .execute(conn)
+    .map(|_| ())
+}
+
+pub fn delete_login_throttle(conn: &PgConnection, subject: &str) -> Result<(), Error> {
+  diesel::delete(login_throttles::table.filter(login_throttles::subject.eq(subject)))
+    .execute(conn)
+    .map(|_| ())
+}
+
+/// Deletes throttles that are no longer locked and whose last failure is
+/// older than `failed_before`, as those failures no longer count.
+pub fn delete_expired_login_throttles(
+  conn: &PgConnection,
+  failed_before: NaiveDateTime,
+  now: NaiveDateTime,
+) -> Result<usize, Error> {
+  diesel::delete(
+    login_throttles::table
+      .filter(login_throttles::last_failed_at.lt(failed_before))
+      .filter(
+        login_throttles::locked_until
+          .is_null()
+          .or(login_throttles::locked_until.lt(now)),
+      ),
+  )
+  .execute(conn)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "password_resets"]
+pub struct PasswordReset {
+  pub token_hash: String,
+  pub user_id: Uuid,
+  pub created_at: NaiveDateTime,
+  pub expires_at: NaiveDateTime,
+}
+
+/// Creates a reset token for `uid`, returning the token to send to the user.
+pub fn create_password_reset(conn: &PgConnection, uid: Uuid, ttl: chrono::Duration) -> Result<String, Error> {
+  let token = generate_secret();
+  let now = chrono::Utc::now().naive_utc();
+  let reset = PasswordReset {
+    token_hash: hash_token(&token),
+    user_id: uid,
+    created_at: now,
+    expires_at: now + ttl,
+  };
+
+  diesel::insert_into(password_resets::table)
+    .values(&reset)
+    .execute(conn)
+    .map(|_| token)
+}
+
+/// Removes and returns an unexpired reset token so it can only be used once.
+pub fn take_password_reset(conn: &PgConnection, token: &str) -> Result<PasswordReset, Error> {
+  diesel::delete(
+    password_resets::table
+      .filter(password_resets::token_hash.eq(hash_token(token)))
+      .filter(password_resets::expires_at.gt(chrono::Utc::now().naive_utc())),
+  )
+  .get_result(conn)
+}
+
+/// The user a live reset token belongs to, leaving the token in place.
+pub fn find_password_reset_user(conn: &PgConnection, token: &str) -> Result<User, Error> {
+  password_resets::table
+    .inner_join(users::table)
+    .filter(password_resets::token_hash.eq(hash_token(token)))
+    .filter(password_resets::expires_at.gt(chrono::Utc::now().naive_utc()))
+    .select(users::all_columns)
+    .first(conn)
+}
+
+pub fn delete_user_password_resets(conn: &PgConnection, uid: Uuid) -> Result<usize, Error> {
+  diesel::delete(password_resets::table.filter(password_resets::user_id.eq(uid))).execute(conn)
+}
+
+/// Reset tokens sent to `uid` that can still be used.
+pub fn get_user_password_resets(conn: &PgConnection, uid: Uuid) -> Result<Vec<PasswordReset>, Error> {
+  password_resets::table
+    .filter(password_resets::user_id.eq(uid))
+    .filter(password_resets::expires_at.gt(chrono::Utc::now().naive_utc()))
+    .order(password_resets::created_at.asc())
+    .load(conn)
+}
+
+pub fn delete_expired_password_resets(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+  diesel::delete(password_resets::table.filter(password_resets::expires_at.lt(now))).execute(conn)
+}
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "passkeys"]
+pub struct Passkey {
+  pub id: Uuid,
+  pub user_id: Uuid,
+  pub credential_id: Vec<u8>,
+  /// SEC1 encoded P-256 public key.
+  pub public_key: Vec<u8>,
+  pub sign_count: i64,
+  pub name: String,
+  pub created_at: NaiveDateTime,
+  pub last_used: Option<NaiveDateTime>,
+}
+
+pub fn create_passkey(
+  conn: &PgConnection,
+  uid: Uuid,
+  name: String,
+  credential: RegisteredCredential,
+) -> Result<Passkey, Error> {
+  let passkey = Passkey {
+    id: Uuid::new_v4(),
+    user_id: uid,
+    credential_id: credential.credential_id,
+    public_key: credential.public_key,
+    sign_count: credential.sign_count as i64,
+    name,
+    created_at: chrono::Utc::now().naive_utc(),
+    last_used: None,
+  };
+
+  diesel::insert_into(passkeys::table).values(&passkey).get_result(conn)
+}
+
+pub fn find_passkey(conn: &PgConnection, credential_id: &[u8]) -> Result<Passkey, Error> {
+  passkeys::table
+    .filter(passkeys::credential_id.eq(credential_id))
+    .first(conn)
+}
+
+pub fn get_user_passkeys(conn: &PgConnection, uid: Uuid) -> Result<Vec<Passkey>, Error> {
+  passkeys::table
+    .filter(passkeys::user_id.eq(uid))
+    .order(passkeys::created_at.asc())
+    .load(conn)
+}
+
+/// Deletes a passkey, scoped to its owner so users cannot remove each other's.
+pub fn delete_user_passkey(conn: &PgConnection, uid: Uuid, passkey_id: Uuid) -> Result<usize, Error> {
+  diesel::delete(
+    passkeys::table
+      .filter(passkeys::id.eq(passkey_id))
+      .filter(passkeys::user_id.eq(uid)),
+  )
+  .execute(conn)
+}
+
+pub fn update_passkey_sign_count(conn: &PgConnection, passkey_id: Uuid, sign_count: u32) -> Result<(), Error> {
+  diesel::update(passkeys::table.filter(passkeys::id.eq(passkey_id)))
+    .set((
+      passkeys::sign_count.eq(sign_count as i64),
+      passkeys::last_used.eq(chrono::Utc::now().naive_utc()),
+    ))
+    .execute(conn)
+    .map(|_| ())
+}
+
+pub const WEBAUTHN_REGISTRATION: &str = "registration";
+pub const WEBAUTHN_AUTHENTICATION: &str = "authentication";
+/// A signed in user confirming a sensitive action with a passkey.
+pub const WEBAUTHN_REAUTHENTICATION: &str = "reauthentication";
+/// An account scheduled for deletion cancelling it with a passkey.
+pub const WEBAUTHN_CANCEL_DELETION: &str = "cancel_deletion";
+
+#[derive(Associations, Insertable, Queryable)]
+#[belongs_to(User)]
+#[table_name = "webauthn_challenges"]
+pub struct WebAuthnChallenge {
+  pub id: Uuid,
+  /// The registering user, or the user named at the start of an
+  /// authentication.
+  pub user_id: Option<Uuid>,
+  pub challenge: String,
+  pub purpose: String,
+  pub expires_at: NaiveDateTime,
+}
+
+pub fn create_webauthn_challenge(
+  conn: &PgConnection,
+  uid: Option<Uuid>,
+  purpose: &str,
+  ttl: chrono::Duration,
+) -> Result<WebAuthnChallenge, Error> {
+  let challenge = WebAuthnChallenge {
+    id: Uuid::new_v4(),
+    user_id: uid,
+    challenge: generate_challenge(),
+    purpose: purpose.to_string(),
+    expires_at: chrono::Utc::now().naive_utc() + ttl,
+  };
+
+  diesel::insert_into(webauthn_challenges::table)
+    .values(&challenge)
+    .get_result(conn)
+}
+
+/// Removes and returns an unexpired challenge so each one can only be
+/// answered once.
+pub fn take_webauthn_challenge(conn: &PgConnection, id: Uuid, purpose: &str) -> Result<WebAuthnChallenge, Error> {
+  diesel::delete(
+    webauthn_challenges::table
+      .filter(webauthn_challenges::id.eq(id))
+      .filter(webauthn_challenges::purpose.eq(purpose))
+      .filter(webauthn_challenges::expires_at.gt(chrono::Utc::now().naive_utc())),
+  )
+  .get_result(conn)
+}
+
+pub fn delete_expired_webauthn_challenges(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+  diesel::delete(webauthn_challenges::table.filter(webauthn_challenges::expires_at.lt(now))).execute(conn)
+}
+
 #[derive(Associations, Insertable, Queryable, Debug)]
 #[belongs_to(User)]
 #[table_name = "games"]
@@ -98,6 +1058,10 @@ impl Game {
   }
 }
 
+pub fn get_user_game(conn: &PgConnection, uid: Uuid) -> Result<Option<Game>, Error> {
+  games::table.filter(games::user_id.eq(uid)).first(conn).optional()
+}
+
 pub fn create_game_instruction(conn: &PgConnection, uid: Uuid, inst: u16) -> Result<(User, Game), Error> {
   let game = Game {
     token: Uuid::new_v4(),
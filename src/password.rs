@@ -0,0 +1,322 @@
+use std::env;
+use std::str::FromStr;
+
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, ParamsBuilder, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use rand_core::OsRng;
+
+lazy_static::lazy_static! {
+  static ref PASSWORD_HASHER: Hasher = hasher_from_env();
+  /// Stands in for the digest of accounts that do not exist or have no
+  /// password, made with the current settings so checking it costs the same.
+  static ref DUMMY_DIGEST: String = hash_password("not the password of any account");
+}
+
+/// Secret mixed into every hash, so a leaked database alone is not enough to
+/// brute force passwords. The id is stored in each hash as its Argon2 `keyid`.
+struct Pepper {
+  id: String,
+  secret: Vec<u8>,
+}
+
+impl FromStr for Pepper {
+  type Err = String;
+
+  /// Parses `<id>:<secret>`, with an id of at most 8 bytes.
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.split_once(':') {
+      Some((id, secret)) if !id.is_empty() && id.len() <= Params::MAX_KEYID_LEN && !secret.is_empty() => Ok(Self {
+        id: id.to_string(),
+        secret: secret.as_bytes().to_vec(),
+      }),
+      _ => Err(format!(
+        "Peppers must look like <id>:<secret>, with an id of 1 to {} bytes",
+        Params::MAX_KEYID_LEN
+      )),
+    }
+  }
+}
+
+/// Argon2 settings for new hashes, along with retired peppers that are only
+/// used to check old hashes.
+struct Hasher {
+  algorithm: Algorithm,
+  params: Params,
+  pepper: Option<Pepper>,
+  retired_peppers: Vec<Pepper>,
+}
+
+impl Hasher {
+  fn new(algorithm: Algorithm, params: Params) -> Self {
+    Self {
+      algorithm,
+      params,
+      pepper: None,
+      retired_peppers: Vec::new(),
+    }
+  }
+
+  /// Argon2 for new hashes, tagged with the current pepper's id.
+  fn argon2(&self) -> Argon2<'_> {
+    match &self.pepper {
+      Some(pepper) => {
+        let mut builder = ParamsBuilder::new();
+        builder.m_cost(self.params.m_cost()).unwrap();
+        builder.t_cost(self.params.t_cost()).unwrap();
+        builder.p_cost(self.params.p_cost()).unwrap();
+        builder.keyid(pepper.id.as_bytes()).unwrap();
+        Argon2::new_with_secret(
+          &pepper.secret,
+          self.algorithm,
+          Version::default(),
+          builder.params().unwrap(),
+        )
+        .unwrap()
+      },
+      None => Argon2::new(self.algorithm, Version::default(), self.params.clone()),
+    }
+  }
+
+  /// Argon2 holding the pepper a hash was made with. The remaining parameters
+  /// are read from the hash itself. `None` when that pepper is not configured.
+  fn argon2_for(&self, keyid: &[u8]) -> Option<Argon2<'_>> {
+    if keyid.is_empty() {
+      return Some(Argon2::default());
+    }
+    self
+      .pepper
+      .iter()
+      .chain(self.retired_peppers.iter())
+      .find(|pepper| pepper.id.as_bytes() == keyid)
+      .map(|pepper| {
+        Argon2::new_with_secret(
+          &pepper.secret,
+          Algorithm::default(),
+          Version::default(),
+          Params::default(),
+        )
+        .unwrap()
+      })
+  }
+}
+
+/// Builds the hasher from `ARGON2_ALGORITHM`, `ARGON2_MEMORY_COST` (KiB),
+/// `ARGON2_TIME_COST` and `ARGON2_PARALLELISM`. Unset values fall back to the
+/// `argon2` crate defaults, which earlier releases always used. New hashes are
+/// peppered with `PASSWORD_PEPPER` when set, and `PASSWORD_RETIRED_PEPPERS`
+/// lists earlier peppers, comma separated, that old hashes may still use.
+fn hasher_from_env() -> Hasher {
+  let algorithm = env::var("ARGON2_ALGORITHM")
+    .map(|name| Algorithm::from_str(&name).unwrap_or_else(|_| panic!("Unknown ARGON2_ALGORITHM {}", name)))
+    .unwrap_or_default();
+  let params = Params::new(
+    cost_from_env("ARGON2_MEMORY_COST", Params::DEFAULT_M_COST),
+    cost_from_env("ARGON2_TIME_COST", Params::DEFAULT_T_COST),
+    cost_from_env("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+    None,
+  )
+  .unwrap_or_else(|err| panic!("Invalid Argon2 parameters. {}", err));
+
+  let mut hasher = Hasher::new(algorithm, params);
+  hasher.pepper = env::var("PASSWORD_PEPPER").ok().map(|pepper| {
+    pepper
+      .parse()
+      .unwrap_or_else(|err| panic!("Invalid PASSWORD_PEPPER. {}", err))
+  });
+  hasher.retired_peppers = env::var("PASSWORD_RETIRED_PEPPERS")
+    .map(|peppers| {
+      peppers
+        .split(',')
+        .map(|pepper| {
+          pepper
+            .trim()
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid PASSWORD_RETIRED_PEPPERS. {}", err))
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  hasher
+}
+
+fn cost_from_env(name: &str, default: u32) -> u32 {
+  env::var(name)
+    .map(|value| {
+      value
+        .parse()
+        .unwrap_or_else(|_| panic!("Failed to parse {} as an integer", name))
+    })
+    .unwrap_or(default)
+}
+
+pub fn hash_password(password: &str) -> String {
+  hash_with(&PASSWORD_HASHER, password)
+}
+
+fn hash_with(hasher: &Hasher, password: &str) -> String {
+  let salt = SaltString::generate(&mut OsRng);
+  hasher
+    .argon2()
+    .hash_password(password.as_bytes(), &salt)
+    .unwrap()
+    .to_string()
+}
+
+/// Checks `password` against a digest using the parameters and pepper stored
+/// in the digest, so hashes made under older settings keep working.
+pub fn verify_password(digest: &str, password: &str) -> bool {
+  verify_with(&PASSWORD_HASHER, digest, password)
+}
+
+fn verify_with(hasher: &Hasher, digest: &str, password: &str) -> bool {
+  let parsed_hash = match PasswordHash::new(digest) {
+    Ok(parsed_hash) => parsed_hash,
+    Err(_) => return false,
+  };
+  let keyid = match Params::try_from(&parsed_hash) {
+    Ok(params) => params.keyid().to_vec(),
+    Err(_) => return false,
+  };
+  match hasher.argon2_for(&keyid) {
+    Some(argon2) => argon2.verify_password(password.as_bytes(), &parsed_hash).is_ok(),
+    None => false,
+  }
+}
+
+/// Takes as long as checking a real password but never succeeds, so failed
+/// logins for unknown accounts cannot be told apart by response time.
+pub fn verify_dummy_password(password: &str) -> bool {
+  verify_password(&DUMMY_DIGEST, password);
+  false
+}
+
+/// Whether a digest was made with another Argon2 variant, weaker costs or
+/// another pepper than currently configured, and should be replaced on the
+/// next successful login.
+pub fn needs_rehash(digest: &str) -> bool {
+  needs_rehash_for(&PASSWORD_HASHER, digest)
+}
+
+fn needs_rehash_for(hasher: &Hasher, digest: &str) -> bool {
+  let parsed_hash = match PasswordHash::new(digest) {
+    Ok(parsed_hash) => parsed_hash,
+    Err(_) => return true,
+  };
+  let algorithm = match Algorithm::try_from(parsed_hash.algorithm) {
+    Ok(algorithm) => algorithm,
+    Err(_) => return true,
+  };
+  let version = parsed_hash
+    .version
+    .and_then(|version| Version::try_from(version).ok())
+    .unwrap_or_default();
+  let params = match Params::try_from(&parsed_hash) {
+    Ok(params) => params,
+    Err(_) => return true,
+  };
+
+  let wanted = &hasher.params;
+  let wanted_keyid = hasher.pepper.as_ref().map_or(&[][..], |pepper| pepper.id.as_bytes());
+  algorithm != hasher.algorithm
+    || version != Version::default()
+    || params.m_cost() < wanted.m_cost()
+    || params.t_cost() < wanted.t_cost()
+    || params.p_cost() < wanted.p_cost()
+    || params.keyid() != wanted_keyid
+}
+
+#[cfg(test)]
+mod test {
+  use argon2::{Algorithm, Params};
+
+  use super::{hash_with, needs_rehash_for, verify_with, Hasher, Pepper};
+
+  fn hasher(algorithm: Algorithm, m_cost: u32, t_cost: u32) -> Hasher {
+    Hasher::new(algorithm, Params::new(m_cost, t_cost, 1, None).unwrap())
+  }
+
+  fn peppered(pepper: &str, retired_peppers: &[&str]) -> Hasher {
+    let mut hasher = hasher(Algorithm::Argon2id, 1024, 1);
+    hasher.pepper = Some(pepper.parse().unwrap());
+    hasher.retired_peppers = retired_peppers.iter().map(|pepper| pepper.parse().unwrap()).collect();
+    hasher
+  }
+
+  #[test]
+  fn verifies_hashes_made_with_other_parameters() {
+    let current = hasher(Algorithm::Argon2id, 8192, 3);
+    let digest = hash_with(&hasher(Algorithm::Argon2i, 1024, 1), "testtesttest");
+    assert!(verify_with(&current, &digest, "testtesttest"));
+    assert!(!verify_with(&current, &digest, "wrongpassword"));
+  }
+
+  #[test]
+  fn rehashes_weaker_digests() {
+    let current = hasher(Algorithm::Argon2id, 8192, 3);
+
+    assert!(!needs_rehash_for(&current, &hash_with(&current, "testtesttest")));
+    assert!(!needs_rehash_for(
+      &current,
+      &hash_with(&hasher(Algorithm::Argon2id, 16384, 4), "testtesttest")
+    ));
+    assert!(needs_rehash_for(
+      &current,
+      &hash_with(&hasher(Algorithm::Argon2id, 4096, 3), "testtesttest")
+    ));
+    assert!(needs_rehash_for(
+      &current,
+      &hash_with(&hasher(Algorithm::Argon2id, 8192, 2), "testtesttest")
+    ));
+    assert!(needs_rehash_for(
+      &current,
+      &hash_with(&hasher(Algorithm::Argon2i, 8192, 3), "testtesttest")
+    ));
+    assert!(needs_rehash_for(&current, "not a digest"));
+  }
+
+  #[test]
+  fn peppered_hashes_need_their_pepper() {
+    let digest = hash_with(&peppered("one:firstsecret", &[]), "testtesttest");
+    assert!(digest.contains("keyid="));
+
+    assert!(verify_with(&peppered("one:firstsecret", &[]), &digest, "testtesttest"));
+    assert!(!verify_with(
+      &peppered("one:firstsecret", &[]),
+      &digest,
+      "wrongpassword"
+    ));
+    assert!(!verify_with(&peppered("one:othersecret", &[]), &digest, "testtesttest"));
+    assert!(!verify_with(
+      &peppered("two:secondsecret", &[]),
+      &digest,
+      "testtesttest"
+    ));
+    assert!(!verify_with(
+      &hasher(Algorithm::Argon2id, 1024, 1),
+      &digest,
+      "testtesttest"
+    ));
+  }
+
+  #[test]
+  fn rotated_peppers_are_upgraded() {
+    let unpeppered = hash_with(&hasher(Algorithm::Argon2id, 1024, 1), "testtesttest");
+    let old = hash_with(&peppered("one:firstsecret", &[]), "testtesttest");
+    let rotated = peppered("two:secondsecret", &["one:firstsecret"]);
+
+    assert!(verify_with(&rotated, &unpeppered, "testtesttest"));
+    assert!(verify_with(&rotated, &old, "testtesttest"));
+    assert!(needs_rehash_for(&rotated, &unpeppered));
+    assert!(needs_rehash_for(&rotated, &old));
+    assert!(!needs_rehash_for(&rotated, &hash_with(&rotated, "testtesttest")));
+  }
+
+  #[test]
+  fn pepper_ids_are_checked() {
+    assert!("abc:secret".parse::<Pepper>().is_ok());
+    assert!("toolongid:secret".parse::<Pepper>().is_err());
+    assert!(":secret".parse::<Pepper>().is_err());
+    assert!("abc:".parse::<Pepper>().is_err());
+    assert!("secret".parse::<Pepper>().is_err());
+  }
+}
@@ -0,0 +1,521 @@
+use std::collections::HashSet;
+use std::env;
+
+use serde::Serialize;
+
+use crate::util::parse_env;
+
+/// Passwords rejected everywhere, one per line. `PASSWORD_BLOCKLIST_FILE` can
+/// add more, such as a dump of breached passwords.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+/// Usernames nobody may register, one per line, compared by
+/// [`username_skeleton`]. `USERNAME_RESERVED_FILE` can add more.
+const RESERVED_USERNAMES: &str = include_str!("reserved_usernames.txt");
+
+const DEFAULT_MIN_LENGTH: usize = 12;
+const DEFAULT_MAX_LENGTH: usize = 128;
+const DEFAULT_MIN_ENTROPY: f64 = 30.0;
+/// Names shorter than this are too common to forbid inside passwords.
+const MIN_PERSONAL_INFO_LEN: usize = 3;
+const DEFAULT_USERNAME_MIN_LENGTH: usize = 3;
+/// Also the size of the `users.username` column.
+const DEFAULT_USERNAME_MAX_LENGTH: usize = 100;
+const DEFAULT_COMPANY_MIN_LENGTH: usize = 3;
+/// Also the size of the `users.name` column.
+const DEFAULT_COMPANY_MAX_LENGTH: usize = 100;
+
+lazy_static::lazy_static! {
+  pub static ref PASSWORD_POLICY: PasswordPolicy = PasswordPolicy::from_env();
+  pub static ref NAME_POLICY: NamePolicy = NamePolicy::from_env();
+}
+
+/// A broken validation rule. `code` is stable for clients to switch on while
+/// `message` is meant for people.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+  pub code: &'static str,
+  pub message: String,
+}
+
+impl Violation {
+  pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+    Self {
+      code,
+      message: message.into(),
+    }
+  }
+}
+
+pub struct PasswordPolicy {
+  pub min_length: usize,
+  pub max_length: usize,
+  /// Rough guessing resistance in bits, see [`estimate_entropy`].
+  pub min_entropy: f64,
+  blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+  pub const TOO_SHORT: &'static str = "password_too_short";
+  pub const TOO_LONG: &'static str = "password_too_long";
+  pub const TOO_WEAK: &'static str = "password_too_weak";
+  pub const PERSONAL_INFO: &'static str = "password_contains_personal_info";
+  pub const BREACHED: &'static str = "password_breached";
+
+  pub fn new(min_length: usize, max_length: usize, min_entropy: f64) -> Self {
+    Self {
+      min_length,
+      max_length,
+      min_entropy,
+      blocklist: COMMON_PASSWORDS.lines().map(str::to_lowercase).collect(),
+    }
+  }
+
+  /// Reads `PASSWORD_MIN_LENGTH`, `PASSWORD_MAX_LENGTH`, `PASSWORD_MIN_ENTROPY`
+  /// and `PASSWORD_BLOCKLIST_FILE`.
+  pub fn from_env() -> Self {
+    let mut policy = Self::new(
+      parse_env("PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH),
+      parse_env("PASSWORD_MAX_LENGTH", DEFAULT_MAX_LENGTH),
+      parse_env("PASSWORD_MIN_ENTROPY", DEFAULT_MIN_ENTROPY),
+    );
+    if let Ok(path) = env::var("PASSWORD_BLOCKLIST_FILE") {
+      let list = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Failed to read PASSWORD_BLOCKLIST_FILE {}. {}", path, err));
+      policy.extend_blocklist(list.lines());
+    }
+    policy
+  }
+
+  pub fn extend_blocklist<'a>(&mut self, passwords: impl Iterator<Item = &'a str>) {
+    self.blocklist.extend(
+      passwords
+        .map(|password| password.trim().to_lowercase())
+        .filter(|password| !password.is_empty()),
+    );
+  }
+
+  /// Checks `password` against every rule. `personal_info` holds the
+  /// username, company name and the like, none of which may appear in it.
+  pub fn check(&self, password: &str, personal_info: &[&str]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let length = password.chars().count();
+    if length < self.min_length {
+      violations.push(Violation::new(
+        Self::TOO_SHORT,
+        format!("Password must be at least {} characters long", self.min_length),
+      ));
+    }
+    if length > self.max_length {
+      violations.push(Violation::new(
+        Self::TOO_LONG,
+        format!("Password must be at most {} characters long", self.max_length),
+      ));
+    }
+
+    let lowercase = password.to_lowercase();
+    if self.blocklist.contains(&lowercase) {
+      violations.push(Violation::new(
+        Self::BREACHED,
+        "Password is too common, it appears in lists of leaked passwords",
+      ));
+    } else if estimate_entropy(password) < self.min_entropy {
+      violations.push(Violation::new(Self::TOO_WEAK, "Password is too easy to guess"));
+    }
+
+    let compact: String = lowercase.chars().filter(|c| !c.is_whitespace()).collect();
+    let contains_personal_info = personal_info.iter().any(|info| {
+      let info: String = info.to_lowercase().chars().filter(|c| !c.is_whitespace()).collect();
+      info.chars().count() >= MIN_PERSONAL_INFO_LEN && compact.contains(&info)
+    });
+    if contains_personal_info {
+      violations.push(Violation::new(
+        Self::PERSONAL_INFO,
+        "Password must not contain your username or company name",
+      ));
+    }
+
+    violations
+  }
+}
+
+/// Rules for usernames and company names. Lengths count characters rather
+/// than bytes.
+pub struct NamePolicy {
+  pub username_min_length: usize,
+  pub username_max_length: usize,
+  /// Allow letters and digits of any script in usernames instead of only
+  /// ASCII ones.
+  pub allow_unicode: bool,
+  /// Characters allowed in usernames besides letters and digits.
+  pub extra_characters: Vec<char>,
+  pub company_min_length: usize,
+  pub company_max_length: usize,
+  /// Skeletons of the reserved usernames.
+  reserved: HashSet<String>,
+}
+
+impl NamePolicy {
+  pub const USERNAME_TOO_SHORT: &'static str = "username_too_short";
+  pub const USERNAME_TOO_LONG: &'static str = "username_too_long";
+  pub const USERNAME_INVALID_CHARACTERS: &'static str = "username_invalid_characters";
+  pub const USERNAME_RESERVED: &'static str = "username_reserved";
+  pub const COMPANY_TOO_SHORT: &'static str = "name_too_short";
+  pub const COMPANY_TOO_LONG: &'static str = "name_too_long";
+  pub const COMPANY_INVALID_CHARACTERS: &'static str = "name_invalid_characters";
+
+  pub fn new(
+    username_min_length: usize,
+    username_max_length: usize,
+    company_min_length: usize,
+    company_max_length: usize,
+  ) -> Self {
+    let mut policy = Self {
+      username_min_length,
+      username_max_length,
+      allow_unicode: false,
+      extra_characters: Vec::new(),
+      company_min_length,
+      company_max_length,
+      reserved: HashSet::new(),
+    };
+    policy.extend_reserved(RESERVED_USERNAMES.lines());
+    policy
+  }
+
+  /// Reads `USERNAME_MIN_LENGTH`, `USERNAME_MAX_LENGTH`, `USERNAME_ALLOW_UNICODE`,
+  /// `USERNAME_EXTRA_CHARACTERS`, `USERNAME_RESERVED_FILE`,
+  /// `COMPANY_NAME_MIN_LENGTH` and `COMPANY_NAME_MAX_LENGTH`.
+  pub fn from_env() -> Self {
+    let mut policy = Self::new(
+      parse_env("USERNAME_MIN_LENGTH", DEFAULT_USERNAME_MIN_LENGTH),
+      parse_env("USERNAME_MAX_LENGTH", DEFAULT_USERNAME_MAX_LENGTH),
+      parse_env("COMPANY_NAME_MIN_LENGTH", DEFAULT_COMPANY_MIN_LENGTH),
+      parse_env("COMPANY_NAME_MAX_LENGTH", DEFAULT_COMPANY_MAX_LENGTH),
+    );
+    policy.allow_unicode = parse_env("USERNAME_ALLOW_UNICODE", false);
+    policy.extra_characters = env::var("USERNAME_EXTRA_CHARACTERS")
+      .map(|chars| chars.chars().collect())
+      .unwrap_or_default();
+    // Logins containing an @ are looked up by email
+    if policy
+      .extra_characters
+      .iter()
+      .any(|c| *c == '@' || c.is_whitespace() || c.is_control())
+    {
+      panic!("USERNAME_EXTRA_CHARACTERS may not contain @, whitespace or control characters");
+    }
+    if let Ok(path) = env::var("USERNAME_RESERVED_FILE") {
+      let list = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Failed to read USERNAME_RESERVED_FILE {}. {}", path, err));
+      policy.extend_reserved(list.lines());
+    }
+    policy
+  }
+
+  pub fn extend_reserved<'a>(&mut self, usernames: impl Iterator<Item = &'a str>) {
+    self.reserved.extend(
+      usernames
+        .map(|username| username_skeleton(username.trim()))
+        .filter(|skeleton| !skeleton.is_empty()),
+    );
+  }
+
+  /// Whether `username` is, or looks like, a reserved name.
+  pub fn is_reserved(&self, username: &str) -> bool {
+    self.reserved.contains(&username_skeleton(username))
+  }
+
+  pub fn check_username(&self, username: &str) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let length = username.chars().count();
+    if length < self.username_min_length {
+      violations.push(Violation::new(
+        Self::USERNAME_TOO_SHORT,
+        format!("Username must be at least {} characters long", self.username_min_length),
+      ));
+    }
+    if length > self.username_max_length {
+      violations.push(Violation::new(
+        Self::USERNAME_TOO_LONG,
+        format!("Username must be at most {} characters long", self.username_max_length),
+      ));
+    }
+
+    let allowed = |c: char| {
+      if self.allow_unicode {
+        c.is_alphanumeric()
+      } else {
+        c.is_ascii_alphanumeric()
+      }
+    };
+    if !username
+      .chars()
+      .all(|c| allowed(c) || self.extra_characters.contains(&c))
+    {
+      let mut message = String::from("Username may only contain letters and digits");
+      if !self.extra_characters.is_empty() {
+        let extra: String = self.extra_characters.iter().collect();
+        message.push_str(&format!(" and any of {}", extra));
+      }
+      violations.push(Violation::new(Self::USERNAME_INVALID_CHARACTERS, message));
+    } else if self.is_reserved(username) {
+      violations.push(Violation::new(Self::USERNAME_RESERVED, "Username is reserved"));
+    }
+
+    violations
+  }
+
+  pub fn check_company_name(&self, name: &str) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let length = name.trim().chars().count();
+    if length < self.company_min_length {
+      violations.push(Violation::new(
+        Self::COMPANY_TOO_SHORT,
+        format!(
+          "Company Name must be at least {} characters long",
+          self.company_min_length
+        ),
+      ));
+    }
+    if name.chars().count() > self.company_max_length {
+      violations.push(Violation::new(
+        Self::COMPANY_TOO_LONG,
+        format!(
+          "Company Name must be at most {} characters long",
+          self.company_max_length
+        ),
+      ));
+    }
+    if name.chars().any(char::is_control) {
+      violations.push(Violation::new(
+        Self::COMPANY_INVALID_CHARACTERS,
+        "Company Name must not contain control characters",
+      ));
+    }
+
+    violations
+  }
+}
+
+/// Reduces a username to a form shared by names that look alike, such as
+/// `admin`, `Adm1n` and `adrnin`. Case, separators and common homoglyphs,
+/// including Cyrillic and Greek letters resembling Latin ones, are folded
+/// away.
+///
+/// Keep the ASCII folding in step with the backfill in the
+/// `add_username_skeletons` migration.
+pub fn username_skeleton(username: &str) -> String {
+  let folded: String = username
+    .chars()
+    .flat_map(char::to_lowercase)
+    .filter(|c| c.is_alphanumeric())
+    .map(|c| match c {
+      '0' | 'о' | 'ο' => 'o',
+      '1' | 'i' | 'ı' | 'і' | 'ι' | 'ӏ' => 'l',
+      '2' => 'z',
+      '5' | 'ѕ' => 's',
+      'а' | 'α' => 'a',
+      'в' | 'β' => 'b',
+      'с' | 'ϲ' => 'c',
+      'ԁ' => 'd',
+      'е' | 'ε' => 'e',
+      'ɡ' => 'g',
+      'һ' => 'h',
+      'ј' => 'j',
+      'к' | 'κ' => 'k',
+      'м' => 'm',
+      'п' | 'η' => 'n',
+      'р' | 'ρ' => 'p',
+      'т' | 'τ' => 't',
+      'υ' => 'u',
+      'ν' => 'v',
+      'ω' => 'w',
+      'х' | 'χ' => 'x',
+      'у' | 'γ' => 'y',
+      c => c,
+    })
+    .collect();
+  folded.replace("rn", "m").replace("vv", "w").replace("cl", "d")
+}
+
+/// Estimates the bits of guessing work for a password from the character
+/// classes it uses. Characters already seen earlier in the password only add
+/// 2 bits, so repetition does not pass for strength.
+pub fn estimate_entropy(password: &str) -> f64 {
+  let mut pool = 0;
+  if password.chars().any(|c| c.is_ascii_lowercase()) {
+    pool += 26;
+  }
+  if password.chars().any(|c| c.is_ascii_uppercase()) {
+    pool += 26;
+  }
+  if password.chars().any(|c| c.is_ascii_digit()) {
+    pool += 10;
+  }
+  if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+    pool += 33;
+  }
+  if !password.is_ascii() {
+    pool += 100;
+  }
+  if pool == 0 {
+    return 0.0;
+  }
+
+  let bits_per_char = (pool as f64).log2();
+  let mut seen = HashSet::new();
+  password
+    .chars()
+    .map(|c| if seen.insert(c) { bits_per_char } else { 2.0 })
+    .sum()
+}
+
+#[cfg(test)]
+mod test {
+  use super::{estimate_entropy, username_skeleton, NamePolicy, PasswordPolicy};
+
+  fn codes(policy: &PasswordPolicy, password: &str, personal_info: &[&str]) -> Vec<&'static str> {
+    policy
+      .check(password, personal_info)
+      .into_iter()
+      .map(|violation| violation.code)
+      .collect()
+  }
+
+  #[test]
+  fn accepts_reasonable_passwords() {
+    let policy = PasswordPolicy::new(12, 128, 30.0);
+    assert!(codes(&policy, "testtesttest", &["tester"]).is_empty());
+    assert!(codes(&policy, "correct horse battery", &["tester", "Tester McTester"]).is_empty());
+  }
+
+  #[test]
+  fn checks_length_in_characters() {
+    let policy = PasswordPolicy::new(12, 16, 0.0);
+    assert_eq!(codes(&policy, "short", &[]), vec![PasswordPolicy::TOO_SHORT]);
+    assert_eq!(codes(&policy, "ünïcödé", &[]), vec![PasswordPolicy::TOO_SHORT]);
+    assert!(codes(&policy, "ünïcödéünïcödé", &[]).is_empty());
+    assert_eq!(
+      codes(&policy, "waytoolongforthispolicy", &[]),
+      vec![PasswordPolicy::TOO_LONG]
+    );
+  }
+
+  #[test]
+  fn rejects_guessable_passwords() {
+    let policy = PasswordPolicy::new(8, 128, 30.0);
+    assert_eq!(codes(&policy, "aaaaaaaaaaaa", &[]), vec![PasswordPolicy::BREACHED]);
+    assert_eq!(codes(&policy, "bbbbbbbbbbbb", &[]), vec![PasswordPolicy::TOO_WEAK]);
+    assert_eq!(codes(&policy, "Password123", &[]), vec![PasswordPolicy::BREACHED]);
+    assert!(estimate_entropy("Tr0ub4dor&3") > estimate_entropy("troubadour"));
+  }
+
+  #[test]
+  fn rejects_personal_info() {
+    let policy = PasswordPolicy::new(12, 128, 0.0);
+    assert_eq!(
+      codes(&policy, "MyNameIsTester1", &["tester"]),
+      vec![PasswordPolicy::PERSONAL_INFO]
+    );
+    assert_eq!(
+      codes(&policy, "acmewidgets2024", &["someone", "Acme Widgets"]),
+      vec![PasswordPolicy::PERSONAL_INFO]
+    );
+    // Very short names are too likely to match by accident
+    assert!(codes(&policy, "boxofchocolates", &["bo"]).is_empty());
+  }
+
+  #[test]
+  fn blocklist_can_be_extended() {
+    let mut policy = PasswordPolicy::new(12, 128, 0.0);
+    assert!(codes(&policy, "leakedpassword", &[]).is_empty());
+    policy.extend_blocklist("leakedpassword\n\n".lines());
+    assert_eq!(codes(&policy, "LeakedPassword", &[]), vec![PasswordPolicy::BREACHED]);
+  }
+
+  fn name_codes(violations: Vec<super::Violation>) -> Vec<&'static str> {
+    violations.into_iter().map(|violation| violation.code).collect()
+  }
+
+  #[test]
+  fn skeletons_fold_lookalikes() {
+    assert_eq!(username_skeleton("Admin"), "admln");
+    assert_eq!(username_skeleton("adm1n"), username_skeleton("admin"));
+    assert_eq!(username_skeleton("adrnin"), username_skeleton("admin"));
+    assert_eq!(username_skeleton("r00t"), username_skeleton("root"));
+    assert_eq!(username_skeleton("vvizard"), username_skeleton("wizard"));
+    // Cyrillic а and о
+    assert_eq!(username_skeleton("\u{430}dmin"), username_skeleton("admin"));
+    assert_eq!(username_skeleton("r\u{43e}ot"), username_skeleton("root"));
+    assert_ne!(username_skeleton("tester"), username_skeleton("testers"));
+  }
+
+  #[test]
+  fn reserved_usernames_are_rejected() {
+    let mut policy = NamePolicy::new(3, 100, 3, 100);
+    policy.extra_characters = vec!['_'];
+    assert_eq!(
+      name_codes(policy.check_username("admin")),
+      vec![NamePolicy::USERNAME_RESERVED]
+    );
+    assert_eq!(
+      name_codes(policy.check_username("FizzBuzz")),
+      vec![NamePolicy::USERNAME_RESERVED]
+    );
+    assert_eq!(
+      name_codes(policy.check_username("r_o_o_t")),
+      vec![NamePolicy::USERNAME_RESERVED]
+    );
+    assert!(policy.check_username("administrators").is_empty());
+
+    assert!(policy.check_username("acmecorp").is_empty());
+    policy.extend_reserved("AcmeCorp\n\n".lines());
+    assert_eq!(
+      name_codes(policy.check_username("acmec0rp")),
+      vec![NamePolicy::USERNAME_RESERVED]
+    );
+  }
+
+  #[test]
+  fn username_characters_follow_the_policy() {
+    let mut policy = NamePolicy::new(3, 100, 3, 100);
+    assert!(policy.check_username("tester2").is_empty());
+    assert_eq!(
+      name_codes(policy.check_username("test_er")),
+      vec![NamePolicy::USERNAME_INVALID_CHARACTERS]
+    );
+    assert_eq!(
+      name_codes(policy.check_username("tëster")),
+      vec![NamePolicy::USERNAME_INVALID_CHARACTERS]
+    );
+
+    policy.extra_characters = vec!['_', '-'];
+    policy.allow_unicode = true;
+    assert!(policy.check_username("test_er").is_empty());
+    assert!(policy.check_username("tëster").is_empty());
+    assert_eq!(
+      name_codes(policy.check_username("test.er")),
+      vec![NamePolicy::USERNAME_INVALID_CHARACTERS]
+    );
+  }
+
+  #[test]
+  fn name_lengths_count_characters() {
+    let mut policy = NamePolicy::new(3, 5, 3, 5);
+    policy.allow_unicode = true;
+    assert!(policy.check_username("äöü").is_empty());
+    assert_eq!(
+      name_codes(policy.check_username("äö")),
+      vec![NamePolicy::USERNAME_TOO_SHORT]
+    );
+    assert!(policy.check_company_name("Ünï").is_empty());
+    assert_eq!(
+      name_codes(policy.check_company_name("Ünïcödé")),
+      vec![NamePolicy::COMPANY_TOO_LONG]
+    );
+    assert_eq!(
+      name_codes(policy.check_company_name("Acme\n")),
+      vec![NamePolicy::COMPANY_INVALID_CHARACTERS]
+    );
+  }
+}
@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+
+/// Prune expired windows once this many keys are tracked.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Fixed window request counter kept in memory, so limits start over when
+/// the server restarts.
+pub struct RateLimiter {
+  max: u32,
+  window: Duration,
+  hits: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+  pub fn new(max: u32, window: Duration) -> Self {
+    Self {
+      max,
+      window,
+      hits: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Counts a request against `key`. Once over the limit, returns how long
+  /// until the window resets.
+  pub fn check(&self, key: &str) -> Result<(), Duration> {
+    let now = Instant::now();
+    let mut hits = self.hits.lock().unwrap();
+    if hits.len() >= PRUNE_THRESHOLD {
+      hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+    }
+
+    let (start, count) = hits.entry(key.to_string()).or_insert((now, 0));
+    if now.duration_since(*start) >= self.window {
+      *start = now;
+      *count = 0;
+    }
+    if *count >= self.max {
+      return Err(self.window - now.duration_since(*start));
+    }
+
+    *count += 1;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::RateLimiter;
+
+  #[test]
+  fn limits_each_key_separately() {
+    let limiter = RateLimiter::new(2, Duration::from_secs(60));
+    assert!(limiter.check("a").is_ok());
+    assert!(limiter.check("a").is_ok());
+    let retry_after = limiter.check("a").unwrap_err();
+    assert!(retry_after <= Duration::from_secs(60));
+    assert!(limiter.check("b").is_ok());
+  }
+
+  #[test]
+  fn window_resets() {
+    let limiter = RateLimiter::new(1, Duration::from_millis(10));
+    assert!(limiter.check("a").is_ok());
+    assert!(limiter.check("a").is_err());
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(limiter.check("a").is_ok());
+  }
+}
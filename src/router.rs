@@ -46,20 +46,62 @@ impl RouteBuilder {
   }
 
   pub fn not_found_route(self, route: RouteFuture) -> Router {
+    let (param_routes, routes): (Vec<_>, Vec<_>) = self
+      .routes
+      .into_iter()
+      .partition(|(Route(_, path), _)| path.contains('{'));
+
     Router {
-      routes: self.routes.into_iter().collect(),
+      routes: routes.into_iter().collect(),
+      param_routes: param_routes
+        .into_iter()
+        .map(|(Route(method, path), func)| (method, split_path(&path).map(str::to_string).collect(), func))
+        .collect(),
       not_found_route: route,
     }
   }
 }
 
+/// Values captured from `{name}` segments of a matched route, stored in the
+/// request extensions.
+#[derive(Debug, Default, Clone)]
+pub struct PathParams(HashMap<String, String>);
+
+impl PathParams {
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.0.get(name).map(String::as_str)
+  }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+  path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn match_path(pattern: &[String], path: &str) -> Option<PathParams> {
+  let segments: Vec<&str> = split_path(path).collect();
+  if segments.len() != pattern.len() {
+    return None;
+  }
+
+  let mut params = HashMap::new();
+  for (expected, actual) in pattern.iter().zip(segments) {
+    if let Some(name) = expected.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+      params.insert(name.to_string(), actual.to_string());
+    } else if expected != actual {
+      return None;
+    }
+  }
+  Some(PathParams(params))
+}
+
 pub struct Router {
   routes: HashMap<Route, RouteFuture>,
+  param_routes: Vec<(Method, Vec<String>, RouteFuture)>,
   not_found_route: RouteFuture,
 }
 
 impl Router {
-  pub async fn route(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  pub async fn route(&self, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
     let route = Route(req.method().clone(), Cow::Owned(req.uri().path().to_string()));
     debug!("Begin Request {}", &route);
     let start = Instant::now();
@@ -67,7 +109,10 @@ impl Router {
     let span = span!(Level::TRACE, "request");
     let result = {
       let _guard = span.enter();
-      let func = self.routes.get(&route).unwrap_or(&self.not_found_route);
+      let func = match self.routes.get(&route) {
+        Some(func) => func,
+        None => self.find_param_route(&mut req).unwrap_or(&self.not_found_route),
+      };
       func(req).await
     };
     let time_to_complete = start.elapsed();
@@ -85,6 +130,17 @@ impl Router {
   pub fn builder() -> RouteBuilder {
     RouteBuilder::new()
   }
+
+  fn find_param_route(&self, req: &mut Request<Body>) -> Option<&RouteFuture> {
+    self.param_routes.iter().find_map(|(method, pattern, func)| {
+      if method != req.method() {
+        return None;
+      }
+      let params = match_path(pattern, req.uri().path())?;
+      req.extensions_mut().insert(params);
+      Some(func)
+    })
+  }
 }
 
 #[macro_export]
@@ -0,0 +1,139 @@
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::policy::Violation;
+
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+  /// Id of the request being handled, set by `handle_requests`.
+  static REQUEST_ID: String;
+}
+
+/// Body of every error response, so clients can switch on `code` whatever
+/// the endpoint. `message` is meant for people and may change.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+  pub code: &'static str,
+  pub message: String,
+  /// Every rule the request broke, for errors concerning individual fields.
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub details: Vec<Violation>,
+  /// Also sent as the `X-Request-Id` header and attached to the server logs.
+  pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+  pub const INTERNAL: &'static str = "internal_error";
+  pub const MALFORMED_BODY: &'static str = "malformed_body";
+  pub const VALIDATION_FAILED: &'static str = "validation_failed";
+  pub const NOT_FOUND: &'static str = "not_found";
+  pub const NOT_ENABLED: &'static str = "not_enabled";
+  pub const TOO_MANY_REQUESTS: &'static str = "too_many_requests";
+  pub const INVALID_CREDENTIALS: &'static str = "invalid_credentials";
+  pub const INVALID_PASSWORD: &'static str = "invalid_password";
+
+  pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+    Self {
+      code,
+      message: message.into(),
+      details: Vec::new(),
+      request_id: request_id(),
+    }
+  }
+
+  pub fn into_response(self, status: StatusCode) -> Response<Body> {
+    Response::builder()
+      .status(status)
+      .header("Content-Type", "application/json")
+      .body(Body::from(serde_json::to_string(&self).unwrap()))
+      .unwrap()
+  }
+}
+
+pub fn error_response(status: StatusCode, code: &'static str, message: impl Into<String>) -> Response<Body> {
+  ErrorBody::new(code, message).into_response(status)
+}
+
+/// `400 Bad Request` listing every rule the request broke.
+pub fn validation_response(details: Vec<Violation>) -> Response<Body> {
+  ErrorBody {
+    details,
+    ..ErrorBody::new(ErrorBody::VALIDATION_FAILED, "Request is invalid")
+  }
+  .into_response(StatusCode::BAD_REQUEST)
+}
+
+/// Id of the request being handled, `None` outside of `handle_requests`.
+pub fn request_id() -> Option<String> {
+  REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Takes the client's `X-Request-Id` when it is short and printable so
+/// requests can be traced across services, otherwise makes a new one.
+pub fn choose_request_id(req: &Request<Body>) -> String {
+  req
+    .headers()
+    .get("X-Request-Id")
+    .and_then(|value| value.to_str().ok())
+    .filter(|id| {
+      !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    })
+    .map(str::to_string)
+    .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Runs `handler` with `id` as the current request id.
+pub async fn with_request_id<F: std::future::Future>(id: String, handler: F) -> F::Output {
+  REQUEST_ID.scope(id, handler).await
+}
+
+#[cfg(test)]
+pub mod test {
+  use hyper::{Method, StatusCode};
+
+  use crate::routes::handle_requests;
+  use crate::routes::test::build_test_request;
+
+  /// The `code` of an error body.
+  pub fn error_code(body: impl AsRef<[u8]>) -> String {
+    let body = body.as_ref();
+    let body: serde_json::Value = serde_json::from_slice(body)
+      .unwrap_or_else(|_| panic!("Error body is not JSON: {}", String::from_utf8_lossy(body)));
+    body["code"].as_str().unwrap().to_string()
+  }
+
+  #[tokio::test]
+  async fn errors_carry_the_request_id() {
+    let res = handle_requests(build_test_request(Method::GET, "/fakepath", "", None))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let header = res.headers()["X-Request-Id"].to_str().unwrap().to_string();
+    assert_eq!(res.headers()["Content-Type"], "application/json");
+
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "not_found");
+    assert_eq!(body["request_id"], header.as_str());
+    assert!(body.get("details").is_none());
+  }
+
+  #[tokio::test]
+  async fn client_request_ids_are_kept_when_sane() {
+    let mut req = build_test_request(Method::GET, "/fakepath", "", None);
+    req.headers_mut().insert("X-Request-Id", "trace-1234".parse().unwrap());
+    let res = handle_requests(req).await.unwrap();
+    assert_eq!(res.headers()["X-Request-Id"], "trace-1234");
+
+    let mut req = build_test_request(Method::GET, "/fakepath", "", None);
+    req.headers_mut().insert("X-Request-Id", "not ok\t".parse().unwrap());
+    let res = handle_requests(req).await.unwrap();
+    assert_ne!(res.headers()["X-Request-Id"], "not ok\t");
+  }
+}
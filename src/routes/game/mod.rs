@@ -10,7 +10,9 @@ use super::util::get_user_by_auth_header;
 use super::DB;
 use crate::models::create_game_instruction;
 use crate::router::{Routable, RoutedFunction};
-use crate::{respond, route_func};
+use crate::routes::error::ErrorBody;
+use crate::scope::Scope;
+use crate::{respond, respond_error, route_func};
 
 mod resp;
 
@@ -24,7 +26,7 @@ pub struct InstructionResponse {
 pub async fn post_next_instruction(req: Request<Body>) -> Result<Response<Body>, Infallible> {
   let db = DB.lock().await;
 
-  let user = get_user_by_auth_header(&db, &req);
+  let user = get_user_by_auth_header(&db, &req, Scope::GamePlay);
   if let Err(res) = user {
     return Ok(res);
   }
@@ -59,7 +61,7 @@ pub async fn post_next_instruction(req: Request<Body>) -> Result<Response<Body>,
           .unwrap(),
       )
     },
-    Err(_) => respond!(StatusCode::INTERNAL_SERVER_ERROR, "Unknown error occured."),
+    Err(_) => respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error"),
   }
 }
 
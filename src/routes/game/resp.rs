@@ -5,9 +5,11 @@ use serde::Deserialize;
 use uuid::Uuid;
 
 use crate::models::{update_buzz, update_fizz, update_instructions};
-use crate::respond;
+use crate::routes::error::ErrorBody;
 use crate::routes::util::get_user_by_auth_header;
 use crate::routes::DB;
+use crate::scope::Scope;
+use crate::{respond, respond_error};
 
 #[derive(Deserialize)]
 struct TokenBody {
@@ -18,19 +20,19 @@ async fn read_body_for_token(req: Request<Body>) -> Result<Uuid, Result<Response
   let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
   let token_body = serde_json::from_slice(&body);
   if let Err(err) = token_body {
-    return Err(respond!(StatusCode::BAD_REQUEST, err.to_string()));
+    return Err(respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string()));
   }
   let token_body: TokenBody = token_body.unwrap();
   match Uuid::parse_str(token_body.token.as_str()) {
     Ok(uuid) => Ok(uuid),
-    Err(err) => Err(respond!(StatusCode::BAD_REQUEST, err.to_string())),
+    Err(err) => Err(respond_error!(StatusCode::BAD_REQUEST, "invalid_token", err.to_string())),
   }
 }
 
 pub async fn post_fizz(req: Request<Body>) -> Result<Response<Body>, Infallible> {
   let db = DB.lock().await;
 
-  let user = get_user_by_auth_header(&db, &req);
+  let user = get_user_by_auth_header(&db, &req, Scope::GamePlay);
   if let Err(res) = user {
     return Ok(res);
   }
@@ -44,14 +46,14 @@ pub async fn post_fizz(req: Request<Body>) -> Result<Response<Body>, Infallible>
 
   match update_fizz(&db, user.id, token) {
     Ok(_) => respond!(StatusCode::OK, "Fizz: Instruction Received!"),
-    Err(_) => respond!(StatusCode::INTERNAL_SERVER_ERROR, "An internal error occured"),
+    Err(_) => respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error"),
   }
 }
 
 pub async fn post_buzz(req: Request<Body>) -> Result<Response<Body>, Infallible> {
   let db = DB.lock().await;
 
-  let user = get_user_by_auth_header(&db, &req);
+  let user = get_user_by_auth_header(&db, &req, Scope::GamePlay);
   if let Err(res) = user {
     return Ok(res);
   }
@@ -65,14 +67,14 @@ pub async fn post_buzz(req: Request<Body>) -> Result<Response<Body>, Infallible>
 
   match update_buzz(&db, user.id, token) {
     Ok(_) => respond!(StatusCode::OK, "Buzz: Instruction Received!"),
-    Err(_) => respond!(StatusCode::INTERNAL_SERVER_ERROR, "An internal error occured"),
+    Err(_) => respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error"),
   }
 }
 
 pub async fn post_instructions(req: Request<Body>) -> Result<Response<Body>, Infallible> {
   let db = DB.lock().await;
 
-  let user = get_user_by_auth_header(&db, &req);
+  let user = get_user_by_auth_header(&db, &req, Scope::GamePlay);
   if let Err(res) = user {
     return Ok(res);
   }
@@ -86,7 +88,7 @@ pub async fn post_instructions(req: Request<Body>) -> Result<Response<Body>, Inf
 
   match update_instructions(&db, user.id, token) {
     Ok(_) => respond!(StatusCode::OK, "Instruction Received for Rescheduling"),
-    Err(_) => respond!(StatusCode::INTERNAL_SERVER_ERROR, "An internal error occured"),
+    Err(_) => respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error"),
   }
 }
 
@@ -2,22 +2,29 @@ use std::convert::Infallible;
 
 use diesel::{Connection, PgConnection};
 use futures::FutureExt;
+use hyper::header::HeaderValue;
 use hyper::{Body, Request, Response, StatusCode};
 use lazy_static::lazy_static;
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, info_span, Instrument};
 
-use crate::respond;
+use crate::respond_error;
 use crate::router::Router;
+use crate::routes::error::{choose_request_id, with_request_id, ErrorBody};
 use crate::routes::game::GameRouter;
 use crate::routes::health::HealthRouter;
+use crate::routes::oauth::OAuthRouter;
 use crate::routes::users::UserRouter;
+use crate::routes::well_known::WellKnownRouter;
 use crate::util::get_db_url;
 
+pub mod error;
 pub mod game;
 pub mod health;
+pub mod oauth;
 pub mod users;
 pub mod util;
+pub mod well_known;
 
 lazy_static! {
   // This connection sometimes closes. Consider a pool method.
@@ -28,6 +35,8 @@ lazy_static! {
       .add_routes(&UserRouter)
       .add_routes(&GameRouter)
       .add_routes(&HealthRouter)
+      .add_routes(&WellKnownRouter)
+      .add_routes(&OAuthRouter)
       .not_found_route(|req| not_found_route(req).boxed())
   };
 }
@@ -46,11 +55,19 @@ fn establish_connection() -> PgConnection {
 }
 
 pub async fn handle_requests(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-  ROUTER.route(req).await
+  let request_id = choose_request_id(&req);
+  let span = info_span!("request", id = %request_id);
+  let mut res = with_request_id(request_id.clone(), ROUTER.route(req))
+    .instrument(span)
+    .await?;
+  res
+    .headers_mut()
+    .insert("X-Request-Id", HeaderValue::from_str(&request_id).unwrap());
+  Ok(res)
 }
 
 async fn not_found_route(_: Request<Body>) -> Result<Response<Body>, Infallible> {
-  respond!(StatusCode::NOT_FOUND, "Not found")
+  respond_error!(StatusCode::NOT_FOUND, ErrorBody::NOT_FOUND, "Not found")
 }
 
 #[cfg(test)]
@@ -73,6 +90,16 @@ mod test {
       .unwrap()
   }
 
+  /// Sends a request through the router, returning the status and body.
+  pub async fn request(method: Method, path: &str, body: String, token: Option<&str>) -> (StatusCode, String) {
+    let res = handle_requests(build_test_request(method, path, &body, token.map(str::to_string)))
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    (status, body)
+  }
+
   #[tokio::test]
   async fn can_receive_404() {
     let res = handle_requests(build_test_request(Method::GET, "/fakepath", "", None))
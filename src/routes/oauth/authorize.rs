@@ -0,0 +1,431 @@
+use std::convert::Infallible;
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use url::Url;
+use uuid::Uuid;
+
+use crate::jwt::ACCESS_TOKENS;
+use crate::models::{create_oauth_authorization_code, get_oauth_client, AuthorizationGrant, OAuthClient};
+use crate::routes::oauth::{oauth_error, parse_scopes};
+use crate::routes::util::get_session_by_auth_header;
+use crate::routes::DB;
+use crate::scope::Scope;
+
+/// Authorization codes must be exchanged within this many seconds.
+const AUTHORIZATION_CODE_TTL_SECS: i64 = 60 * 10;
+
+/// Length of a base64url encoded SHA-256 digest.
+const CODE_CHALLENGE_LEN: usize = 43;
+
+/// Parameters of an authorization request, read from the query string on
+/// `GET /authorize` and from the JSON body on `POST /authorize`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct AuthorizeParams {
+  pub response_type: Option<String>,
+  pub client_id: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub scope: Option<String>,
+  pub state: Option<String>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+  pub nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct AuthorizeBody {
+  #[serde(flatten)]
+  pub params: AuthorizeParams,
+  pub approve: bool,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct ConsentClient {
+  pub id: String,
+  pub name: String,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct ConsentScope {
+  pub scope: String,
+  pub description: String,
+}
+
+/// Everything a frontend needs to render the consent screen.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct ConsentResponse {
+  pub client: ConsentClient,
+  pub scopes: Vec<ConsentScope>,
+  pub redirect_uri: String,
+  pub state: Option<String>,
+}
+
+/// Where the user agent should be sent next, carrying either the
+/// authorization code or an OAuth error.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct AuthorizeRedirect {
+  pub redirect_to: String,
+}
+
+struct AuthorizationRequest {
+  client: OAuthClient,
+  redirect_uri: String,
+  scopes: Vec<Scope>,
+  state: Option<String>,
+  code_challenge: String,
+  nonce: Option<String>,
+}
+
+enum AuthorizeError {
+  /// The client or redirect URI could not be verified, so the error must not
+  /// be sent to the redirect URI.
+  Fatal(Response<Body>),
+  /// Reported back to the client through its redirect URI.
+  Redirect(String),
+}
+
+fn redirect_with(redirect_uri: &str, params: &[(&str, &str)], state: Option<&str>) -> String {
+  let mut url = Url::parse(redirect_uri).unwrap();
+  {
+    let mut query = url.query_pairs_mut();
+    for (key, value) in params {
+      query.append_pair(key, value);
+    }
+    if let Some(state) = state {
+      query.append_pair("state", state);
+    }
+  }
+  url.to_string()
+}
+
+fn redirect_error(redirect_uri: &str, state: Option<&str>, error: &str, description: &str) -> AuthorizeError {
+  AuthorizeError::Redirect(redirect_with(
+    redirect_uri,
+    &[("error", error), ("error_description", description)],
+    state,
+  ))
+}
+
+fn validate(db: &diesel::PgConnection, params: AuthorizeParams) -> Result<AuthorizationRequest, AuthorizeError> {
+  let client = params
+    .client_id
+    .as_deref()
+    .and_then(|client_id| Uuid::parse_str(client_id).ok())
+    .and_then(|client_id| get_oauth_client(db, client_id).ok())
+    .ok_or_else(|| {
+      AuthorizeError::Fatal(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        "Unknown client_id",
+      ))
+    })?;
+
+  // Redirect URIs are matched exactly, never by prefix.
+  let redirect_uri = match params.redirect_uri {
+    Some(redirect_uri) if client.redirect_uris.contains(&redirect_uri) => redirect_uri,
+    _ => {
+      return Err(AuthorizeError::Fatal(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        "redirect_uri is not registered for this client",
+      )))
+    },
+  };
+
+  let state = params.state.as_deref();
+  if params.response_type.as_deref() != Some("code") {
+    return Err(redirect_error(
+      &redirect_uri,
+      state,
+      "unsupported_response_type",
+      "Only the code response type is supported",
+    ));
+  }
+
+  let scopes = parse_scopes(params.scope.as_deref())
+    .ok_or_else(|| redirect_error(&redirect_uri, state, "invalid_scope", "Unknown or missing scope"))?;
+  if scopes.contains(&Scope::OpenId) && ACCESS_TOKENS.is_none() {
+    return Err(redirect_error(
+      &redirect_uri,
+      state,
+      "invalid_scope",
+      "OpenID Connect is not enabled",
+    ));
+  }
+
+  let code_challenge = match params.code_challenge {
+    Some(challenge) if challenge.len() == CODE_CHALLENGE_LEN => challenge,
+    _ => {
+      return Err(redirect_error(
+        &redirect_uri,
+        state,
+        "invalid_request",
+        "A PKCE code_challenge is required",
+      ))
+    },
+  };
+  if params.code_challenge_method.as_deref() != Some("S256") {
+    return Err(redirect_error(
+      &redirect_uri,
+      state,
+      "invalid_request",
+      "code_challenge_method must be S256",
+    ));
+  }
+  if matches!(&params.nonce, Some(nonce) if nonce.len() > 255) {
+    return Err(redirect_error(
+      &redirect_uri,
+      state,
+      "invalid_request",
+      "nonce must be at most 255 characters long",
+    ));
+  }
+
+  Ok(AuthorizationRequest {
+    client,
+    redirect_uri,
+    scopes,
+    state: params.state,
+    code_challenge,
+    nonce: params.nonce,
+  })
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Result<Response<Body>, Infallible> {
+  Ok(
+    Response::builder()
+      .status(status)
+      .header("Content-Type", "application/json")
+      .header("Cache-Control", "no-store")
+      .body(Body::from(serde_json::to_string(body).unwrap()))
+      .unwrap(),
+  )
+}
+
+/// Validates an authorization request and describes it for the consent screen.
+pub async fn get_authorize(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let params: AuthorizeParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or_default()) {
+    Ok(params) => params,
+    Err(err) => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        &err.to_string(),
+      ))
+    },
+  };
+
+  let db = DB.lock().await;
+  let request = match validate(&db, params) {
+    Ok(request) => request,
+    Err(AuthorizeError::Fatal(res)) => return Ok(res),
+    Err(AuthorizeError::Redirect(redirect_to)) => {
+      return json_response(StatusCode::BAD_REQUEST, &AuthorizeRedirect { redirect_to })
+    },
+  };
+
+  json_response(
+    StatusCode::OK,
+    &ConsentResponse {
+      client: ConsentClient {
+        id: request.client.id.to_string(),
+        name: request.client.name,
+      },
+      scopes: request
+        .scopes
+        .iter()
+        .map(|scope| ConsentScope {
+          scope: scope.to_string(),
+          description: scope.description().to_string(),
+        })
+        .collect(),
+      redirect_uri: request.redirect_uri,
+      state: request.state,
+    },
+  )
+}
+
+/// Records the logged in user's decision on an authorization request. When
+/// approved, a single use authorization code is sent back to the client.
+pub async fn post_authorize(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let authorize_body: AuthorizeBody = match serde_json::from_slice(&body) {
+    Ok(authorize_body) => authorize_body,
+    Err(err) => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        &err.to_string(),
+      ))
+    },
+  };
+
+  let request = match validate(&db, authorize_body.params) {
+    Ok(request) => request,
+    Err(AuthorizeError::Fatal(res)) => return Ok(res),
+    Err(AuthorizeError::Redirect(redirect_to)) => {
+      return json_response(StatusCode::OK, &AuthorizeRedirect { redirect_to })
+    },
+  };
+
+  let state = request.state.as_deref();
+  if !authorize_body.approve {
+    let redirect_to = redirect_with(
+      &request.redirect_uri,
+      &[
+        ("error", "access_denied"),
+        ("error_description", "The user denied the request"),
+      ],
+      state,
+    );
+    return json_response(StatusCode::OK, &AuthorizeRedirect { redirect_to });
+  }
+
+  let grant = AuthorizationGrant {
+    client_id: request.client.id,
+    user_id: user.id,
+    redirect_uri: request.redirect_uri.clone(),
+    scopes: request.scopes.iter().map(|scope| scope.to_string()).collect(),
+    code_challenge: request.code_challenge.clone(),
+    nonce: request.nonce.clone(),
+  };
+  match create_oauth_authorization_code(&db, grant, chrono::Duration::seconds(AUTHORIZATION_CODE_TTL_SECS)) {
+    Ok(code) => {
+      let redirect_to = redirect_with(&request.redirect_uri, &[("code", &code)], state);
+      json_response(StatusCode::OK, &AuthorizeRedirect { redirect_to })
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      Ok(oauth_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        "Failed to issue authorization code",
+      ))
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::{AuthorizeBody, AuthorizeParams, AuthorizeRedirect, ConsentResponse};
+  use crate::routes::handle_requests;
+  use crate::routes::oauth::test::{challenge, register_client, REDIRECT_URI, VERIFIER};
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+
+  fn params(client_id: &str) -> AuthorizeParams {
+    AuthorizeParams {
+      response_type: Some("code".to_string()),
+      client_id: Some(client_id.to_string()),
+      redirect_uri: Some(REDIRECT_URI.to_string()),
+      scope: Some("user:read".to_string()),
+      state: Some("xyz".to_string()),
+      code_challenge: Some(challenge(VERIFIER)),
+      code_challenge_method: Some("S256".to_string()),
+      nonce: None,
+    }
+  }
+
+  async fn get(params: &AuthorizeParams) -> (StatusCode, String) {
+    let path = format!("/authorize?{}", serde_urlencoded::to_string(params).unwrap());
+    let res = handle_requests(build_test_request(Method::GET, &path, "", None))
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    (status, body)
+  }
+
+  #[tokio::test]
+  async fn consent_describes_client_and_scopes() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+
+    let (status, body) = get(&params(&client.client_id)).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let consent: ConsentResponse = serde_json::from_str(&body).unwrap();
+    assert_eq!(consent.client.name, client.name);
+    assert_eq!(consent.scopes.len(), 1);
+    assert_eq!(consent.scopes[0].scope, "user:read");
+    assert_eq!(consent.state.as_deref(), Some("xyz"));
+  }
+
+  #[tokio::test]
+  async fn unregistered_redirect_uri_is_not_followed() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+
+    let (status, body) = get(&AuthorizeParams {
+      redirect_uri: Some("https://evil.example/callback".to_string()),
+      ..params(&client.client_id)
+    })
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(!body.contains("evil.example"));
+  }
+
+  #[tokio::test]
+  async fn pkce_is_required() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+
+    for params in [
+      AuthorizeParams {
+        code_challenge: None,
+        ..params(&client.client_id)
+      },
+      AuthorizeParams {
+        code_challenge_method: Some("plain".to_string()),
+        ..params(&client.client_id)
+      },
+    ] {
+      let (status, body) = get(&params).await;
+      assert_eq!(status, StatusCode::BAD_REQUEST);
+      let redirect: AuthorizeRedirect = serde_json::from_str(&body).unwrap();
+      assert!(redirect.redirect_to.starts_with(REDIRECT_URI));
+      assert!(redirect.redirect_to.contains("error=invalid_request"));
+      assert!(redirect.redirect_to.contains("state=xyz"));
+    }
+  }
+
+  #[tokio::test]
+  async fn denied_request_redirects_with_error() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+
+    let body = serde_json::to_string(&AuthorizeBody {
+      params: params(&client.client_id),
+      approve: false,
+    })
+    .unwrap();
+    let res = handle_requests(build_test_request(Method::POST, "/authorize", &body, Some(token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let redirect: AuthorizeRedirect = serde_json::from_slice(&body).unwrap();
+    assert!(redirect.redirect_to.contains("error=access_denied"));
+    assert!(!redirect.redirect_to.contains("code="));
+  }
+}
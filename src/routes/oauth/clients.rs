@@ -0,0 +1,292 @@
+use std::convert::Infallible;
+
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use url::Url;
+use uuid::Uuid;
+
+use crate::models::{create_oauth_client, delete_user_oauth_client, get_user_oauth_clients, OAuthClient};
+use crate::policy::Violation;
+use crate::router::PathParams;
+use crate::routes::error::{validation_response, ErrorBody};
+use crate::routes::util::get_session_by_auth_header;
+use crate::routes::DB;
+use crate::{respond, respond_error};
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct ClientBody {
+  pub name: String,
+  pub redirect_uris: Vec<String>,
+  /// Confidential clients receive a secret and must present it at `/token`.
+  #[serde(default)]
+  pub confidential: bool,
+}
+
+impl ClientBody {
+  pub const EMPTY_NAME_ERR: &'static str = "Client name must not be empty";
+  pub const LONG_NAME_ERR: &'static str = "Client name must be at most 100 characters long";
+  pub const NO_REDIRECT_URIS_ERR: &'static str = "Client must have at least one redirect URI";
+  pub const TOO_MANY_REDIRECT_URIS_ERR: &'static str = "Client can have at most 10 redirect URIs";
+
+  pub fn is_valid(&self) -> Result<(), Vec<Violation>> {
+    let mut errors = Vec::new();
+    if self.name.trim().is_empty() {
+      errors.push(Violation::new("name_empty", Self::EMPTY_NAME_ERR));
+    }
+
+    if self.name.chars().count() > 100 {
+      errors.push(Violation::new("name_too_long", Self::LONG_NAME_ERR));
+    }
+
+    if self.redirect_uris.is_empty() {
+      errors.push(Violation::new("redirect_uris_missing", Self::NO_REDIRECT_URIS_ERR));
+    }
+
+    if self.redirect_uris.len() > 10 {
+      errors.push(Violation::new("redirect_uris_too_many", Self::TOO_MANY_REDIRECT_URIS_ERR));
+    }
+
+    for redirect_uri in self.redirect_uris.iter().filter(|uri| !is_valid_redirect_uri(uri)) {
+      errors.push(Violation::new(
+        "redirect_uri_invalid",
+        format!("Invalid redirect URI {}", redirect_uri),
+      ));
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+}
+
+/// Redirect URIs must be absolute, without a fragment, and use https unless
+/// they point back at the local machine.
+fn is_valid_redirect_uri(uri: &str) -> bool {
+  let url = match Url::parse(uri) {
+    Ok(url) => url,
+    Err(_) => return false,
+  };
+  if url.fragment().is_some() {
+    return false;
+  }
+
+  match url.scheme() {
+    "https" => true,
+    "http" => matches!(url.host_str(), Some("localhost") | Some("127.0.0.1") | Some("[::1]")),
+    _ => false,
+  }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct ClientResult {
+  pub client_id: String,
+  pub name: String,
+  pub redirect_uris: Vec<String>,
+  pub confidential: bool,
+  pub created_at: DateTime<Utc>,
+  /// Only returned once when a confidential client is registered.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_secret: Option<String>,
+}
+
+impl From<OAuthClient> for ClientResult {
+  fn from(client: OAuthClient) -> Self {
+    Self {
+      client_id: client.id.to_string(),
+      name: client.name,
+      redirect_uris: client.redirect_uris,
+      confidential: client.secret_hash.is_some(),
+      created_at: Utc.from_utc_datetime(&client.created_at),
+      client_secret: None,
+    }
+  }
+}
+
+pub async fn post_client(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let client_body = serde_json::from_slice(&body);
+  if let Err(err) = client_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let client_body: ClientBody = client_body.unwrap();
+  if let Err(errors) = client_body.is_valid() {
+    return Ok(validation_response(errors));
+  }
+
+  match create_oauth_client(
+    &db,
+    user.id,
+    client_body.name,
+    client_body.redirect_uris,
+    client_body.confidential,
+  ) {
+    Ok((client_secret, client)) => {
+      let result = ClientResult {
+        client_secret,
+        ..ClientResult::from(client)
+      };
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .header("Cache-Control", "no-store")
+          .body(Body::from(serde_json::to_string(&result).unwrap()))
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn list_clients(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match get_user_oauth_clients(&db, user.id) {
+    Ok(clients) => {
+      let clients: Vec<ClientResult> = clients.into_iter().map(ClientResult::from).collect();
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .body(Body::from(serde_json::to_string(&clients).unwrap()))
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Deleting a client also revokes every token and code issued to it.
+pub async fn revoke_client(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  let client_id = req
+    .extensions()
+    .get::<PathParams>()
+    .and_then(|params| params.get("id"))
+    .map(Uuid::parse_str);
+  let client_id = match client_id {
+    Some(Ok(client_id)) => client_id,
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "invalid_client_id", "Invalid client id"),
+  };
+
+  match delete_user_oauth_client(&db, user.id, client_id) {
+    Ok(0) => respond_error!(StatusCode::NOT_FOUND, "client_not_found", "Client not found"),
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::{is_valid_redirect_uri, ClientBody};
+  use crate::routes::handle_requests;
+  use crate::routes::oauth::test::register_client;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+
+  #[test]
+  fn redirect_uri_rules() {
+    assert!(is_valid_redirect_uri("https://tools.example/callback"));
+    assert!(is_valid_redirect_uri("http://localhost:8080/callback"));
+    assert!(is_valid_redirect_uri("http://127.0.0.1/callback"));
+    assert!(!is_valid_redirect_uri("http://tools.example/callback"));
+    assert!(!is_valid_redirect_uri("https://tools.example/callback#frag"));
+    assert!(!is_valid_redirect_uri("/callback"));
+    assert!(!is_valid_redirect_uri("javascript:alert(1)"));
+  }
+
+  #[tokio::test]
+  async fn secret_only_issued_to_confidential_clients() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let public = register_client(&token, false).await;
+    assert!(!public.confidential);
+    assert!(public.client_secret.is_none());
+
+    let confidential = register_client(&token, true).await;
+    assert!(confidential.confidential);
+    let secret = confidential.client_secret.unwrap();
+
+    let res = handle_requests(build_test_request(Method::GET, "/oauth/clients", "", Some(token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert!(!String::from_utf8_lossy(&body).contains(&secret));
+  }
+
+  #[tokio::test]
+  async fn only_owner_can_delete_client() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let other = register_and_login("other").await;
+    let client = register_client(&token, false).await;
+
+    let path = format!("/oauth/clients/{}", client.client_id);
+    let status_of = |token: String| async {
+      handle_requests(build_test_request(Method::DELETE, &path, "", Some(token)))
+        .await
+        .unwrap()
+        .status()
+    };
+    assert_eq!(status_of(other).await, StatusCode::NOT_FOUND);
+    assert_eq!(status_of(token).await, StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn invalid_client_registration() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let body = serde_json::to_string(&ClientBody {
+      name: "".to_string(),
+      redirect_uris: vec!["http://tools.example/callback".to_string()],
+      confidential: false,
+    })
+    .unwrap();
+    let res = handle_requests(build_test_request(Method::POST, "/oauth/clients", &body, Some(token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    assert!(body.contains(ClientBody::EMPTY_NAME_ERR));
+    assert!(body.contains("Invalid redirect URI http://tools.example/callback"));
+  }
+}
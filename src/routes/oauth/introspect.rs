@@ -0,0 +1,240 @@
+use std::convert::Infallible;
+
+use chrono::{TimeZone, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::User;
+use crate::routes::oauth::{authenticate_client, basic_credentials, oauth_error};
+use crate::routes::util::{peek_user_by_api_key, peek_user_by_auth, Credential};
+use crate::routes::DB;
+use crate::scope::Scope;
+use crate::session::SESSION_POLICY;
+use crate::tokens::is_api_key;
+
+/// Body of `POST /introspect`, sent as `application/x-www-form-urlencoded`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct IntrospectionRequest {
+  pub token: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+}
+
+/// RFC 7662 introspection response. Inactive tokens only carry `active`.
+#[derive(Serialize, Default)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct IntrospectionResponse {
+  pub active: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sub: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub username: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub licensed: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub iat: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub exp: Option<i64>,
+}
+
+impl IntrospectionResponse {
+  fn new(user: User, credential: Credential) -> Self {
+    let now = Utc::now().naive_utc();
+    let (scopes, client_id, iat, exp) = match credential {
+      Credential::Session(session) => (
+        session
+          .scopes
+          .clone()
+          .unwrap_or_else(|| Scope::ALL.iter().map(|scope| scope.to_string()).collect()),
+        session.oauth_client_id,
+        session.created_at,
+        SESSION_POLICY.expires_at(&session, now),
+      ),
+      Credential::ApiKey(api_key) => (api_key.scopes, None, api_key.created_at, api_key.expires_at),
+    };
+
+    Self {
+      active: true,
+      sub: Some(user.id.to_string()),
+      licensed: Some(user.is_licensed()),
+      username: Some(user.username),
+      scope: Some(scopes.join(" ")),
+      client_id: client_id.map(|client_id| client_id.to_string()),
+      iat: Some(Utc.from_utc_datetime(&iat).timestamp()),
+      exp: exp.map(|exp| Utc.from_utc_datetime(&exp).timestamp()),
+    }
+  }
+}
+
+fn introspect(db: &diesel::PgConnection, token: &str) -> IntrospectionResponse {
+  let result = if is_api_key(token) {
+    peek_user_by_api_key(db, token).map(|(user, api_key)| (user, Credential::ApiKey(api_key)))
+  } else {
+    match Uuid::parse_str(token) {
+      Ok(token) => peek_user_by_auth(db, token).map(|(user, session)| (user, Credential::Session(session))),
+      Err(_) => return IntrospectionResponse::default(),
+    }
+  };
+
+  match result {
+    Ok((user, credential)) => IntrospectionResponse::new(user, credential),
+    Err(_) => IntrospectionResponse::default(),
+  }
+}
+
+/// Lets services that cannot use the RPC server validate a session token or
+/// API key. Callers authenticate as a confidential OAuth client.
+pub async fn post_introspect(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let basic = basic_credentials(&req);
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let introspection_request: IntrospectionRequest = match serde_urlencoded::from_bytes(&body) {
+    Ok(introspection_request) => introspection_request,
+    Err(err) => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        &err.to_string(),
+      ))
+    },
+  };
+
+  let (client_id, client_secret) = match basic {
+    Some((client_id, client_secret)) => (Some(client_id), Some(client_secret)),
+    None => (introspection_request.client_id, introspection_request.client_secret),
+  };
+
+  let db = DB.lock().await;
+  let client = match authenticate_client(&db, client_id.as_deref(), client_secret.as_deref()) {
+    Ok(client) => client,
+    Err(res) => return Ok(res),
+  };
+  if client.secret_hash.is_none() {
+    return Ok(oauth_error(
+      StatusCode::UNAUTHORIZED,
+      "invalid_client",
+      "Only confidential clients can introspect tokens",
+    ));
+  }
+
+  let token = match introspection_request.token {
+    Some(token) => token,
+    None => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        "token is required",
+      ))
+    },
+  };
+
+  Ok(
+    Response::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/json")
+      .header("Cache-Control", "no-store")
+      .body(Body::from(serde_json::to_string(&introspect(&db, &token)).unwrap()))
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use diesel::{QueryDsl, RunQueryDsl};
+  use hyper::{Method, StatusCode};
+
+  use super::IntrospectionResponse;
+  use crate::routes::handle_requests;
+  use crate::routes::oauth::clients::ClientResult;
+  use crate::routes::oauth::test::{post_form, register_client};
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+  use crate::routes::DB;
+  use crate::schema::api_keys;
+
+  async fn introspect(client: &ClientResult, token: &str) -> IntrospectionResponse {
+    let (status, body) = post_form(
+      "/introspect",
+      &[
+        ("token", token),
+        ("client_id", &client.client_id),
+        ("client_secret", client.client_secret.as_deref().unwrap()),
+      ],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  #[tokio::test]
+  async fn active_session_token() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, true).await;
+
+    let response = introspect(&client, &token).await;
+    assert!(response.active);
+    assert_eq!(response.username.as_deref(), Some("tester"));
+    assert_eq!(response.licensed, Some(false));
+    assert!(response.scope.unwrap().contains("game:play"));
+    assert!(response.exp.unwrap() > chrono::Utc::now().timestamp());
+
+    let res = handle_requests(build_test_request(Method::POST, "/logout", "", Some(token.clone())))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let response = introspect(&client, &token).await;
+    assert!(!response.active);
+    assert!(response.username.is_none());
+  }
+
+  #[tokio::test]
+  async fn api_key_and_garbage_tokens() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, true).await;
+
+    let body = r#"{"name": "FizzBuzz Bot", "scopes": ["user:read"], "expires_in": 3600}"#;
+    let res = handle_requests(build_test_request(Method::POST, "/api_keys", body, Some(token)))
+      .await
+      .unwrap();
+    let body: serde_json::Value =
+      serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    let key = body["key"].as_str().unwrap();
+
+    let response = introspect(&client, key).await;
+    assert!(response.active);
+    assert_eq!(response.scope.as_deref(), Some("user:read"));
+    assert!(response.exp.is_some());
+
+    // Looking at a key on someone else's behalf does not count as using it
+    let last_used: Option<chrono::NaiveDateTime> = api_keys::table
+      .select(api_keys::last_used)
+      .first(&*DB.lock().await)
+      .unwrap();
+    assert!(last_used.is_none());
+
+    assert!(!introspect(&client, "ipv8_notakey_atall").await.active);
+    assert!(!introspect(&client, "1234").await.active);
+  }
+
+  #[tokio::test]
+  async fn caller_must_be_confidential_client() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let public = register_client(&token, false).await;
+
+    let (status, _) = post_form("/introspect", &[("token", &token), ("client_id", &public.client_id)]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = post_form("/introspect", &[("token", &token)]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+  }
+}
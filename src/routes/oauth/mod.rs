@@ -0,0 +1,216 @@
+use diesel::PgConnection;
+use futures::FutureExt;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use uuid::Uuid;
+
+use self::authorize::{get_authorize, post_authorize};
+use self::clients::{list_clients, post_client, revoke_client};
+use self::introspect::post_introspect;
+use self::token::post_token;
+use self::userinfo::get_userinfo;
+use crate::models::{get_oauth_client, OAuthClient};
+use crate::route_func;
+use crate::router::{Routable, RoutedFunction};
+use crate::scope::Scope;
+use crate::tokens::hash_token;
+
+pub mod authorize;
+pub mod clients;
+pub mod introspect;
+pub mod token;
+pub mod userinfo;
+
+pub struct OAuthRouter;
+
+impl Routable for OAuthRouter {
+  fn routes(&self) -> Vec<RoutedFunction> {
+    vec![
+      route_func!(Method::POST, "/oauth/clients", post_client),
+      route_func!(Method::GET, "/oauth/clients", list_clients),
+      route_func!(Method::DELETE, "/oauth/clients/{id}", revoke_client),
+      route_func!(Method::GET, "/authorize", get_authorize),
+      route_func!(Method::POST, "/authorize", post_authorize),
+      route_func!(Method::POST, "/token", post_token),
+      route_func!(Method::POST, "/introspect", post_introspect),
+      route_func!(Method::GET, "/userinfo", get_userinfo),
+      route_func!(Method::POST, "/userinfo", get_userinfo),
+    ]
+  }
+}
+
+/// Error body defined by RFC 6749 section 5.2.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize))]
+pub struct OAuthError {
+  pub error: String,
+  pub error_description: String,
+}
+
+pub fn oauth_error(status: StatusCode, error: &str, description: &str) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .header("Cache-Control", "no-store")
+    .body(Body::from(
+      serde_json::to_string(&OAuthError {
+        error: error.to_string(),
+        error_description: description.to_string(),
+      })
+      .unwrap(),
+    ))
+    .unwrap()
+}
+
+/// Client credentials sent with HTTP Basic authentication, which take
+/// precedence over `client_id` and `client_secret` in the body.
+pub fn basic_credentials(req: &Request<Body>) -> Option<(String, String)> {
+  let header = req.headers().get("Authorization")?.to_str().ok()?;
+  let decoded = base64::decode(header.strip_prefix("Basic ")?).ok()?;
+  let decoded = String::from_utf8(decoded).ok()?;
+  let (client_id, client_secret) = decoded.split_once(':')?;
+  Some((client_id.to_string(), client_secret.to_string()))
+}
+
+/// Looks up the calling client, checking its secret if it is confidential.
+pub fn authenticate_client(
+  db: &PgConnection,
+  client_id: Option<&str>,
+  client_secret: Option<&str>,
+) -> Result<OAuthClient, Response<Body>> {
+  let client = client_id
+    .and_then(|client_id| Uuid::parse_str(client_id).ok())
+    .and_then(|client_id| get_oauth_client(db, client_id).ok())
+    .ok_or_else(|| oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Unknown client"))?;
+
+  if let Some(secret_hash) = &client.secret_hash {
+    if client_secret.map(hash_token).as_ref() != Some(secret_hash) {
+      return Err(oauth_error(
+        StatusCode::UNAUTHORIZED,
+        "invalid_client",
+        "Invalid client credentials",
+      ));
+    }
+  }
+
+  Ok(client)
+}
+
+/// Parses a space separated `scope` parameter, rejecting unknown or empty
+/// scope lists.
+pub fn parse_scopes(scope: Option<&str>) -> Option<Vec<Scope>> {
+  let mut scopes = Vec::new();
+  for name in scope.unwrap_or_default().split(' ').filter(|name| !name.is_empty()) {
+    let scope = Scope::parse(name)?;
+    if !scopes.contains(&scope) {
+      scopes.push(scope);
+    }
+  }
+
+  if scopes.is_empty() {
+    None
+  } else {
+    Some(scopes)
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use hyper::{Body, Method, Request, StatusCode};
+  use sha2::{Digest, Sha256};
+
+  use super::authorize::{AuthorizeBody, AuthorizeParams, AuthorizeRedirect};
+  use super::clients::{ClientBody, ClientResult};
+  use crate::routes::handle_requests;
+  use crate::routes::test::build_test_request;
+
+  pub const REDIRECT_URI: &str = "https://tools.example/callback";
+  pub const VERIFIER: &str = "dBjftJeZ4CVP-mJ92K9lDUT4t8DkSDYXnKzWd0iO1bHAbGw";
+
+  pub fn challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+  }
+
+  pub async fn register_client(token: &str, confidential: bool) -> ClientResult {
+    let body = serde_json::to_string(&ClientBody {
+      name: "Trade Route Planner".to_string(),
+      redirect_uris: vec![REDIRECT_URI.to_string()],
+      confidential,
+    })
+    .unwrap();
+    let res = handle_requests(build_test_request(
+      Method::POST,
+      "/oauth/clients",
+      &body,
+      Some(token.to_string()),
+    ))
+    .await
+    .unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  pub async fn post_form(path: &str, form: &[(&str, &str)]) -> (StatusCode, String) {
+    let req = Request::builder()
+      .method(Method::POST)
+      .uri(path)
+      .header("Content-Type", "application/x-www-form-urlencoded")
+      .body(Body::from(serde_urlencoded::to_string(form).unwrap()))
+      .unwrap();
+    let res = handle_requests(req).await.unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    (status, body)
+  }
+
+  pub async fn authorize(token: &str, client: &ClientResult, scope: &str, nonce: Option<&str>) -> String {
+    let body = serde_json::to_string(&AuthorizeBody {
+      params: AuthorizeParams {
+        response_type: Some("code".to_string()),
+        client_id: Some(client.client_id.clone()),
+        redirect_uri: Some(REDIRECT_URI.to_string()),
+        scope: Some(scope.to_string()),
+        state: Some("xyz".to_string()),
+        code_challenge: Some(challenge(VERIFIER)),
+        code_challenge_method: Some("S256".to_string()),
+        nonce: nonce.map(str::to_string),
+      },
+      approve: true,
+    })
+    .unwrap();
+    let res = handle_requests(build_test_request(
+      Method::POST,
+      "/authorize",
+      &body,
+      Some(token.to_string()),
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let redirect: AuthorizeRedirect = serde_json::from_slice(&body).unwrap();
+    let url = url::Url::parse(&redirect.redirect_to).unwrap();
+    assert_eq!(url.query_pairs().find(|(key, _)| key == "state").unwrap().1, "xyz");
+    url
+      .query_pairs()
+      .find(|(key, _)| key == "code")
+      .map(|(_, code)| code.to_string())
+      .unwrap()
+  }
+
+  pub async fn exchange(client: &ClientResult, code: &str, verifier: &str) -> (StatusCode, String) {
+    let mut form = vec![
+      ("grant_type", "authorization_code"),
+      ("code", code),
+      ("redirect_uri", REDIRECT_URI),
+      ("client_id", &client.client_id),
+      ("code_verifier", verifier),
+    ];
+    if let Some(secret) = &client.client_secret {
+      form.push(("client_secret", secret));
+    }
+    post_form("/token", &form).await
+  }
+}
@@ -0,0 +1,315 @@
+use std::convert::Infallible;
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::jwt::ACCESS_TOKENS;
+use crate::models::{create_session, take_oauth_authorization_code, SessionMetadata, User};
+use crate::routes::oauth::{authenticate_client, basic_credentials, oauth_error};
+use crate::routes::util::{client_ip, user_agent};
+use crate::routes::DB;
+use crate::scope::Scope;
+
+/// Body of `POST /token`, sent as `application/x-www-form-urlencoded`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct TokenRequest {
+  pub grant_type: String,
+  pub code: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+  pub code_verifier: Option<String>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct OAuthTokenResponse {
+  pub access_token: String,
+  pub token_type: String,
+  pub scope: String,
+  /// Only issued when the `openid` scope was granted.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_token: Option<String>,
+}
+
+/// Checks a PKCE verifier against the S256 challenge stored with the code.
+fn verify_code_challenge(code_verifier: &str, code_challenge: &str) -> bool {
+  let well_formed = (43..=128).contains(&code_verifier.len())
+    && code_verifier
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~'));
+  let digest = base64::encode_config(Sha256::digest(code_verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+  well_formed && digest == code_challenge
+}
+
+/// Exchanges an authorization code for an access token scoped to what the
+/// user approved.
+pub async fn post_token(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  use crate::schema::users;
+
+  let client_ip = client_ip(&req);
+  let client_user_agent = user_agent(&req);
+  let basic = basic_credentials(&req);
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let token_request: TokenRequest = match serde_urlencoded::from_bytes(&body) {
+    Ok(token_request) => token_request,
+    Err(err) => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        &err.to_string(),
+      ))
+    },
+  };
+  if token_request.grant_type != "authorization_code" {
+    return Ok(oauth_error(
+      StatusCode::BAD_REQUEST,
+      "unsupported_grant_type",
+      "Only the authorization_code grant is supported",
+    ));
+  }
+
+  let (client_id, client_secret) = match basic {
+    Some((client_id, client_secret)) => (Some(client_id), Some(client_secret)),
+    None => (token_request.client_id, token_request.client_secret),
+  };
+
+  let (code, redirect_uri, code_verifier) = match (
+    token_request.code,
+    token_request.redirect_uri,
+    token_request.code_verifier,
+  ) {
+    (Some(code), Some(redirect_uri), Some(code_verifier)) => (code, redirect_uri, code_verifier),
+    _ => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        "code, redirect_uri and code_verifier are required",
+      ))
+    },
+  };
+
+  // Authenticate the client
+  let db = DB.lock().await;
+  let client = match authenticate_client(&db, client_id.as_deref(), client_secret.as_deref()) {
+    Ok(client) => client,
+    Err(res) => return Ok(res),
+  };
+
+  // The code is consumed before it is checked, so a failed exchange cannot be
+  // retried with a different verifier.
+  let authorization_code = match take_oauth_authorization_code(&db, &code) {
+    Ok(authorization_code) => authorization_code,
+    Err(_) => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_grant",
+        "Invalid authorization code",
+      ))
+    },
+  };
+  if authorization_code.client_id != client.id
+    || authorization_code.redirect_uri != redirect_uri
+    || authorization_code.expires_at < chrono::Utc::now().naive_utc()
+    || !verify_code_challenge(&code_verifier, &authorization_code.code_challenge)
+  {
+    return Ok(oauth_error(
+      StatusCode::BAD_REQUEST,
+      "invalid_grant",
+      "Invalid authorization code",
+    ));
+  }
+
+  let user: User = match users::table
+    .filter(users::id.eq(authorization_code.user_id))
+    .first(&*db)
+  {
+    Ok(user) => user,
+    Err(_) => {
+      return Ok(oauth_error(
+        StatusCode::BAD_REQUEST,
+        "invalid_grant",
+        "Invalid authorization code",
+      ))
+    },
+  };
+
+  let scope = authorization_code.scopes.join(" ");
+  let id_token = match ACCESS_TOKENS.as_ref() {
+    Some(signer)
+      if authorization_code
+        .scopes
+        .iter()
+        .any(|scope| scope == Scope::OpenId.as_str()) =>
+    {
+      Some(signer.issue_id_token(&user, &client.id.to_string(), authorization_code.nonce.clone()))
+    },
+    _ => None,
+  };
+  let metadata = SessionMetadata {
+    ip: client_ip.map(|ip| ip.to_string()),
+    user_agent: client_user_agent,
+    device_label: Some(client.name),
+    scopes: Some(authorization_code.scopes),
+    oauth_client_id: Some(client.id),
+  };
+  match create_session(&db, user.id, metadata) {
+    Ok((token, _)) => {
+      let response = OAuthTokenResponse {
+        access_token: token.to_string(),
+        token_type: "Bearer".to_string(),
+        scope,
+        id_token,
+      };
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .header("Cache-Control", "no-store")
+          .body(Body::from(serde_json::to_string(&response).unwrap()))
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      Ok(oauth_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        "Failed to issue token",
+      ))
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::{verify_code_challenge, OAuthTokenResponse};
+  use crate::routes::handle_requests;
+  use crate::routes::oauth::clients::ClientResult;
+  use crate::routes::oauth::test::{authorize, exchange, register_client, VERIFIER};
+  use crate::routes::oauth::OAuthError;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+
+  async fn status_of(method: Method, path: &str, token: &str) -> StatusCode {
+    handle_requests(build_test_request(method, path, "", Some(format!("Bearer {}", token))))
+      .await
+      .unwrap()
+      .status()
+  }
+
+  #[test]
+  fn verifies_s256_challenge() {
+    let challenge = "EzyGwEdjKWlkCFGcneEGMBC14RrWsBzKaUWXzwb_o_g";
+    assert!(verify_code_challenge(VERIFIER, challenge));
+    assert!(!verify_code_challenge(&VERIFIER.to_uppercase(), challenge));
+    assert!(!verify_code_challenge("short", challenge));
+  }
+
+  #[tokio::test]
+  async fn code_exchange_issues_scoped_token() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+    let code = authorize(&token, &client, "user:read", None).await;
+
+    let (status, body) = exchange(&client, &code, VERIFIER).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let response: OAuthTokenResponse = serde_json::from_str(&body).unwrap();
+    assert_eq!(response.token_type, "Bearer");
+    assert_eq!(response.scope, "user:read");
+
+    let access_token = response.access_token;
+    assert_eq!(status_of(Method::GET, "/user", &access_token).await, StatusCode::OK);
+    assert_eq!(
+      status_of(Method::POST, "/next_instruction", &access_token).await,
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      status_of(Method::GET, "/sessions", &access_token).await,
+      StatusCode::FORBIDDEN
+    );
+  }
+
+  #[tokio::test]
+  async fn code_is_single_use() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+    let code = authorize(&token, &client, "user:read", None).await;
+
+    assert_eq!(exchange(&client, &code, VERIFIER).await.0, StatusCode::OK);
+    let (status, body) = exchange(&client, &code, VERIFIER).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+      serde_json::from_str::<OAuthError>(&body).unwrap().error,
+      "invalid_grant"
+    );
+  }
+
+  #[tokio::test]
+  async fn wrong_verifier_burns_code() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+    let code = authorize(&token, &client, "user:read", None).await;
+
+    let wrong = "a".repeat(43);
+    assert_eq!(exchange(&client, &code, &wrong).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(exchange(&client, &code, VERIFIER).await.0, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn confidential_client_must_authenticate() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, true).await;
+    let code = authorize(&token, &client, "user:read game:play", None).await;
+
+    let wrong_secret = ClientResult {
+      client_id: client.client_id.clone(),
+      name: client.name.clone(),
+      redirect_uris: client.redirect_uris.clone(),
+      confidential: true,
+      created_at: client.created_at,
+      client_secret: Some("wrong".to_string()),
+    };
+    let (status, body) = exchange(&wrong_secret, &code, VERIFIER).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+      serde_json::from_str::<OAuthError>(&body).unwrap().error,
+      "invalid_client"
+    );
+
+    let (status, body) = exchange(&client, &code, VERIFIER).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+  }
+
+  #[tokio::test]
+  async fn deleting_client_revokes_tokens() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+    let code = authorize(&token, &client, "user:read", None).await;
+    let (_, body) = exchange(&client, &code, VERIFIER).await;
+    let access_token = serde_json::from_str::<OAuthTokenResponse>(&body).unwrap().access_token;
+
+    let path = format!("/oauth/clients/{}", client.client_id);
+    let res = handle_requests(build_test_request(Method::DELETE, &path, "", Some(token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+      status_of(Method::GET, "/user", &access_token).await,
+      StatusCode::BAD_REQUEST
+    );
+  }
+}
@@ -0,0 +1,107 @@
+use std::convert::Infallible;
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::routes::util::get_user_by_auth_header;
+use crate::routes::DB;
+use crate::scope::Scope;
+
+/// OpenID Connect view of the same fields `GET /user` returns.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize))]
+pub struct UserInfo {
+  pub sub: String,
+  pub name: String,
+  pub preferred_username: String,
+  pub licensed: bool,
+}
+
+pub async fn get_userinfo(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  match get_user_by_auth_header(&db, &req, Scope::OpenId) {
+    Ok((user, _)) => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+          serde_json::to_string(&UserInfo {
+            sub: user.id.to_string(),
+            licensed: user.is_licensed(),
+            name: user.name,
+            preferred_username: user.username,
+          })
+          .unwrap(),
+        ))
+        .unwrap(),
+    ),
+    Err(err) => Ok(err),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::UserInfo;
+  use crate::jwt::{IdClaims, ACCESS_TOKENS};
+  use crate::routes::handle_requests;
+  use crate::routes::oauth::test::{authorize, exchange, register_client, VERIFIER};
+  use crate::routes::oauth::token::OAuthTokenResponse;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+
+  async fn token_for(scope: &str) -> (String, OAuthTokenResponse) {
+    let token = register_and_login("tester").await;
+    let client = register_client(&token, false).await;
+    let code = authorize(&token, &client, scope, Some("n-0S6_WzA2Mj")).await;
+    let (status, body) = exchange(&client, &code, VERIFIER).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    (client.client_id, serde_json::from_str(&body).unwrap())
+  }
+
+  #[tokio::test]
+  async fn openid_scope_issues_id_token() {
+    before_user_test().await;
+    let (client_id, response) = token_for("openid").await;
+
+    let signer = ACCESS_TOKENS.as_ref().unwrap();
+    let claims: IdClaims = signer.verify(&response.id_token.unwrap()).unwrap();
+    assert_eq!(claims.aud, client_id);
+    assert_eq!(claims.preferred_username, "tester");
+    assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+    assert!(!claims.licensed);
+
+    let res = handle_requests(build_test_request(
+      Method::GET,
+      "/userinfo",
+      "",
+      Some(format!("Bearer {}", response.access_token)),
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let userinfo: UserInfo = serde_json::from_slice(&body).unwrap();
+    assert_eq!(userinfo.sub, claims.sub);
+    assert_eq!(userinfo.preferred_username, "tester");
+  }
+
+  #[tokio::test]
+  async fn userinfo_requires_openid_scope() {
+    before_user_test().await;
+    let (_, response) = token_for("user:read").await;
+    assert!(response.id_token.is_none());
+
+    let res = handle_requests(build_test_request(
+      Method::GET,
+      "/userinfo",
+      "",
+      Some(response.access_token),
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+  }
+}
@@ -0,0 +1,349 @@
+use std::convert::Infallible;
+
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::models::{create_api_key, delete_user_api_key, get_user_api_keys, ApiKey};
+use crate::policy::Violation;
+use crate::router::PathParams;
+use crate::routes::error::{validation_response, ErrorBody};
+use crate::routes::util::get_session_by_auth_header;
+use crate::routes::DB;
+use crate::scope::Scope;
+use crate::{respond, respond_error};
+
+/// Longest lifetime an API key can be given, ten years.
+pub const MAX_API_KEY_LIFETIME_SECS: i64 = 10 * 365 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct ApiKeyBody {
+  pub name: String,
+  pub scopes: Vec<String>,
+  /// Lifetime of the key in seconds, keys without one never expire.
+  #[serde(default)]
+  pub expires_in: Option<i64>,
+}
+
+impl ApiKeyBody {
+  pub const EMPTY_NAME_ERR: &'static str = "API key name must not be empty";
+  pub const LONG_NAME_ERR: &'static str = "API key name must be at most 100 characters long";
+  pub const NO_SCOPES_ERR: &'static str = "API key must have at least one scope";
+  pub const BAD_EXPIRY_ERR: &'static str = "API key expiry must be between 1 second and 10 years";
+
+  pub fn is_valid(&self) -> Result<(), Vec<Violation>> {
+    let mut errors = Vec::new();
+    if self.name.trim().is_empty() {
+      errors.push(Violation::new("name_empty", Self::EMPTY_NAME_ERR));
+    }
+
+    if self.name.chars().count() > 100 {
+      errors.push(Violation::new("name_too_long", Self::LONG_NAME_ERR));
+    }
+
+    if self.scopes.is_empty() {
+      errors.push(Violation::new("scopes_missing", Self::NO_SCOPES_ERR));
+    }
+
+    for scope in &self.scopes {
+      match Scope::parse(scope) {
+        None => errors.push(Violation::new("scope_unknown", format!("Unknown scope {}", scope))),
+        Some(known) if !known.grantable_to_api_keys() => errors.push(Violation::new(
+          "scope_not_grantable",
+          format!("Scope {} cannot be granted to API keys", scope),
+        )),
+        Some(_) => {},
+      }
+    }
+
+    if matches!(self.expires_in, Some(expires_in) if !(1..=MAX_API_KEY_LIFETIME_SECS).contains(&expires_in)) {
+      errors.push(Violation::new("expires_in_invalid", Self::BAD_EXPIRY_ERR));
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct ApiKeyResult {
+  pub id: String,
+  pub name: String,
+  pub prefix: String,
+  pub scopes: Vec<String>,
+  pub created_at: DateTime<Utc>,
+  pub last_used: Option<DateTime<Utc>>,
+  pub expires_at: Option<DateTime<Utc>>,
+  /// The full key, only returned once when it is created.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub key: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyResult {
+  fn from(api_key: ApiKey) -> Self {
+    Self {
+      id: api_key.id.to_string(),
+      name: api_key.name,
+      prefix: api_key.prefix,
+      scopes: api_key.scopes,
+      created_at: Utc.from_utc_datetime(&api_key.created_at),
+      last_used: api_key.last_used.map(|last_used| Utc.from_utc_datetime(&last_used)),
+      expires_at: api_key.expires_at.map(|expires_at| Utc.from_utc_datetime(&expires_at)),
+      key: None,
+    }
+  }
+}
+
+pub async fn post_api_key(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let api_key_body = serde_json::from_slice(&body);
+  if let Err(err) = api_key_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let api_key_body: ApiKeyBody = api_key_body.unwrap();
+  if let Err(errors) = api_key_body.is_valid() {
+    return Ok(validation_response(errors));
+  }
+
+  let expires_at = api_key_body
+    .expires_in
+    .map(|expires_in| chrono::Utc::now().naive_utc() + chrono::Duration::seconds(expires_in));
+  match create_api_key(&db, user.id, api_key_body.name, api_key_body.scopes, expires_at) {
+    Ok((key, api_key)) => {
+      let result = ApiKeyResult {
+        key: Some(key),
+        ..ApiKeyResult::from(api_key)
+      };
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .header("Cache-Control", "no-store")
+          .body(Body::from(serde_json::to_string(&result).unwrap()))
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn list_api_keys(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match get_user_api_keys(&db, user.id) {
+    Ok(api_keys) => {
+      let api_keys: Vec<ApiKeyResult> = api_keys.into_iter().map(ApiKeyResult::from).collect();
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .body(Body::from(serde_json::to_string(&api_keys).unwrap()))
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn revoke_api_key(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  let api_key_id = req
+    .extensions()
+    .get::<PathParams>()
+    .and_then(|params| params.get("id"))
+    .map(Uuid::parse_str);
+  let api_key_id = match api_key_id {
+    Some(Ok(api_key_id)) => api_key_id,
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "invalid_api_key_id", "Invalid API key id"),
+  };
+
+  match delete_user_api_key(&db, user.id, api_key_id) {
+    Ok(0) => respond_error!(StatusCode::NOT_FOUND, "api_key_not_found", "API key not found"),
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use diesel::{ExpressionMethods, RunQueryDsl};
+  use hyper::{Method, StatusCode};
+
+  use super::{ApiKeyBody, ApiKeyResult, MAX_API_KEY_LIFETIME_SECS};
+  use crate::routes::handle_requests;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+  use crate::routes::DB;
+  use crate::schema::api_keys;
+
+  async fn create_key(token: &str, scopes: &[&str], expires_in: Option<i64>) -> (StatusCode, String) {
+    let body = serde_json::to_string(&ApiKeyBody {
+      name: "FizzBuzz Bot".to_string(),
+      scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+      expires_in,
+    })
+    .unwrap();
+    let res = handle_requests(build_test_request(Method::POST, "/api_keys", &body, Some(token.to_string())))
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    (status, body)
+  }
+
+  async fn create_valid_key(token: &str, scopes: &[&str]) -> ApiKeyResult {
+    let (status, body) = create_key(token, scopes, None).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  async fn status_of(method: Method, path: &str, token: &str) -> StatusCode {
+    handle_requests(build_test_request(method, path, "", Some(token.to_string())))
+      .await
+      .unwrap()
+      .status()
+  }
+
+  #[tokio::test]
+  async fn api_key_authenticates_within_scope() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let api_key = create_valid_key(&token, &["user:read"]).await;
+    let key = api_key.key.unwrap();
+    assert!(key.starts_with(&format!("ipv8_{}_", api_key.prefix)));
+
+    assert_eq!(status_of(Method::GET, "/user", &key).await, StatusCode::OK);
+    assert_eq!(
+      status_of(Method::POST, "/next_instruction", &key).await,
+      StatusCode::FORBIDDEN
+    );
+  }
+
+  #[tokio::test]
+  async fn api_key_cannot_manage_account() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let key = create_valid_key(&token, &["user:read", "game:play"]).await.key.unwrap();
+
+    assert_eq!(status_of(Method::GET, "/sessions", &key).await, StatusCode::FORBIDDEN);
+    assert_eq!(status_of(Method::GET, "/api_keys", &key).await, StatusCode::FORBIDDEN);
+    let (status, _) = create_key(&key, &["user:read"], None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+  }
+
+  #[tokio::test]
+  async fn list_tracks_last_use_without_exposing_key() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let key = create_valid_key(&token, &["user:read"]).await.key.unwrap();
+    assert_eq!(status_of(Method::GET, "/user", &key).await, StatusCode::OK);
+
+    let res = handle_requests(build_test_request(Method::GET, "/api_keys", "", Some(token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert!(!String::from_utf8_lossy(&body).contains(&key));
+    let api_keys: Vec<ApiKeyResult> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(api_keys.len(), 1);
+    assert!(api_keys[0].last_used.is_some());
+    assert!(api_keys[0].key.is_none());
+  }
+
+  #[tokio::test]
+  async fn revoked_api_key_stops_working() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let api_key = create_valid_key(&token, &["user:read"]).await;
+    let key = api_key.key.unwrap();
+
+    let path = format!("/api_keys/{}", api_key.id);
+    let other = register_and_login("other").await;
+    assert_eq!(status_of(Method::DELETE, &path, &other).await, StatusCode::NOT_FOUND);
+    assert_eq!(status_of(Method::DELETE, &path, &token).await, StatusCode::OK);
+    assert_eq!(status_of(Method::GET, "/user", &key).await, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn expired_api_key_is_rejected() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let (status, body) = create_key(&token, &["user:read"], Some(3600)).await;
+    assert_eq!(status, StatusCode::OK);
+    let key = serde_json::from_str::<ApiKeyResult>(&body).unwrap().key.unwrap();
+
+    {
+      let db = DB.lock().await;
+      diesel::update(api_keys::table)
+        .set(api_keys::expires_at.eq(chrono::Utc::now().naive_utc() - chrono::Duration::minutes(1)))
+        .execute(&*db)
+        .unwrap();
+    }
+
+    assert_eq!(status_of(Method::GET, "/user", &key).await, StatusCode::UNAUTHORIZED);
+  }
+
+  #[tokio::test]
+  async fn invalid_api_key_requests() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let (status, body) = create_key(&token, &[], None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(ApiKeyBody::NO_SCOPES_ERR));
+
+    let (status, body) = create_key(&token, &["admin:everything"], None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Unknown scope admin:everything"));
+
+    let (status, body) = create_key(&token, &["openid"], None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Scope openid cannot be granted to API keys"));
+
+    let (status, body) = create_key(&token, &["user:read"], Some(-5)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(ApiKeyBody::BAD_EXPIRY_ERR));
+
+    // Lifetimes that would overflow the expiry are refused rather than panicking
+    let (status, body) = create_key(&token, &["user:read"], Some(1_000_000_000_000_000)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(ApiKeyBody::BAD_EXPIRY_ERR));
+    let (status, _) = create_key(&token, &["user:read"], Some(MAX_API_KEY_LIFETIME_SECS)).await;
+    assert_eq!(status, StatusCode::OK);
+  }
+}
@@ -0,0 +1,407 @@
+use std::convert::Infallible;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use diesel::PgConnection;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::deletion::DELETION_POLICY;
+use crate::lockout::{account_subject, ip_subject, login_subject, LOCKOUT_POLICY};
+use crate::models::{delete_user_sessions, find_user_by_login, set_user_deletion, User, WEBAUTHN_CANCEL_DELETION};
+use crate::password::verify_dummy_password;
+use crate::routes::error::{error_response, ErrorBody};
+use crate::routes::users::login::{clear_login_failures, record_login_failure};
+use crate::routes::users::passkeys::{
+  check_passkey_assertion, find_assertion_passkey, verify_reauthentication, PasskeyAssertion,
+};
+use crate::routes::util::{client_ip, get_session_by_auth_header, too_many_requests};
+use crate::routes::DB;
+use crate::{respond, respond_error};
+
+/// Confirms the deletion with either the password or, for accounts that
+/// use passkeys, an answer to a ceremony from `/passkeys/reauthenticate`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct DeleteAccountBody {
+  #[serde(default)]
+  pub password: Option<String>,
+  #[serde(default)]
+  pub passkey: Option<PasskeyAssertion>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct CancelDeletionBody {
+  /// Username or email address, given along with the password.
+  #[serde(default)]
+  pub username: Option<String>,
+  #[serde(default)]
+  pub password: Option<String>,
+  #[serde(default)]
+  pub passkey: Option<PasskeyAssertion>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct DeletionResponse {
+  /// When the account and everything belonging to it is deleted, unless the
+  /// deletion is cancelled before.
+  pub deletion_due_at: DateTime<Utc>,
+}
+
+/// Schedules the signed in account for deletion once the grace period is
+/// over. Every session is revoked, and until then the account can only be
+/// used to cancel the deletion.
+pub async fn delete_account(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let delete_body = serde_json::from_slice(&body);
+  if let Err(err) = delete_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let delete_body: DeleteAccountBody = delete_body.unwrap();
+
+  match (&delete_body.password, &delete_body.passkey) {
+    (Some(password), None) => {
+      if !user.verify_password(password) {
+        return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_PASSWORD, "Invalid password");
+      }
+    },
+    (None, Some(assertion)) => {
+      if let Err(res) = verify_reauthentication(&db, &user, assertion) {
+        return Ok(res);
+      }
+    },
+    _ => {
+      return respond_error!(
+        StatusCode::BAD_REQUEST,
+        "reauthentication_required",
+        "Provide either the password or a passkey assertion"
+      )
+    },
+  }
+
+  let now = chrono::Utc::now().naive_utc();
+  let due_at = DELETION_POLICY.due_at(now);
+  if let Err(err) = set_user_deletion(&db, user.id, Some(due_at)) {
+    error!("{}", err.to_string());
+    return respond_error!(
+      StatusCode::INTERNAL_SERVER_ERROR,
+      ErrorBody::INTERNAL,
+      "Internal server error"
+    );
+  }
+  if let Err(err) = delete_user_sessions(&db, user.id) {
+    error!("{}", err.to_string());
+    return respond_error!(
+      StatusCode::INTERNAL_SERVER_ERROR,
+      ErrorBody::INTERNAL,
+      "Internal server error"
+    );
+  }
+
+  // Without a grace period there is no reason to wait for the reaper
+  if due_at <= now {
+    if let Err(err) = DELETION_POLICY.purge_due_accounts(&db, now) {
+      error!("{}", err.to_string());
+      return respond_error!(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorBody::INTERNAL,
+        "Internal server error"
+      );
+    }
+  }
+
+  Ok(
+    Response::builder()
+      .status(StatusCode::ACCEPTED)
+      .header("Content-Type", "application/json")
+      .body(Body::from(
+        serde_json::to_string(&DeletionResponse {
+          deletion_due_at: Utc.from_utc_datetime(&due_at),
+        })
+        .unwrap(),
+      ))
+      .unwrap(),
+  )
+}
+
+/// Answers `429 Too Many Requests` if any of `subjects` is locked out.
+fn check_lockouts(db: &PgConnection, subjects: &[String], now: NaiveDateTime) -> Result<(), Response<Body>> {
+  match LOCKOUT_POLICY.locked_for(db, subjects, now) {
+    Ok(Some(remaining)) => Err(too_many_requests(remaining.to_std().unwrap_or_default())),
+    Ok(None) => Ok(()),
+    Err(err) => {
+      error!("{}", err.to_string());
+      Err(error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorBody::INTERNAL,
+        "Internal server error",
+      ))
+    },
+  }
+}
+
+/// The account `login` names, if `password` is its password. Counts towards
+/// the same lockouts as `/login`.
+fn verify_cancel_password(
+  db: &PgConnection,
+  login: &str,
+  password: &str,
+  ip_subject: Option<&str>,
+  now: NaiveDateTime,
+) -> Result<User, Response<Body>> {
+  let login_subject = login_subject(login);
+  let user = find_user_by_login(db, login).ok();
+
+  let name_subjects: Vec<String> = std::iter::once(login_subject.clone())
+    .chain(user.iter().map(|user| account_subject(user.id)))
+    .collect();
+  let subjects: Vec<String> = name_subjects.iter().cloned().chain(ip_subject.map(str::to_string)).collect();
+  check_lockouts(db, &subjects, now)?;
+
+  let verified = match &user {
+    Some(user) => user.verify_password(password),
+    None => verify_dummy_password(password),
+  };
+  let user = match user {
+    Some(user) if verified => user,
+    _ => {
+      if let Err(err) = record_login_failure(db, &name_subjects, ip_subject, now) {
+        error!("{}", err.to_string());
+      }
+      return Err(error_response(
+        StatusCode::BAD_REQUEST,
+        ErrorBody::INVALID_CREDENTIALS,
+        "Invalid login credentials",
+      ));
+    },
+  };
+  if let Err(err) = clear_login_failures(db, &login_subject, &user) {
+    error!("{}", err.to_string());
+  }
+  Ok(user)
+}
+
+/// The account whose passkey signed `assertion`, counting towards the same
+/// lockouts as `/login/passkey/finish`.
+fn verify_cancel_passkey(
+  db: &PgConnection,
+  assertion: &PasskeyAssertion,
+  ip_subject: Option<&str>,
+  now: NaiveDateTime,
+) -> Result<User, Response<Body>> {
+  use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+  use crate::schema::users;
+
+  let ip_subjects: Vec<String> = ip_subject.map(str::to_string).into_iter().collect();
+  check_lockouts(db, &ip_subjects, now)?;
+  let (ceremony, passkey) = match find_assertion_passkey(db, assertion, WEBAUTHN_CANCEL_DELETION, None) {
+    Ok(result) => result,
+    Err(res) => {
+      if let Err(err) = record_login_failure(db, &[], ip_subject, now) {
+        error!("{}", err.to_string());
+      }
+      return Err(res);
+    },
+  };
+
+  let account = account_subject(passkey.user_id);
+  let subjects: Vec<String> = std::iter::once(account.clone()).chain(ip_subjects).collect();
+  check_lockouts(db, &subjects, now)?;
+  if let Err(res) = check_passkey_assertion(db, &ceremony, &passkey, assertion) {
+    if let Err(err) = record_login_failure(db, std::slice::from_ref(&account), ip_subject, now) {
+      error!("{}", err.to_string());
+    }
+    return Err(res);
+  }
+  if let Err(err) = LOCKOUT_POLICY.clear_failures(db, &account) {
+    error!("{}", err.to_string());
+  }
+
+  users::table.filter(users::id.eq(passkey.user_id)).first(db).map_err(|err| {
+    error!("{}", err.to_string());
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+  })
+}
+
+/// Keeps an account scheduled for deletion. Signing in is refused during the
+/// grace period, so this takes the same credentials as `/login`, or a passkey
+/// assertion for a ceremony from `/user/deletion/cancel/passkey`, and counts
+/// towards the same lockouts.
+pub async fn cancel_account_deletion(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let client_ip = client_ip(&req);
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let cancel_body = serde_json::from_slice(&body);
+  if let Err(err) = cancel_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let cancel_body: CancelDeletionBody = cancel_body.unwrap();
+
+  let db = DB.lock().await;
+  let now = chrono::Utc::now().naive_utc();
+  let ip_subject = client_ip.map(ip_subject);
+  let user = match (&cancel_body.username, &cancel_body.password, &cancel_body.passkey) {
+    (Some(username), Some(password), None) => {
+      verify_cancel_password(&db, username, password, ip_subject.as_deref(), now)
+    },
+    (None, None, Some(assertion)) => verify_cancel_passkey(&db, assertion, ip_subject.as_deref(), now),
+    _ => {
+      return respond_error!(
+        StatusCode::BAD_REQUEST,
+        "reauthentication_required",
+        "Provide either the username and password or a passkey assertion"
+      )
+    },
+  };
+  let user = match user {
+    Ok(user) => user,
+    Err(res) => return Ok(res),
+  };
+
+  if !user.is_pending_deletion() {
+    return respond_error!(
+      StatusCode::CONFLICT,
+      "deletion_not_scheduled",
+      "Account is not scheduled for deletion"
+    );
+  }
+  match set_user_deletion(&db, user.id, None) {
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorBody::INTERNAL,
+        "Internal server error"
+      )
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::Duration;
+  use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+  use hyper::{Method, StatusCode};
+
+  use super::{CancelDeletionBody, DeleteAccountBody, DeletionResponse};
+  use crate::deletion::{DELETED_ACCOUNTS, DELETION_POLICY};
+  use crate::models::{create_game_instruction, find_user_by_login};
+  use crate::routes::error::test::error_code;
+  use crate::routes::test::request;
+  use crate::routes::users::test::{before_user_test, login, register_and_login};
+  use crate::routes::DB;
+  use crate::schema::{games, users};
+
+  async fn delete_account(token: &str, password: &str) -> (StatusCode, String) {
+    let body = DeleteAccountBody {
+      password: Some(password.to_string()),
+      passkey: None,
+    };
+    request(Method::DELETE, "/user", serde_json::to_string(&body).unwrap(), Some(token)).await
+  }
+
+  async fn cancel_deletion(username: &str, password: &str) -> (StatusCode, String) {
+    let body = CancelDeletionBody {
+      username: Some(username.to_string()),
+      password: Some(password.to_string()),
+      passkey: None,
+    };
+    request(
+      Method::POST,
+      "/user/deletion/cancel",
+      serde_json::to_string(&body).unwrap(),
+      None,
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn deletion_requires_the_password() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let (status, body) = delete_account(&token, "wrongpassword").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "Test failed: {}", body);
+    assert_eq!(error_code(&body), "invalid_password");
+
+    login("tester").await;
+  }
+
+  #[tokio::test]
+  async fn scheduled_deletion_blocks_login_until_cancelled() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let (status, body) = delete_account(&token, "testtesttest").await;
+    assert_eq!(status, StatusCode::ACCEPTED, "Test failed: {}", body);
+    let response: DeletionResponse = serde_json::from_str(&body).unwrap();
+    assert!(response.deletion_due_at > chrono::Utc::now());
+
+    // Every session is revoked and no new one is handed out
+    let (status, _) = request(Method::GET, "/user", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body = r#"{"username": "tester", "password": "testtesttest"}"#.to_string();
+    let (status, body) = request(Method::POST, "/login", body, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "Test failed: {}", body);
+    assert_eq!(error_code(&body), "account_pending_deletion");
+
+    let (status, body) = cancel_deletion("tester", "wrongpassword").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "Test failed: {}", body);
+    let (status, body) = cancel_deletion("tester", "testtesttest").await;
+    assert_eq!(status, StatusCode::OK, "Test failed: {}", body);
+    let (status, body) = cancel_deletion("tester", "testtesttest").await;
+    assert_eq!(status, StatusCode::CONFLICT, "Test failed: {}", body);
+
+    login("tester").await;
+  }
+
+  #[tokio::test]
+  async fn due_accounts_are_purged_with_their_data() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    register_and_login("testertwo").await;
+    {
+      let db = DB.lock().await;
+      let user = find_user_by_login(&db, "tester").unwrap();
+      create_game_instruction(&db, user.id, 1).unwrap();
+    }
+
+    let (status, body) = delete_account(&token, "testtesttest").await;
+    assert_eq!(status, StatusCode::ACCEPTED, "Test failed: {}", body);
+
+    let now = chrono::Utc::now().naive_utc();
+    let db = DB.lock().await;
+    let user = find_user_by_login(&db, "tester").unwrap();
+    let mut deleted = DELETED_ACCOUNTS.subscribe();
+    assert_eq!(DELETION_POLICY.purge_due_accounts(&db, now).unwrap(), 0);
+
+    diesel::update(users::table.filter(users::id.eq(user.id)))
+      .set(users::deletion_due_at.eq(now - Duration::minutes(1)))
+      .execute(&*db)
+      .unwrap();
+    assert_eq!(DELETION_POLICY.purge_due_accounts(&db, now).unwrap(), 1);
+    assert_eq!(deleted.try_recv().unwrap(), user.id);
+
+    assert!(find_user_by_login(&db, "tester").is_err());
+    assert!(find_user_by_login(&db, "testertwo").is_ok());
+    let games: i64 = games::table
+      .filter(games::user_id.eq(user.id))
+      .count()
+      .get_result(&*db)
+      .unwrap();
+    assert_eq!(games, 0);
+  }
+}
@@ -0,0 +1,190 @@
+use std::convert::Infallible;
+use std::time::Duration as StdDuration;
+
+use diesel::PgConnection;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+#[cfg(test)]
+use serde::Serialize;
+use tracing::error;
+
+use crate::mailer::{Mail, MAILER};
+use crate::models::{create_email_verification, verify_email, User};
+use crate::rate_limit::RateLimiter;
+use crate::routes::error::ErrorBody;
+use crate::routes::util::{get_session_by_auth_header, too_many_requests};
+use crate::routes::DB;
+use crate::{respond, respond_error};
+
+const VERIFICATION_TTL_SECS: i64 = 60 * 60 * 24;
+
+lazy_static::lazy_static! {
+  static ref RESENDS_PER_USER: RateLimiter = RateLimiter::new(3, StdDuration::from_secs(60 * 60));
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct VerifyEmailBody {
+  pub token: String,
+}
+
+/// Creates a verification token for the user's email address and returns the
+/// mail carrying it, or `None` if the user has no address.
+pub fn verification_mail(db: &PgConnection, user: &User) -> Result<Option<Mail>, diesel::result::Error> {
+  let email = match &user.email {
+    Some(email) => email.clone(),
+    None => return Ok(None),
+  };
+
+  let token = create_email_verification(
+    db,
+    user.id,
+    email.clone(),
+    chrono::Duration::seconds(VERIFICATION_TTL_SECS),
+  )?;
+  Ok(Some(Mail {
+    to: email,
+    subject: "Verify your email address".to_string(),
+    body: format!(
+      "Use this token within a day to verify the email address of {}:\n\n{}\n\nIf you did not create this account, \
+       you can ignore this email.",
+      user.username, token
+    ),
+  }))
+}
+
+/// Marks an email address as verified with a mailed token.
+pub async fn confirm_email(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let verify_body = serde_json::from_slice(&body);
+  if let Err(err) = verify_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let verify_body: VerifyEmailBody = verify_body.unwrap();
+
+  let db = DB.lock().await;
+  match verify_email(&db, &verify_body.token) {
+    Ok(true) => respond!(StatusCode::OK, ""),
+    Ok(false) => respond_error!(
+      StatusCode::BAD_REQUEST,
+      "invalid_verification_token",
+      "Invalid or expired verification token"
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Mails a new verification token to the signed in user.
+pub async fn resend_verification(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let mail = {
+    let db = DB.lock().await;
+
+    let (user, _) = match get_session_by_auth_header(&db, &req) {
+      Ok(result) => result,
+      Err(res) => return Ok(res),
+    };
+    if user.email.is_none() {
+      return respond_error!(StatusCode::BAD_REQUEST, "no_email", "Account has no email address");
+    }
+    if user.is_email_verified() {
+      return respond_error!(StatusCode::CONFLICT, "email_already_verified", "Email is already verified");
+    }
+    if let Err(retry_after) = RESENDS_PER_USER.check(&user.id.to_string()) {
+      return Ok(too_many_requests(retry_after));
+    }
+
+    match verification_mail(&db, &user) {
+      Ok(Some(mail)) => mail,
+      Ok(None) => return respond_error!(StatusCode::BAD_REQUEST, "no_email", "Account has no email address"),
+      Err(err) => {
+        error!("{}", err.to_string());
+        return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+      },
+    }
+  };
+
+  if let Err(err) = MAILER.send(&mail).await {
+    error!("{}", err.to_string());
+    return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+  }
+  respond!(StatusCode::ACCEPTED, "")
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::VerifyEmailBody;
+  use crate::routes::test::request;
+  use crate::routes::users::register::UserBody;
+  use crate::routes::users::test::{before_user_test, last_mailed_token, register_and_login, verify_email};
+
+  async fn user_json(token: &str) -> serde_json::Value {
+    let (status, body) = request(Method::GET, "/user", String::new(), Some(token)).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  #[tokio::test]
+  async fn verification_flow() {
+    before_user_test().await;
+    let token = register_and_login("verifier").await;
+    assert_eq!(user_json(&token).await["email_verified"], false);
+
+    let (status, _) = request(Method::POST, "/user/email/verification", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    verify_email("verifier").await;
+
+    let user = user_json(&token).await;
+    assert_eq!(user["email"], "verifier@example.com");
+    assert_eq!(user["email_verified"], true);
+
+    let (status, _) = request(Method::POST, "/user/email/verification", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+  }
+
+  #[tokio::test]
+  async fn verification_token_is_single_use() {
+    before_user_test().await;
+    register_and_login("verifier").await;
+    let body = serde_json::to_string(&VerifyEmailBody {
+      token: last_mailed_token("verifier@example.com"),
+    })
+    .unwrap();
+
+    let (status, _) = request(Method::POST, "/user/email/verify", body.clone(), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(Method::POST, "/user/email/verify", body, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn login_by_email() {
+    before_user_test().await;
+    register_and_login("emaillogin").await;
+
+    let body = r#"{"username": "EmailLogin@Example.com", "password": "testtesttest"}"#.to_string();
+    let (status, body) = request(Method::POST, "/login", body, None).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+  }
+
+  #[tokio::test]
+  async fn email_must_be_unique() {
+    before_user_test().await;
+    register_and_login("first").await;
+
+    let body = serde_json::to_string(&UserBody {
+      name: "Tester McTester".to_string(),
+      username: "second".to_string(),
+      password: "testtesttest".to_string(),
+      email: Some("FIRST@example.com".to_string()),
+    })
+    .unwrap();
+    let (status, _) = request(Method::POST, "/register", body, None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+  }
+}
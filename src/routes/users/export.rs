@@ -0,0 +1,337 @@
+use std::convert::Infallible;
+
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::result::Error;
+use diesel::PgConnection;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+#[cfg(test)]
+use serde::Deserialize;
+use tracing::error;
+
+use crate::lockout::{account_subject, login_subject, second_factor_subject};
+use crate::models::{
+  count_unused_recovery_codes, find_login_throttles, get_totp_credential, get_user_api_keys,
+  get_user_email_verifications, get_user_game, get_user_oauth_clients, get_user_passkeys, get_user_password_resets,
+  get_user_sessions, EmailVerification, Game, LoginThrottle, PasswordReset, Session, User,
+};
+use crate::respond_error;
+use crate::routes::error::ErrorBody;
+use crate::routes::oauth::clients::ClientResult;
+use crate::routes::users::api_keys::ApiKeyResult;
+use crate::routes::users::passkeys::PasskeyResult;
+use crate::routes::users::sessions::SessionResult;
+use crate::routes::util::get_session_by_auth_header;
+use crate::routes::DB;
+
+/// Bumped whenever a field is removed or changes meaning. Added fields do not
+/// change it.
+pub const EXPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Everything stored about an account, as documented under "Data export" in
+/// the README. Secrets such as password digests, key hashes and the TOTP
+/// secret are left out, only their existence is exported.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct UserExport {
+  pub schema_version: u32,
+  pub exported_at: DateTime<Utc>,
+  pub profile: ProfileExport,
+  pub sessions: Vec<SessionResult>,
+  /// The current license game instruction. Earlier instructions are not
+  /// kept, `profile.license_game_stage` counts the completed ones.
+  pub game: Option<GameExport>,
+  pub api_keys: Vec<ApiKeyResult>,
+  pub passkeys: Vec<PasskeyResult>,
+  pub oauth_clients: Vec<ClientResult>,
+  pub two_factor: TwoFactorExport,
+  pub email_verifications: Vec<EmailVerificationExport>,
+  pub password_resets: Vec<PasswordResetExport>,
+  pub lockouts: Vec<LockoutExport>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct ProfileExport {
+  pub id: String,
+  pub name: String,
+  pub username: String,
+  pub email: Option<String>,
+  pub email_verified_at: Option<DateTime<Utc>>,
+  pub has_password: bool,
+  pub license_game_stage: i32,
+  pub licensed: bool,
+  pub deletion_due_at: Option<DateTime<Utc>>,
+}
+
+impl From<&User> for ProfileExport {
+  fn from(user: &User) -> Self {
+    Self {
+      id: user.id.to_string(),
+      name: user.name.clone(),
+      username: user.username.clone(),
+      email: user.email.clone(),
+      email_verified_at: user
+        .email_verified_at
+        .map(|verified_at| Utc.from_utc_datetime(&verified_at)),
+      has_password: user.password_digest.is_some(),
+      license_game_stage: user.license_game_stage,
+      licensed: user.is_licensed(),
+      deletion_due_at: user.deletion_due_at.map(|due_at| Utc.from_utc_datetime(&due_at)),
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct GameExport {
+  pub instruction: i32,
+  pub contacted_fizz: bool,
+  pub contacted_buzz: bool,
+  pub contacted_instructions: bool,
+  pub completed: bool,
+}
+
+impl From<Game> for GameExport {
+  fn from(game: Game) -> Self {
+    Self {
+      instruction: game.instruction,
+      contacted_fizz: game.contacted_fizz,
+      contacted_buzz: game.contacted_buzz,
+      contacted_instructions: game.contacted_instructions,
+      completed: game.instruction_completed(),
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct TwoFactorExport {
+  pub totp_enabled: bool,
+  pub totp_confirmed_at: Option<DateTime<Utc>>,
+  pub recovery_codes_remaining: i64,
+}
+
+/// A verification mail whose link has not been followed yet.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct EmailVerificationExport {
+  pub email: String,
+  pub created_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl From<EmailVerification> for EmailVerificationExport {
+  fn from(verification: EmailVerification) -> Self {
+    Self {
+      email: verification.email,
+      created_at: Utc.from_utc_datetime(&verification.created_at),
+      expires_at: Utc.from_utc_datetime(&verification.expires_at),
+    }
+  }
+}
+
+/// A password reset link that can still be used.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct PasswordResetExport {
+  pub created_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl From<PasswordReset> for PasswordResetExport {
+  fn from(reset: PasswordReset) -> Self {
+    Self {
+      created_at: Utc.from_utc_datetime(&reset.created_at),
+      expires_at: Utc.from_utc_datetime(&reset.expires_at),
+    }
+  }
+}
+
+/// Failed logins counted against the account, see "Lockouts" in the README.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct LockoutExport {
+  pub subject: String,
+  pub failed_attempts: i32,
+  pub last_failed_at: DateTime<Utc>,
+  pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LockoutExport {
+  fn new(throttle: LoginThrottle, subject: String) -> Self {
+    Self {
+      subject,
+      failed_attempts: throttle.failed_attempts,
+      last_failed_at: Utc.from_utc_datetime(&throttle.last_failed_at),
+      locked_until: throttle
+        .locked_until
+        .map(|locked_until| Utc.from_utc_datetime(&locked_until)),
+    }
+  }
+}
+
+/// The lockout subjects that belong to `user`, along with how to show them.
+/// Login names are stored hashed, so they are shown by name. Client addresses
+/// are shared between accounts and left out.
+fn lockout_subjects(user: &User) -> Vec<(String, String)> {
+  std::iter::once(user.username.as_str())
+    .chain(user.email.as_deref())
+    .map(|login| (login_subject(login), format!("login:{}", login.to_lowercase())))
+    .chain(
+      [account_subject(user.id), second_factor_subject(user.id)]
+        .into_iter()
+        .map(|subject| (subject.clone(), subject)),
+    )
+    .collect()
+}
+
+fn find_lockouts(conn: &PgConnection, user: &User) -> Result<Vec<LockoutExport>, Error> {
+  let subjects = lockout_subjects(user);
+  let stored: Vec<String> = subjects.iter().map(|(subject, _)| subject.clone()).collect();
+  Ok(
+    find_login_throttles(conn, &stored)?
+      .into_iter()
+      .filter_map(|throttle| {
+        let shown = subjects
+          .iter()
+          .find(|(subject, _)| *subject == throttle.subject)
+          .map(|(_, shown)| shown.clone())?;
+        Some(LockoutExport::new(throttle, shown))
+      })
+      .collect(),
+  )
+}
+
+fn collect_export(conn: &PgConnection, user: &User, current: &Session) -> Result<UserExport, Error> {
+  let totp = get_totp_credential(conn, user.id).ok();
+  Ok(UserExport {
+    schema_version: EXPORT_SCHEMA_VERSION,
+    exported_at: Utc::now(),
+    profile: ProfileExport::from(user),
+    sessions: get_user_sessions(conn, user.id)?
+      .iter()
+      .map(|session| SessionResult::new(session, current))
+      .collect(),
+    game: get_user_game(conn, user.id)?.map(GameExport::from),
+    api_keys: get_user_api_keys(conn, user.id)?
+      .into_iter()
+      .map(ApiKeyResult::from)
+      .collect(),
+    passkeys: get_user_passkeys(conn, user.id)?
+      .into_iter()
+      .map(PasskeyResult::from)
+      .collect(),
+    oauth_clients: get_user_oauth_clients(conn, user.id)?
+      .into_iter()
+      .map(ClientResult::from)
+      .collect(),
+    two_factor: TwoFactorExport {
+      totp_enabled: matches!(&totp, Some(totp) if totp.is_confirmed()),
+      totp_confirmed_at: totp
+        .and_then(|totp| totp.confirmed_at)
+        .map(|confirmed_at| Utc.from_utc_datetime(&confirmed_at)),
+      recovery_codes_remaining: count_unused_recovery_codes(conn, user.id)?,
+    },
+    email_verifications: get_user_email_verifications(conn, user.id)?
+      .into_iter()
+      .map(EmailVerificationExport::from)
+      .collect(),
+    password_resets: get_user_password_resets(conn, user.id)?
+      .into_iter()
+      .map(PasswordResetExport::from)
+      .collect(),
+    lockouts: find_lockouts(conn, user)?,
+  })
+}
+
+/// Hands the signed in user a copy of everything stored about them.
+pub async fn export_user(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, current) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match collect_export(&db, &user, &current) {
+    Ok(export) => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Disposition", "attachment; filename=\"user-export.json\"")
+        .body(Body::from(serde_json::to_string_pretty(&export).unwrap()))
+        .unwrap(),
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorBody::INTERNAL,
+        "Internal server error"
+      )
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::{UserExport, EXPORT_SCHEMA_VERSION};
+  use crate::models::{
+    create_game_instruction, create_password_reset, create_recovery_codes, find_user_by_login, use_recovery_code,
+  };
+  use crate::routes::test::request;
+  use crate::routes::users::test::{before_user_test, login, register_and_login};
+  use crate::routes::DB;
+
+  #[tokio::test]
+  async fn export_contains_profile_sessions_and_game() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    login("tester").await;
+    register_and_login("testertwo").await;
+    {
+      let db = DB.lock().await;
+      let user = find_user_by_login(&db, "tester").unwrap();
+      create_game_instruction(&db, user.id, 15).unwrap();
+      create_password_reset(&db, user.id, chrono::Duration::hours(1)).unwrap();
+      let codes = create_recovery_codes(&db, user.id).unwrap();
+      assert!(use_recovery_code(&db, user.id, &codes[0]).unwrap());
+    }
+    let body = r#"{"username": "Tester", "password": "wrongwrongwrong"}"#.to_string();
+    let (status, _) = request(Method::POST, "/login", body, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = request(Method::GET, "/user/export", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::OK, "Test failed: {}", body);
+    assert!(!body.contains("password_digest") && !body.contains("$argon2"));
+
+    let export: UserExport = serde_json::from_str(&body).unwrap();
+    assert_eq!(export.schema_version, EXPORT_SCHEMA_VERSION);
+    assert_eq!(export.profile.username, "tester");
+    assert_eq!(export.profile.email.as_deref(), Some("tester@example.com"));
+    assert!(export.profile.has_password);
+    assert_eq!(export.sessions.len(), 2);
+    assert_eq!(export.sessions.iter().filter(|session| session.current).count(), 1);
+    let game = export.game.unwrap();
+    assert_eq!(game.instruction, 15);
+    assert!(!game.completed);
+    assert!(export.api_keys.is_empty() && export.passkeys.is_empty() && export.oauth_clients.is_empty());
+    assert!(!export.two_factor.totp_enabled);
+    assert_eq!(export.two_factor.recovery_codes_remaining, 9);
+    assert_eq!(export.email_verifications.len(), 1);
+    assert_eq!(export.email_verifications[0].email, "tester@example.com");
+    assert_eq!(export.password_resets.len(), 1);
+    let subjects: Vec<&str> = export.lockouts.iter().map(|lockout| lockout.subject.as_str()).collect();
+    assert_eq!(subjects, ["login:tester".to_string(), format!("user:{}", export.profile.id)]);
+    assert!(export.lockouts.iter().all(|lockout| lockout.failed_attempts == 1));
+  }
+
+  #[tokio::test]
+  async fn export_requires_a_session() {
+    let (status, _) = request(Method::GET, "/user/export", String::new(), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+  }
+}
@@ -1,22 +1,36 @@
 use std::convert::Infallible;
 
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use diesel::query_dsl::methods::FilterDsl;
+use diesel::PgConnection;
 use hyper::{Body, Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use tracing::error;
-use uuid::Uuid;
 
-use crate::diesel::{ExpressionMethods, RunQueryDsl};
 use crate::game::GAME_STRINGS;
-use crate::models::{Session, User};
-use crate::respond;
+use crate::jwt::ACCESS_TOKENS;
+use crate::lockout::{account_subject, ip_subject, login_subject, LOCKOUT_POLICY};
+use crate::models::{
+  create_session, find_user_by_login, get_totp_credential, update_user_password, SessionMetadata, User,
+};
+use crate::password::{needs_rehash, verify_dummy_password};
+use crate::policy::Violation;
+use crate::respond_error;
+use crate::routes::error::{validation_response, ErrorBody};
+use crate::routes::users::refresh::issue_token_pair;
+use crate::routes::users::two_factor::login_challenge;
+use crate::routes::util::{client_ip, too_many_requests, user_agent, AuthError};
 use crate::routes::DB;
 
 #[derive(Deserialize)]
 pub struct LoginBody {
+  /// Username or email address.
   username: String,
   password: String,
+  #[serde(default)]
+  device: Option<String>,
+}
+
+impl LoginBody {
+  pub const LONG_DEVICE_ERR: &'static str = "Device label must be at most 100 characters long";
 }
 
 #[derive(Serialize)]
@@ -25,77 +39,184 @@ pub struct LoginResponse {
   pub token: String,
   pub licensed: bool,
   pub incoming_message: Option<Vec<String>>,
+  /// Short lived signed token, only issued when a signing key is configured.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub access_token: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub expires_in: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<String>,
 }
 
 pub async fn login(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-  use crate::schema::sessions::dsl::*;
-  use crate::schema::users::dsl::*;
+  let client_ip = client_ip(&req);
+  let client_user_agent = user_agent(&req);
 
   // Parse Login Body
   let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
   let login_body = serde_json::from_slice(&body);
   if let Err(err) = login_body {
-    return respond!(StatusCode::BAD_REQUEST, err.to_string());
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
   }
   let login_body: LoginBody = login_body.unwrap();
+  if matches!(&login_body.device, Some(label) if label.chars().count() > 100) {
+    return Ok(validation_response(vec![Violation::new(
+      "device_too_long",
+      LoginBody::LONG_DEVICE_ERR,
+    )]));
+  }
 
   // Find user if exists
   let db = DB.lock().await;
-  let result = users.filter(username.eq(&login_body.username)).first(&*db);
-  if let Err(_) = result {
-    return respond!(StatusCode::BAD_REQUEST, "Invalid login credentials");
+  let now = chrono::Utc::now().naive_utc();
+  let ip_subject = client_ip.map(ip_subject);
+  let login_subject = login_subject(&login_body.username);
+  let user = find_user_by_login(&db, &login_body.username).ok();
+
+  // Refuse locked out names, accounts and addresses before looking at the
+  // password
+  let name_subjects: Vec<String> = std::iter::once(login_subject.clone())
+    .chain(user.iter().map(|user| account_subject(user.id)))
+    .collect();
+  let subjects: Vec<String> = name_subjects.iter().cloned().chain(ip_subject.clone()).collect();
+  match LOCKOUT_POLICY.locked_for(&db, &subjects, now) {
+    Ok(Some(remaining)) => return Ok(too_many_requests(remaining.to_std().unwrap_or_default())),
+    Ok(None) => {},
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
   }
 
+  // Unknown logins check a dummy password so they take as long as a wrong
+  // password, and get the same answer
+  let user: User = match user {
+    Some(user) => user,
+    None => {
+      verify_dummy_password(&login_body.password);
+      if let Err(err) = record_login_failure(&db, &name_subjects, ip_subject.as_deref(), now) {
+        error!("{}", err.to_string());
+      }
+      return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_CREDENTIALS, "Invalid login credentials");
+    },
+  };
+
   // Check password
-  let user: User = result.unwrap();
-  let parsed_hash = PasswordHash::new(&user.password_digest).unwrap();
-  if Argon2::default()
-    .verify_password(login_body.password.as_bytes(), &parsed_hash)
-    .is_ok()
-  {
-    // Create session if password was correct
-    let session = Session {
-      token: Uuid::new_v4(),
-      user_id: user.id,
-      last_used: chrono::Utc::now().naive_utc(),
-    };
+  if user.verify_password(&login_body.password) {
+    if let Err(err) = clear_login_failures(&db, &login_subject, &user) {
+      error!("{}", err.to_string());
+    }
 
-    match diesel::insert_into(sessions)
-      .values(&session)
-      .get_result::<Session>(&*db)
-    {
-      Ok(session) => {
-        // Return login message
-        let incoming_message = if !user.is_licensed() {
-          Some(GAME_STRINGS.puzzle_message())
-        } else {
-          None
-        };
-        let incoming_message = incoming_message.map(|x| x.split('\n').map(|x| x.to_owned()).collect());
-        Ok(
-          Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(
-              Body::from(
-                serde_json::to_string(&LoginResponse {
-                  token: session.token.to_string(),
-                  licensed: user.is_licensed(),
-                  incoming_message,
-                })
-                .unwrap(),
-              ),
-            )
-            .unwrap(),
-        )
-      },
-      Err(err) => {
+    // Upgrade hashes made under older Argon2 settings while the password is at hand
+    if user.password_digest.as_deref().is_some_and(needs_rehash) {
+      if let Err(err) = update_user_password(&db, user.id, &login_body.password) {
         error!("{}", err.to_string());
-        respond!(StatusCode::INTERNAL_SERVER_ERROR, "")
-      },
+      }
     }
+
+    // Ask for a second factor before creating a session if one is enabled
+    if matches!(get_totp_credential(&db, user.id), Ok(credential) if credential.is_confirmed()) {
+      return login_challenge(&db, &user, login_body.device);
+    }
+
+    // Create session if password was correct
+    let metadata = SessionMetadata {
+      ip: client_ip.map(|ip| ip.to_string()),
+      user_agent: client_user_agent,
+      device_label: login_body.device,
+      ..SessionMetadata::default()
+    };
+
+    start_session(&db, &user, metadata)
   } else {
-    return respond!(StatusCode::BAD_REQUEST, "Invalid login credentials");
+    if let Err(err) = record_login_failure(&db, &name_subjects, ip_subject.as_deref(), now) {
+      error!("{}", err.to_string());
+    }
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_CREDENTIALS, "Invalid login credentials");
+  }
+}
+
+/// Counts a failed login against the login name and account subjects and
+/// the client address. Every subject is attempted even if an earlier one
+/// fails, the first error is returned.
+pub fn record_login_failure(
+  db: &PgConnection,
+  account_subjects: &[String],
+  ip_subject: Option<&str>,
+  now: chrono::NaiveDateTime,
+) -> Result<(), diesel::result::Error> {
+  let mut result = Ok(());
+  for account_subject in account_subjects {
+    result = result.and(LOCKOUT_POLICY.record_failure(db, account_subject, LOCKOUT_POLICY.max_failures, now));
+  }
+  if let Some(ip_subject) = ip_subject {
+    result = result.and(LOCKOUT_POLICY.record_failure(db, ip_subject, LOCKOUT_POLICY.max_failures_per_ip, now));
+  }
+  result
+}
+
+/// Forgets the failures of `login_subject` and of `user`'s account once they
+/// proved who they are.
+pub fn clear_login_failures(db: &PgConnection, login_subject: &str, user: &User) -> Result<(), diesel::result::Error> {
+  LOCKOUT_POLICY.clear_failures(db, login_subject)?;
+  LOCKOUT_POLICY.clear_failures(db, &account_subject(user.id))
+}
+
+/// Creates a session for `user` and responds with its tokens. This is the last
+/// step of every successful login.
+pub fn start_session(db: &PgConnection, user: &User, metadata: SessionMetadata) -> Result<Response<Body>, Infallible> {
+  // Accounts waiting to be deleted can only cancel the deletion
+  if user.is_pending_deletion() {
+    return respond_error!(
+      StatusCode::FORBIDDEN,
+      AuthError::AccountPendingDeletion.code(),
+      "Account is scheduled for deletion, cancel the deletion to sign in again"
+    );
+  }
+
+  match create_session(db, user.id, metadata) {
+    Ok((token, session)) => {
+      // Return login message
+      let incoming_message = if !user.is_licensed() {
+        Some(GAME_STRINGS.puzzle_message())
+      } else {
+        None
+      };
+      let incoming_message = incoming_message.map(|x| x.split('\n').map(|x| x.to_owned()).collect());
+      let tokens = match ACCESS_TOKENS.as_ref() {
+        Some(signer) => match issue_token_pair(db, signer, user, &session) {
+          Ok(tokens) => Some(tokens),
+          Err(err) => {
+            error!("{}", err.to_string());
+            return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+          },
+        },
+        None => None,
+      };
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .body(
+            Body::from(
+              serde_json::to_string(&LoginResponse {
+                token: token.to_string(),
+                licensed: user.is_licensed(),
+                incoming_message,
+                expires_in: tokens.as_ref().map(|tokens| tokens.expires_in),
+                access_token: tokens.as_ref().map(|tokens| tokens.access_token.clone()),
+                refresh_token: tokens.map(|tokens| tokens.refresh_token),
+              })
+              .unwrap(),
+            ),
+          )
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
   }
 }
 
@@ -104,6 +225,7 @@ mod test {
     use hyper::{Method, StatusCode};
 
     use crate::routes::{users::{test::before_user_test, register::UserBody}, test::build_test_request, handle_requests};
+    use crate::routes::error::{test::error_code, ErrorBody};
 
     use super::LoginResponse;
 
@@ -116,6 +238,7 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "tester".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
     };
     let req = build_test_request(
       Method::POST,
@@ -150,6 +273,250 @@ mod test {
     assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
   }
 
+  #[tokio::test]
+  async fn session_token_is_hashed_at_rest() {
+    use diesel::RunQueryDsl;
+
+    use crate::models::Session;
+    use crate::routes::users::test::register_and_login;
+    use crate::routes::DB;
+    use crate::schema::sessions;
+    use crate::tokens::hash_token;
+
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let db = DB.lock().await;
+    let stored: Vec<Session> = sessions::table.load(&*db).unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].token_hash, hash_token(&token));
+    assert_ne!(stored[0].id.to_string(), token);
+  }
+
+  #[tokio::test]
+  async fn weak_password_hash_is_upgraded() {
+    use argon2::password_hash::SaltString;
+    use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use rand_core::OsRng;
+
+    use crate::models::User;
+    use crate::password::needs_rehash;
+    use crate::routes::users::test::{login, register_and_login};
+    use crate::routes::DB;
+    use crate::schema::users;
+
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    let weak = Argon2::new(Algorithm::Argon2i, Version::default(), Params::new(1024, 1, 1, None).unwrap())
+      .hash_password(b"testtesttest", &SaltString::generate(&mut OsRng))
+      .unwrap()
+      .to_string();
+    {
+      let db = DB.lock().await;
+      diesel::update(users::table.filter(users::username.eq("tester")))
+        .set(users::password_digest.eq(&weak))
+        .execute(&*db)
+        .unwrap();
+    }
+
+    login("tester").await;
+
+    let db = DB.lock().await;
+    let user: User = users::table.filter(users::username.eq("tester")).first(&*db).unwrap();
+    let digest = user.password_digest.clone().unwrap();
+    assert_ne!(digest, weak);
+    assert!(!needs_rehash(&digest));
+    assert!(user.verify_password("testtesttest"));
+  }
+
+  async fn login_from(username: &str, password: &str, ip: [u8; 4]) -> hyper::Response<hyper::Body> {
+    use std::net::SocketAddr;
+
+    use crate::routes::util::ClientAddr;
+
+    let body = format!(r#"{{"username": "{}", "password": "{}"}}"#, username, password);
+    let mut req = build_test_request(Method::POST, "/login", &body, None);
+    req.extensions_mut().insert(ClientAddr(SocketAddr::from((ip, 40000))));
+    handle_requests(req).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn repeated_failures_lock_the_account() {
+    use diesel::{ExpressionMethods, RunQueryDsl};
+
+    use crate::lockout::LOCKOUT_POLICY;
+    use crate::routes::users::test::register_and_login;
+    use crate::routes::DB;
+    use crate::schema::login_throttles;
+
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    for _ in 0..LOCKOUT_POLICY.max_failures {
+      let res = login_from("tester", "wrongpassword", [10, 2, 0, 1]).await;
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // Even the right password is refused while locked, from any address
+    let res = login_from("tester", "testtesttest", [10, 2, 0, 2]).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: i64 = res.headers()["Retry-After"].to_str().unwrap().parse().unwrap();
+    assert_eq!(retry_after, LOCKOUT_POLICY.lockout.num_seconds());
+
+    let expire_lockout = || async {
+      let db = DB.lock().await;
+      diesel::update(login_throttles::table)
+        .set(login_throttles::locked_until.eq(chrono::Utc::now().naive_utc()))
+        .execute(&*db)
+        .unwrap();
+    };
+
+    // Failing again right after a lockout locks for twice as long
+    expire_lockout().await;
+    let res = login_from("tester", "wrongpassword", [10, 2, 0, 1]).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = login_from("tester", "testtesttest", [10, 2, 0, 1]).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: i64 = res.headers()["Retry-After"].to_str().unwrap().parse().unwrap();
+    assert_eq!(retry_after, LOCKOUT_POLICY.lockout.num_seconds() * 2);
+
+    // A successful login clears the failures
+    expire_lockout().await;
+    let res = login_from("tester", "testtesttest", [10, 2, 0, 2]).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = login_from("tester", "wrongpassword", [10, 2, 0, 2]).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = login_from("tester", "testtesttest", [10, 2, 0, 2]).await;
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn repeated_failures_lock_the_address() {
+    use crate::lockout::LOCKOUT_POLICY;
+    use crate::routes::users::test::register_and_login;
+
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    for i in 0..LOCKOUT_POLICY.max_failures_per_ip {
+      let res = login_from(&format!("nobody{}", i), "wrongpassword", [10, 2, 1, 1]).await;
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let res = login_from("tester", "testtesttest", [10, 2, 1, 1]).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key("Retry-After"));
+    let res = login_from("tester", "testtesttest", [10, 2, 1, 2]).await;
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn lockout_does_not_reveal_accounts() {
+    use crate::lockout::LOCKOUT_POLICY;
+    use crate::routes::users::test::register_and_login;
+
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    let mut responses = Vec::new();
+    for (username, ip) in [("tester", [10, 2, 2, 1]), ("nobody", [10, 2, 2, 2])] {
+      for _ in 0..LOCKOUT_POLICY.max_failures {
+        let res = login_from(username, "wrongpassword", ip).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+      }
+
+      let res = login_from(username, "wrongpassword", ip).await;
+      let status = res.status();
+      let retry_after = res.headers().get("Retry-After").cloned();
+      let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+      responses.push((status, retry_after, error_code(&body)));
+    }
+
+    assert_eq!(responses[0].0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(responses[0], responses[1]);
+  }
+
+  #[tokio::test]
+  async fn username_and_email_share_the_account_lockout() {
+    use crate::lockout::LOCKOUT_POLICY;
+    use crate::routes::users::test::register_and_login;
+
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    for i in 0..LOCKOUT_POLICY.max_failures {
+      let login = if i % 2 == 0 { "tester" } else { "tester@example.com" };
+      let res = login_from(login, "wrongpassword", [10, 2, 3, 1]).await;
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let res = login_from("tester@example.com", "testtesttest", [10, 2, 3, 2]).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+  }
+
+  #[tokio::test]
+  async fn long_logins_lock_out() {
+    use crate::lockout::{ip_subject, LOCKOUT_POLICY};
+    use crate::models::find_login_throttle;
+    use crate::routes::DB;
+
+    before_user_test().await;
+    let login = format!("{}@example.com", "a".repeat(188));
+    assert_eq!(login.len(), 200);
+
+    for _ in 0..LOCKOUT_POLICY.max_failures {
+      let res = login_from(&login, "wrongpassword", [10, 2, 4, 1]).await;
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let res = login_from(&login, "wrongpassword", [10, 2, 4, 2]).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let db = DB.lock().await;
+    let throttle = find_login_throttle(&db, &ip_subject([10, 2, 4, 1].into())).unwrap().unwrap();
+    assert_eq!(throttle.failed_attempts, LOCKOUT_POLICY.max_failures);
+  }
+
+  /// Average time taken by `rounds` logins as `username`.
+  async fn time_logins(username: &str, rounds: u32) -> std::time::Duration {
+    let body = format!(r#"{{"username": "{}", "password": "wrongpassword"}}"#, username);
+    let start = std::time::Instant::now();
+    for _ in 0..rounds {
+      let res = handle_requests(build_test_request(Method::POST, "/login", &body, None))
+        .await
+        .unwrap();
+      let status = res.status();
+      let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+      assert_eq!(status, StatusCode::BAD_REQUEST);
+      assert_eq!(error_code(&body), ErrorBody::INVALID_CREDENTIALS);
+    }
+    start.elapsed() / rounds
+  }
+
+  #[tokio::test]
+  async fn login_timing_does_not_reveal_accounts() {
+    use crate::lockout::LOCKOUT_POLICY;
+    use crate::routes::users::test::register_and_login;
+
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    // Stay under the lockout, which would answer without checking the password
+    let rounds = (LOCKOUT_POLICY.max_failures - 1).max(1) as u32;
+    time_logins("nobody", 1).await;
+    let unknown = time_logins("nobody", rounds).await;
+    let known = time_logins("tester", rounds).await;
+
+    let ratio = unknown.as_secs_f64() / known.as_secs_f64();
+    assert!(
+      (0.5..2.0).contains(&ratio),
+      "Unknown accounts took {:?} per login, known ones {:?}",
+      unknown,
+      known
+    );
+  }
+
   #[tokio::test]
   async fn invalid_login() {
     before_user_test().await;
@@ -158,6 +525,7 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "tester".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
     };
 
     // User is never created
@@ -182,6 +550,7 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "tester".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
     };
     let req = build_test_request(
       Method::POST,
@@ -201,6 +570,7 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "tester".to_string(),
       password: "wrongpassword".to_string(),
+      email: None,
     };
 
     let req = build_test_request(
@@ -1,14 +1,35 @@
 use futures::FutureExt;
 use hyper::Method;
 
-use self::register::register_user;
+use self::api_keys::{list_api_keys, post_api_key, revoke_api_key};
+use self::deletion::{cancel_account_deletion, delete_account};
+use self::email::{confirm_email, resend_verification};
+use self::export::export_user;
+use self::passkeys::{
+  begin_passkey_deletion_cancel, begin_passkey_login, begin_passkey_reauthentication, begin_passkey_registration,
+  finish_passkey_login, finish_passkey_registration, list_passkeys, remove_password, revoke_passkey,
+};
+use self::password::{change_password, request_password_reset, reset_password};
+use self::refresh::refresh;
+use self::register::{register_user, username_available};
+use self::sessions::{list_sessions, logout, revoke_other_sessions, revoke_session};
+use self::two_factor::{begin_totp, confirm_totp, disable_totp, verify_login_challenge};
 use self::user::get_user_by_token;
 use crate::route_func;
 use crate::router::{Routable, RoutedFunction};
 use crate::routes::users::login::login;
 
+pub mod api_keys;
+pub mod deletion;
+pub mod email;
+pub mod export;
 pub mod login;
+pub mod passkeys;
+pub mod password;
+pub mod refresh;
 pub mod register;
+pub mod sessions;
+pub mod two_factor;
 pub mod user;
 
 pub struct UserRouter;
@@ -17,24 +38,133 @@ impl Routable for UserRouter {
   fn routes(&self) -> Vec<RoutedFunction> {
     vec![
       route_func!(Method::POST, "/register", register_user),
+      route_func!(Method::GET, "/usernames/{name}/available", username_available),
       route_func!(Method::POST, "/login", login),
+      route_func!(Method::POST, "/login/2fa", verify_login_challenge),
+      route_func!(Method::POST, "/login/passkey/begin", begin_passkey_login),
+      route_func!(Method::POST, "/login/passkey/finish", finish_passkey_login),
       route_func!(Method::GET, "/user", get_user_by_token),
+      route_func!(Method::DELETE, "/user", delete_account),
+      route_func!(Method::POST, "/user/deletion/cancel", cancel_account_deletion),
+      route_func!(Method::POST, "/user/deletion/cancel/passkey", begin_passkey_deletion_cancel),
+      route_func!(Method::GET, "/user/export", export_user),
+      route_func!(Method::POST, "/user/email/verification", resend_verification),
+      route_func!(Method::POST, "/user/email/verify", confirm_email),
+      route_func!(Method::POST, "/logout", logout),
+      route_func!(Method::POST, "/token/refresh", refresh),
+      route_func!(Method::GET, "/sessions", list_sessions),
+      route_func!(Method::DELETE, "/sessions", revoke_other_sessions),
+      route_func!(Method::DELETE, "/sessions/{id}", revoke_session),
+      route_func!(Method::POST, "/api_keys", post_api_key),
+      route_func!(Method::GET, "/api_keys", list_api_keys),
+      route_func!(Method::DELETE, "/api_keys/{id}", revoke_api_key),
+      route_func!(Method::POST, "/2fa/totp", begin_totp),
+      route_func!(Method::POST, "/2fa/totp/confirm", confirm_totp),
+      route_func!(Method::DELETE, "/2fa/totp", disable_totp),
+      route_func!(Method::POST, "/passkeys/register/begin", begin_passkey_registration),
+      route_func!(Method::POST, "/passkeys/register/finish", finish_passkey_registration),
+      route_func!(Method::GET, "/passkeys", list_passkeys),
+      route_func!(Method::DELETE, "/passkeys/{id}", revoke_passkey),
+      route_func!(Method::POST, "/passkeys/reauthenticate", begin_passkey_reauthentication),
+      route_func!(Method::POST, "/user/password", change_password),
+      route_func!(Method::DELETE, "/user/password", remove_password),
+      route_func!(Method::POST, "/password/reset", request_password_reset),
+      route_func!(Method::POST, "/password/reset/confirm", reset_password),
     ]
   }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
   use diesel::RunQueryDsl;
-  use crate::routes::DB;
+  use hyper::{Method, StatusCode};
+
+  use crate::mailer::sent_mail_to;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::email::VerifyEmailBody;
+  use crate::routes::users::login::LoginResponse;
+  use crate::routes::users::register::UserBody;
+  use crate::routes::{handle_requests, DB};
 
   pub async fn before_user_test() {
+    use crate::schema::api_keys::dsl::api_keys;
+    use crate::schema::login_throttles::dsl::login_throttles;
+    use crate::schema::oauth_clients::dsl::oauth_clients;
     use crate::schema::sessions::dsl::sessions;
     use crate::schema::users::dsl::users;
 
     dotenv::dotenv().ok();
     let conn = DB.lock().await;
     diesel::delete(sessions).execute(&*conn).unwrap();
+    diesel::delete(api_keys).execute(&*conn).unwrap();
+    diesel::delete(oauth_clients).execute(&*conn).unwrap();
     diesel::delete(users).execute(&*conn).unwrap();
+    diesel::delete(login_throttles).execute(&*conn).unwrap();
+  }
+
+  fn test_user_body(username: &str) -> String {
+    let value: UserBody = UserBody {
+      name: "Tester McTester".to_string(),
+      username: username.to_string(),
+      password: "testtesttest".to_string(),
+      email: Some(format!("{}@example.com", username)),
+    };
+    serde_json::to_string(&value).unwrap()
+  }
+
+  /// Logs in as a user created by [`register_and_login`] and returns the new
+  /// session token.
+  pub async fn login(username: &str) -> String {
+    let res = handle_requests(build_test_request(
+      Method::POST,
+      "/login",
+      &test_user_body(username),
+      None,
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let response_obj: LoginResponse = serde_json::from_slice(&body).unwrap();
+    response_obj.token
+  }
+
+  /// Registers `username` with a valid password and returns a fresh session
+  /// token for it.
+  pub async fn register_and_login(username: &str) -> String {
+    let res = handle_requests(build_test_request(
+      Method::POST,
+      "/register",
+      &test_user_body(username),
+      None,
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    login(username).await
+  }
+
+  /// Token from the most recent mail sent to `address`.
+  pub fn last_mailed_token(address: &str) -> String {
+    let mail = sent_mail_to(address).pop().expect("No mail was sent");
+    mail.body.split("\n\n").nth(1).unwrap().to_string()
+  }
+
+  /// Verifies the email of a user created by [`register_and_login`] with the
+  /// token mailed to it.
+  pub async fn verify_email(username: &str) {
+    let body = VerifyEmailBody {
+      token: last_mailed_token(&format!("{}@example.com", username)),
+    };
+    let res = handle_requests(build_test_request(
+      Method::POST,
+      "/user/email/verify",
+      &serde_json::to_string(&body).unwrap(),
+      None,
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
   }
 }
@@ -0,0 +1,993 @@
+use std::convert::Infallible;
+
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::lockout::{account_subject, ip_subject, LOCKOUT_POLICY};
+use crate::models::{
+  create_passkey, create_webauthn_challenge, delete_user_passkey, find_passkey, find_user_by_login, get_user_passkeys,
+  remove_user_password, take_webauthn_challenge, update_passkey_sign_count, Passkey, SessionMetadata, User,
+  WebAuthnChallenge, WEBAUTHN_AUTHENTICATION, WEBAUTHN_CANCEL_DELETION, WEBAUTHN_REAUTHENTICATION,
+  WEBAUTHN_REGISTRATION,
+};
+use crate::policy::Violation;
+use crate::router::PathParams;
+use crate::routes::error::{error_response, validation_response, ErrorBody};
+use crate::routes::users::login::{record_login_failure, start_session};
+use crate::routes::util::{client_ip, get_session_by_auth_header, too_many_requests, user_agent};
+use crate::routes::DB;
+use crate::webauthn::{b64, b64_decode, WebAuthnError, COSE_ALG_ES256, RELYING_PARTY};
+use crate::{respond, respond_error};
+
+const CEREMONY_TTL_SECS: i64 = 60 * 5;
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct PublicKeyCredentialDescriptor {
+  #[serde(rename = "type")]
+  pub kind: String,
+  pub id: String,
+}
+
+impl From<&Passkey> for PublicKeyCredentialDescriptor {
+  fn from(passkey: &Passkey) -> Self {
+    Self {
+      kind: "public-key".to_string(),
+      id: b64(&passkey.credential_id),
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct RelyingPartyEntity {
+  pub id: String,
+  pub name: String,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+  pub id: String,
+  pub name: String,
+  pub display_name: String,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct CredentialParameters {
+  #[serde(rename = "type")]
+  pub kind: String,
+  pub alg: i64,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+  pub resident_key: String,
+  pub user_verification: String,
+}
+
+/// `PublicKeyCredentialCreationOptions` with binary fields base64url encoded.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+  pub challenge: String,
+  pub rp: RelyingPartyEntity,
+  pub user: UserEntity,
+  pub pub_key_cred_params: Vec<CredentialParameters>,
+  pub timeout: i64,
+  pub attestation: String,
+  pub exclude_credentials: Vec<PublicKeyCredentialDescriptor>,
+  pub authenticator_selection: AuthenticatorSelection,
+}
+
+/// `PublicKeyCredentialRequestOptions` with binary fields base64url encoded.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+  pub challenge: String,
+  pub rp_id: String,
+  pub timeout: i64,
+  pub user_verification: String,
+  pub allow_credentials: Vec<PublicKeyCredentialDescriptor>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct CeremonyResponse<T> {
+  pub ceremony_id: String,
+  pub public_key: T,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct RegistrationBody {
+  pub ceremony_id: String,
+  pub name: String,
+  pub client_data_json: String,
+  pub attestation_object: String,
+}
+
+impl RegistrationBody {
+  pub const EMPTY_NAME_ERR: &'static str = "Passkey name must not be empty";
+  pub const LONG_NAME_ERR: &'static str = "Passkey name must be at most 100 characters long";
+
+  pub fn is_valid(&self) -> Result<(), Vec<Violation>> {
+    let mut errors = Vec::new();
+    if self.name.trim().is_empty() {
+      errors.push(Violation::new("name_empty", Self::EMPTY_NAME_ERR));
+    }
+
+    if self.name.chars().count() > 100 {
+      errors.push(Violation::new("name_too_long", Self::LONG_NAME_ERR));
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+}
+
+#[derive(Deserialize, Default)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct PasskeyLoginBeginBody {
+  /// Username or email address. Restricts the login to this account's
+  /// passkeys, though the authenticator is still left to pick one so the
+  /// answer does not reveal whether the account exists.
+  #[serde(default)]
+  pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct PasskeyLoginBody {
+  pub ceremony_id: String,
+  pub credential_id: String,
+  pub client_data_json: String,
+  pub authenticator_data: String,
+  pub signature: String,
+  #[serde(default)]
+  pub device: Option<String>,
+}
+
+/// Answer to a ceremony started at `/passkeys/reauthenticate`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct PasskeyAssertion {
+  pub ceremony_id: String,
+  pub credential_id: String,
+  pub client_data_json: String,
+  pub authenticator_data: String,
+  pub signature: String,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct RemovePasswordBody {
+  pub password: String,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct PasskeyResult {
+  pub id: String,
+  pub name: String,
+  pub created_at: DateTime<Utc>,
+  pub last_used: Option<DateTime<Utc>>,
+}
+
+impl From<Passkey> for PasskeyResult {
+  fn from(passkey: Passkey) -> Self {
+    Self {
+      id: passkey.id.to_string(),
+      name: passkey.name,
+      created_at: Utc.from_utc_datetime(&passkey.created_at),
+      last_used: passkey.last_used.map(|last_used| Utc.from_utc_datetime(&last_used)),
+    }
+  }
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<Response<Body>, Infallible> {
+  Ok(
+    Response::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/json")
+      .body(Body::from(serde_json::to_string(body).unwrap()))
+      .unwrap(),
+  )
+}
+
+fn passkeys_of(db: &diesel::PgConnection, user: &User) -> Result<Vec<Passkey>, Response<Body>> {
+  get_user_passkeys(db, user.id).map_err(|err| {
+    error!("{}", err.to_string());
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+  })
+}
+
+/// Checks the response of a passkey to `ceremony`, returning the new sign
+/// count to store.
+fn check_assertion(
+  ceremony: &WebAuthnChallenge,
+  passkey: &Passkey,
+  client_data_json: &str,
+  authenticator_data: &str,
+  signature: &str,
+) -> Result<u32, WebAuthnError> {
+  RELYING_PARTY.verify_assertion(
+    &ceremony.challenge,
+    &passkey.public_key,
+    passkey.sign_count as u32,
+    &b64_decode(client_data_json)?,
+    &b64_decode(authenticator_data)?,
+    &b64_decode(signature)?,
+  )
+}
+
+/// Starts registering a passkey for the signed in user.
+pub async fn begin_passkey_registration(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+  let existing = match passkeys_of(&db, &user) {
+    Ok(existing) => existing,
+    Err(res) => return Ok(res),
+  };
+
+  let ceremony = match create_webauthn_challenge(
+    &db,
+    Some(user.id),
+    WEBAUTHN_REGISTRATION,
+    chrono::Duration::seconds(CEREMONY_TTL_SECS),
+  ) {
+    Ok(ceremony) => ceremony,
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  };
+
+  json_response(&CeremonyResponse {
+    ceremony_id: ceremony.id.to_string(),
+    public_key: CreationOptions {
+      challenge: ceremony.challenge,
+      rp: RelyingPartyEntity {
+        id: RELYING_PARTY.id.clone(),
+        name: RELYING_PARTY.name.clone(),
+      },
+      user: UserEntity {
+        id: b64(user.id.as_bytes()),
+        name: user.username,
+        display_name: user.name,
+      },
+      pub_key_cred_params: vec![CredentialParameters {
+        kind: "public-key".to_string(),
+        alg: COSE_ALG_ES256,
+      }],
+      timeout: CEREMONY_TTL_SECS * 1000,
+      attestation: "none".to_string(),
+      exclude_credentials: existing.iter().map(PublicKeyCredentialDescriptor::from).collect(),
+      authenticator_selection: AuthenticatorSelection {
+        resident_key: "required".to_string(),
+        user_verification: "required".to_string(),
+      },
+    },
+  })
+}
+
+/// Stores the passkey created by the authenticator.
+pub async fn finish_passkey_registration(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let registration_body = serde_json::from_slice(&body);
+  if let Err(err) = registration_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let registration_body: RegistrationBody = registration_body.unwrap();
+  if let Err(errors) = registration_body.is_valid() {
+    return Ok(validation_response(errors));
+  }
+
+  let ceremony = Uuid::parse_str(&registration_body.ceremony_id)
+    .ok()
+    .and_then(|id| take_webauthn_challenge(&db, id, WEBAUTHN_REGISTRATION).ok());
+  let ceremony = match ceremony {
+    Some(ceremony) if ceremony.user_id == Some(user.id) => ceremony,
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "invalid_ceremony", "Invalid or expired ceremony"),
+  };
+
+  let credential = match (
+    b64_decode(&registration_body.client_data_json),
+    b64_decode(&registration_body.attestation_object),
+  ) {
+    (Ok(client_data_json), Ok(attestation_object)) => {
+      RELYING_PARTY.verify_registration(&ceremony.challenge, &client_data_json, &attestation_object)
+    },
+    (Err(err), _) | (_, Err(err)) => Err(err),
+  };
+  let credential = match credential {
+    Ok(credential) => credential,
+    Err(err) => return respond_error!(StatusCode::BAD_REQUEST, "passkey_verification_failed", err.to_string()),
+  };
+
+  if find_passkey(&db, &credential.credential_id).is_ok() {
+    return respond_error!(StatusCode::CONFLICT, "passkey_already_registered", "Passkey is already registered");
+  }
+
+  match create_passkey(&db, user.id, registration_body.name, credential) {
+    Ok(passkey) => json_response(&PasskeyResult::from(passkey)),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn list_passkeys(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match passkeys_of(&db, &user) {
+    Ok(passkeys) => {
+      let passkeys: Vec<PasskeyResult> = passkeys.into_iter().map(PasskeyResult::from).collect();
+      json_response(&passkeys)
+    },
+    Err(res) => Ok(res),
+  }
+}
+
+/// Deletes a passkey. A passwordless account cannot delete its last passkey.
+pub async fn revoke_passkey(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  let passkey_id = req
+    .extensions()
+    .get::<PathParams>()
+    .and_then(|params| params.get("id"))
+    .map(Uuid::parse_str);
+  let passkey_id = match passkey_id {
+    Some(Ok(passkey_id)) => passkey_id,
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "invalid_passkey_id", "Invalid passkey id"),
+  };
+
+  if user.password_digest.is_none() {
+    match passkeys_of(&db, &user) {
+      Ok(passkeys) if passkeys.len() == 1 && passkeys[0].id == passkey_id => {
+        return respond_error!(
+          StatusCode::CONFLICT,
+          "last_passkey",
+          "Cannot delete the last passkey of an account without a password"
+        )
+      },
+      Ok(_) => {},
+      Err(res) => return Ok(res),
+    }
+  }
+
+  match delete_user_passkey(&db, user.id, passkey_id) {
+    Ok(0) => respond_error!(StatusCode::NOT_FOUND, "passkey_not_found", "Passkey not found"),
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Removes the password from an account that has a passkey, so it can only
+/// be signed into with passkeys.
+pub async fn remove_password(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let password_body = serde_json::from_slice(&body);
+  if let Err(err) = password_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let password_body: RemovePasswordBody = password_body.unwrap();
+
+  if !user.verify_password(&password_body.password) {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_PASSWORD, "Invalid password");
+  }
+  match passkeys_of(&db, &user) {
+    Ok(passkeys) if passkeys.is_empty() => {
+      return respond_error!(StatusCode::CONFLICT, "passkey_required", "Register a passkey before removing the password")
+    },
+    Ok(_) => {},
+    Err(res) => return Ok(res),
+  }
+
+  match remove_user_password(&db, user.id) {
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Starts a passkey login.
+pub async fn begin_passkey_login(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  // Parse Body, an empty body starts a login without a username
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let begin_body = if body.is_empty() {
+    Ok(PasskeyLoginBeginBody::default())
+  } else {
+    serde_json::from_slice(&body)
+  };
+  if let Err(err) = begin_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let begin_body: PasskeyLoginBeginBody = begin_body.unwrap();
+
+  let db = DB.lock().await;
+  // Known accounts bind the ceremony to their passkeys, unknown ones fall back
+  // to a login without a username. Either way no credentials are listed, so
+  // the answer looks the same whether or not the account exists.
+  let user = begin_body
+    .username
+    .and_then(|username| find_user_by_login(&db, &username).ok());
+
+  match create_webauthn_challenge(
+    &db,
+    user.map(|user| user.id),
+    WEBAUTHN_AUTHENTICATION,
+    chrono::Duration::seconds(CEREMONY_TTL_SECS),
+  ) {
+    Ok(ceremony) => json_response(&CeremonyResponse {
+      ceremony_id: ceremony.id.to_string(),
+      public_key: RequestOptions {
+        challenge: ceremony.challenge,
+        rp_id: RELYING_PARTY.id.clone(),
+        timeout: CEREMONY_TTL_SECS * 1000,
+        user_verification: "required".to_string(),
+        allow_credentials: Vec::new(),
+      },
+    }),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Finishes a passkey login. Passkeys verify the user themselves, so no
+/// second factor is asked for. Failures count towards the same lockouts as
+/// `/login`.
+pub async fn finish_passkey_login(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+  use crate::schema::users;
+
+  let client_ip = client_ip(&req);
+  let client_user_agent = user_agent(&req);
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let login_body = serde_json::from_slice(&body);
+  if let Err(err) = login_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let login_body: PasskeyLoginBody = login_body.unwrap();
+  if matches!(&login_body.device, Some(label) if label.chars().count() > 100) {
+    return Ok(validation_response(vec![Violation::new(
+      "device_too_long",
+      "Device label must be at most 100 characters long",
+    )]));
+  }
+
+  let db = DB.lock().await;
+  let now = chrono::Utc::now().naive_utc();
+  let ip_subject = client_ip.map(ip_subject);
+  let ceremony = Uuid::parse_str(&login_body.ceremony_id)
+    .ok()
+    .and_then(|id| take_webauthn_challenge(&db, id, WEBAUTHN_AUTHENTICATION).ok());
+  let ceremony = match ceremony {
+    Some(ceremony) => ceremony,
+    None => return respond_error!(StatusCode::BAD_REQUEST, "invalid_ceremony", "Invalid or expired ceremony"),
+  };
+
+  let passkey = b64_decode(&login_body.credential_id)
+    .ok()
+    .and_then(|credential_id| find_passkey(&db, &credential_id).ok());
+  let passkey = match passkey {
+    Some(passkey) if ceremony.user_id.is_none_or(|uid| uid == passkey.user_id) => passkey,
+    _ => {
+      if let Err(err) = record_login_failure(&db, &[], ip_subject.as_deref(), now) {
+        error!("{}", err.to_string());
+      }
+      return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_CREDENTIALS, "Invalid login credentials");
+    },
+  };
+
+  let account = account_subject(passkey.user_id);
+  let subjects: Vec<String> = std::iter::once(account.clone()).chain(ip_subject.clone()).collect();
+  match LOCKOUT_POLICY.locked_for(&db, &subjects, now) {
+    Ok(Some(remaining)) => return Ok(too_many_requests(remaining.to_std().unwrap_or_default())),
+    Ok(None) => {},
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  }
+
+  let sign_count = check_assertion(
+    &ceremony,
+    &passkey,
+    &login_body.client_data_json,
+    &login_body.authenticator_data,
+    &login_body.signature,
+  );
+  let sign_count = match sign_count {
+    Ok(sign_count) => sign_count,
+    Err(err) => {
+      if let Err(err) = record_login_failure(&db, std::slice::from_ref(&account), ip_subject.as_deref(), now) {
+        error!("{}", err.to_string());
+      }
+      return respond_error!(StatusCode::BAD_REQUEST, "passkey_verification_failed", err.to_string());
+    },
+  };
+  if let Err(err) = LOCKOUT_POLICY.clear_failures(&db, &account) {
+    error!("{}", err.to_string());
+  }
+
+  if let Err(err) = update_passkey_sign_count(&db, passkey.id, sign_count) {
+    error!("{}", err.to_string());
+    return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+  }
+
+  let user: User = match users::table.filter(users::id.eq(passkey.user_id)).first(&*db) {
+    Ok(user) => user,
+    Err(_) => return respond_error!(
+      StatusCode::BAD_REQUEST,
+      ErrorBody::INVALID_CREDENTIALS,
+      "Invalid login credentials"
+    ),
+  };
+  let metadata = SessionMetadata {
+    ip: client_ip.map(|ip| ip.to_string()),
+    user_agent: client_user_agent,
+    device_label: login_body.device,
+    ..SessionMetadata::default()
+  };
+  start_session(&db, &user, metadata)
+}
+
+/// Starts a ceremony through which the signed in user confirms a sensitive
+/// action, such as deleting their account, with a passkey instead of a
+/// password.
+pub async fn begin_passkey_reauthentication(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+  let passkeys = match passkeys_of(&db, &user) {
+    Ok(passkeys) if passkeys.is_empty() => {
+      return respond_error!(StatusCode::BAD_REQUEST, "no_passkeys", "Account has no passkeys")
+    },
+    Ok(passkeys) => passkeys,
+    Err(res) => return Ok(res),
+  };
+
+  match create_webauthn_challenge(
+    &db,
+    Some(user.id),
+    WEBAUTHN_REAUTHENTICATION,
+    chrono::Duration::seconds(CEREMONY_TTL_SECS),
+  ) {
+    Ok(ceremony) => json_response(&CeremonyResponse {
+      ceremony_id: ceremony.id.to_string(),
+      public_key: RequestOptions {
+        challenge: ceremony.challenge,
+        rp_id: RELYING_PARTY.id.clone(),
+        timeout: CEREMONY_TTL_SECS * 1000,
+        user_verification: "required".to_string(),
+        allow_credentials: passkeys.iter().map(PublicKeyCredentialDescriptor::from).collect(),
+      },
+    }),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Takes the ceremony `assertion` answers and finds the passkey it claims to
+/// be from. Ceremonies started for an account, `user_id`, only accept that
+/// account's passkeys.
+pub fn find_assertion_passkey(
+  db: &diesel::PgConnection,
+  assertion: &PasskeyAssertion,
+  purpose: &str,
+  user_id: Option<Uuid>,
+) -> Result<(WebAuthnChallenge, Passkey), Response<Body>> {
+  let ceremony = Uuid::parse_str(&assertion.ceremony_id)
+    .ok()
+    .and_then(|id| take_webauthn_challenge(db, id, purpose).ok());
+  let ceremony = match ceremony {
+    Some(ceremony) if ceremony.user_id == user_id => ceremony,
+    _ => {
+      return Err(error_response(
+        StatusCode::BAD_REQUEST,
+        "invalid_ceremony",
+        "Invalid or expired ceremony",
+      ))
+    },
+  };
+
+  let passkey = b64_decode(&assertion.credential_id)
+    .ok()
+    .and_then(|credential_id| find_passkey(db, &credential_id).ok());
+  match passkey {
+    Some(passkey) if user_id.is_none_or(|uid| uid == passkey.user_id) => Ok((ceremony, passkey)),
+    _ => Err(error_response(
+      StatusCode::BAD_REQUEST,
+      ErrorBody::INVALID_CREDENTIALS,
+      "Invalid passkey",
+    )),
+  }
+}
+
+/// Checks the signature of `assertion` and records the use of `passkey`.
+pub fn check_passkey_assertion(
+  db: &diesel::PgConnection,
+  ceremony: &WebAuthnChallenge,
+  passkey: &Passkey,
+  assertion: &PasskeyAssertion,
+) -> Result<(), Response<Body>> {
+  let sign_count = check_assertion(
+    ceremony,
+    passkey,
+    &assertion.client_data_json,
+    &assertion.authenticator_data,
+    &assertion.signature,
+  )
+  .map_err(|err| error_response(StatusCode::BAD_REQUEST, "passkey_verification_failed", err.to_string()))?;
+  update_passkey_sign_count(db, passkey.id, sign_count).map_err(|err| {
+    error!("{}", err.to_string());
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+  })
+}
+
+/// Checks that `assertion` answers a ceremony `user` started at
+/// `/passkeys/reauthenticate`, with one of their own passkeys.
+pub fn verify_reauthentication(
+  db: &diesel::PgConnection,
+  user: &User,
+  assertion: &PasskeyAssertion,
+) -> Result<(), Response<Body>> {
+  let (ceremony, passkey) = find_assertion_passkey(db, assertion, WEBAUTHN_REAUTHENTICATION, Some(user.id))?;
+  check_passkey_assertion(db, &ceremony, &passkey, assertion)
+}
+
+/// Starts a ceremony to cancel a scheduled deletion with a passkey. Every
+/// session was revoked when the deletion was scheduled, so the ceremony is
+/// not tied to an account and the authenticator picks the passkey.
+pub async fn begin_passkey_deletion_cancel(_req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  match create_webauthn_challenge(
+    &db,
+    None,
+    WEBAUTHN_CANCEL_DELETION,
+    chrono::Duration::seconds(CEREMONY_TTL_SECS),
+  ) {
+    Ok(ceremony) => json_response(&CeremonyResponse {
+      ceremony_id: ceremony.id.to_string(),
+      public_key: RequestOptions {
+        challenge: ceremony.challenge,
+        rp_id: RELYING_PARTY.id.clone(),
+        timeout: CEREMONY_TTL_SECS * 1000,
+        user_verification: "required".to_string(),
+        allow_credentials: Vec::new(),
+      },
+    }),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::{
+    CeremonyResponse, CreationOptions, PasskeyAssertion, PasskeyLoginBeginBody, PasskeyLoginBody, PasskeyResult,
+    RegistrationBody, RemovePasswordBody, RequestOptions,
+  };
+  use crate::lockout::LOCKOUT_POLICY;
+  use crate::routes::test::request;
+  use crate::routes::users::deletion::{CancelDeletionBody, DeleteAccountBody};
+  use crate::routes::users::login::LoginResponse;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+  use crate::webauthn::b64;
+  use crate::webauthn::soft_authenticator::SoftAuthenticator;
+  use crate::webauthn::RELYING_PARTY;
+
+  async fn register_passkey(token: &str, authenticator: &mut SoftAuthenticator) -> PasskeyResult {
+    let (status, body) = request(Method::POST, "/passkeys/register/begin", String::new(), Some(token)).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let options: CeremonyResponse<CreationOptions> = serde_json::from_str(&body).unwrap();
+    assert_eq!(options.public_key.rp.id, RELYING_PARTY.id);
+
+    let response = authenticator.register(&options.public_key.challenge);
+    let body = RegistrationBody {
+      ceremony_id: options.ceremony_id,
+      name: "Laptop".to_string(),
+      client_data_json: b64(&response.client_data_json),
+      attestation_object: b64(&response.attestation_object),
+    };
+    let (status, body) = request(
+      Method::POST,
+      "/passkeys/register/finish",
+      serde_json::to_string(&body).unwrap(),
+      Some(token),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  async fn begin_login(username: Option<&str>) -> CeremonyResponse<RequestOptions> {
+    let body = PasskeyLoginBeginBody {
+      username: username.map(str::to_string),
+    };
+    let (status, body) = request(
+      Method::POST,
+      "/login/passkey/begin",
+      serde_json::to_string(&body).unwrap(),
+      None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  async fn finish_login(
+    ceremony: &CeremonyResponse<RequestOptions>,
+    authenticator: &mut SoftAuthenticator,
+  ) -> (StatusCode, String) {
+    let response = authenticator.assert(&ceremony.public_key.challenge);
+    let body = PasskeyLoginBody {
+      ceremony_id: ceremony.ceremony_id.clone(),
+      credential_id: b64(&authenticator.credential_id),
+      client_data_json: b64(&response.client_data_json),
+      authenticator_data: b64(&response.authenticator_data),
+      signature: b64(&response.signature),
+      device: None,
+    };
+    request(
+      Method::POST,
+      "/login/passkey/finish",
+      serde_json::to_string(&body).unwrap(),
+      None,
+    )
+    .await
+  }
+
+  async fn remove_password(token: &str, password: &str) -> StatusCode {
+    let body = RemovePasswordBody {
+      password: password.to_string(),
+    };
+    request(
+      Method::DELETE,
+      "/user/password",
+      serde_json::to_string(&body).unwrap(),
+      Some(token),
+    )
+    .await
+    .0
+  }
+
+  #[tokio::test]
+  async fn passkey_login_flow() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let mut authenticator = SoftAuthenticator::new(&RELYING_PARTY);
+    let passkey = register_passkey(&token, &mut authenticator).await;
+    assert_eq!(passkey.name, "Laptop");
+
+    // Without a username the authenticator picks the passkey
+    let ceremony = begin_login(None).await;
+    assert!(ceremony.public_key.allow_credentials.is_empty());
+    let (status, body) = finish_login(&ceremony, &mut authenticator).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let login: LoginResponse = serde_json::from_str(&body).unwrap();
+    let (status, _) = request(Method::GET, "/user", String::new(), Some(&login.token)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Naming an account does not reveal its passkeys, or whether it exists
+    let ceremony = begin_login(Some("Tester")).await;
+    assert!(ceremony.public_key.allow_credentials.is_empty());
+    assert!(begin_login(Some("nobody")).await.public_key.allow_credentials.is_empty());
+    let (status, body) = finish_login(&ceremony, &mut authenticator).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+
+    // Ceremonies are single use
+    let (status, _) = finish_login(&ceremony, &mut authenticator).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = request(Method::GET, "/passkeys", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let passkeys: Vec<PasskeyResult> = serde_json::from_str(&body).unwrap();
+    assert_eq!(passkeys.len(), 1);
+    assert!(passkeys[0].last_used.is_some());
+  }
+
+  #[tokio::test]
+  async fn cloned_authenticator_is_rejected() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let mut authenticator = SoftAuthenticator::new(&RELYING_PARTY);
+    register_passkey(&token, &mut authenticator).await;
+
+    let (status, _) = finish_login(&begin_login(None).await, &mut authenticator).await;
+    assert_eq!(status, StatusCode::OK);
+
+    authenticator.sign_count = 0;
+    let (status, body) = finish_login(&begin_login(None).await, &mut authenticator).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("sign count"));
+  }
+
+  #[tokio::test]
+  async fn failed_assertions_lock_the_account() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let mut authenticator = SoftAuthenticator::new(&RELYING_PARTY);
+    register_passkey(&token, &mut authenticator).await;
+
+    for _ in 0..LOCKOUT_POLICY.max_failures {
+      let mut ceremony = begin_login(None).await;
+      ceremony.public_key.challenge = b64(b"not the challenge");
+      let (status, _) = finish_login(&ceremony, &mut authenticator).await;
+      assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    let (status, body) = finish_login(&begin_login(None).await, &mut authenticator).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "Test failed: {}", body);
+  }
+
+  #[tokio::test]
+  async fn passkey_must_belong_to_named_user() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    register_and_login("other").await;
+    let mut authenticator = SoftAuthenticator::new(&RELYING_PARTY);
+    register_passkey(&token, &mut authenticator).await;
+
+    let (status, _) = finish_login(&begin_login(Some("other")).await, &mut authenticator).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn passwordless_account() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    assert_eq!(remove_password(&token, "testtesttest").await, StatusCode::CONFLICT);
+
+    let mut authenticator = SoftAuthenticator::new(&RELYING_PARTY);
+    let passkey = register_passkey(&token, &mut authenticator).await;
+    assert_eq!(
+      remove_password(&token, "wrongwrongwrong").await,
+      StatusCode::BAD_REQUEST
+    );
+    assert_eq!(remove_password(&token, "testtesttest").await, StatusCode::OK);
+
+    let body = r#"{"username": "tester", "password": "testtesttest"}"#.to_string();
+    let (status, _) = request(Method::POST, "/login", body, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let path = format!("/passkeys/{}", passkey.id);
+    let (status, _) = request(Method::DELETE, &path, String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, body) = finish_login(&begin_login(Some("tester")).await, &mut authenticator).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+  }
+
+  fn assert_ceremony(
+    ceremony: &CeremonyResponse<RequestOptions>,
+    authenticator: &mut SoftAuthenticator,
+  ) -> PasskeyAssertion {
+    let response = authenticator.assert(&ceremony.public_key.challenge);
+    PasskeyAssertion {
+      ceremony_id: ceremony.ceremony_id.clone(),
+      credential_id: b64(&authenticator.credential_id),
+      client_data_json: b64(&response.client_data_json),
+      authenticator_data: b64(&response.authenticator_data),
+      signature: b64(&response.signature),
+    }
+  }
+
+  #[tokio::test]
+  async fn passwordless_account_deletes_itself_with_a_passkey() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let (status, _) = request(Method::POST, "/passkeys/reauthenticate", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let mut authenticator = SoftAuthenticator::new(&RELYING_PARTY);
+    register_passkey(&token, &mut authenticator).await;
+    assert_eq!(remove_password(&token, "testtesttest").await, StatusCode::OK);
+
+    let delete = |passkey: Option<PasskeyAssertion>| {
+      let body = DeleteAccountBody { password: None, passkey };
+      request(Method::DELETE, "/user", serde_json::to_string(&body).unwrap(), Some(&token))
+    };
+    let (status, _) = delete(None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Login ceremonies cannot stand in for a reauthentication
+    let assertion = assert_ceremony(&begin_login(Some("tester")).await, &mut authenticator);
+    let (status, _) = delete(Some(assertion)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = request(Method::POST, "/passkeys/reauthenticate", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let ceremony: CeremonyResponse<RequestOptions> = serde_json::from_str(&body).unwrap();
+    assert_eq!(ceremony.public_key.allow_credentials.len(), 1);
+    let (status, body) = delete(Some(assert_ceremony(&ceremony, &mut authenticator))).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "Request failed: {}", body);
+
+    // Every session is gone, so cancelling starts without one
+    let (status, body) = request(Method::POST, "/user/deletion/cancel/passkey", String::new(), None).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let ceremony: CeremonyResponse<RequestOptions> = serde_json::from_str(&body).unwrap();
+    let body = CancelDeletionBody {
+      username: None,
+      password: None,
+      passkey: Some(assert_ceremony(&ceremony, &mut authenticator)),
+    };
+    let (status, body) = request(
+      Method::POST,
+      "/user/deletion/cancel",
+      serde_json::to_string(&body).unwrap(),
+      None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+
+    let (status, body) = finish_login(&begin_login(Some("tester")).await, &mut authenticator).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+  }
+}
@@ -0,0 +1,356 @@
+use std::convert::Infallible;
+use std::time::Duration as StdDuration;
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+#[cfg(test)]
+use serde::Serialize;
+use tracing::error;
+
+use crate::mailer::{send_in_background, Mail};
+use crate::models::{
+  create_password_reset, delete_other_sessions, delete_user_password_resets, delete_user_sessions,
+  find_password_reset_user, find_user_by_login, take_password_reset, update_user_password, User,
+};
+use crate::policy::PASSWORD_POLICY;
+use crate::rate_limit::RateLimiter;
+use crate::routes::error::{validation_response, ErrorBody};
+use crate::routes::users::sessions::RevokeResponse;
+use crate::routes::util::{client_ip, get_session_by_auth_header, too_many_requests};
+use crate::routes::DB;
+use crate::{respond, respond_error};
+
+const RESET_TTL_SECS: i64 = 60 * 60;
+
+lazy_static::lazy_static! {
+  static ref RESET_REQUESTS_PER_IP: RateLimiter = RateLimiter::new(5, StdDuration::from_secs(15 * 60));
+  /// Resets mailed to one account per `RESET_TTL_SECS`, further requests are
+  /// silently dropped so they do not reveal the account exists.
+  static ref RESETS_PER_ACCOUNT: RateLimiter = RateLimiter::new(3, StdDuration::from_secs(RESET_TTL_SECS as u64));
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct ChangePasswordBody {
+  pub current_password: String,
+  pub new_password: String,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct ResetRequestBody {
+  /// Username or email address.
+  pub username: String,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct ResetPasswordBody {
+  pub token: String,
+  pub new_password: String,
+}
+
+/// Changes the password of the signed in user and logs out every other
+/// session. Signed access tokens already handed out stay valid until they
+/// expire, but can no longer be refreshed.
+pub async fn change_password(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, current) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let password_body = serde_json::from_slice(&body);
+  if let Err(err) = password_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let password_body: ChangePasswordBody = password_body.unwrap();
+
+  if !user.verify_password(&password_body.current_password) {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_PASSWORD, "Invalid password");
+  }
+  let errors = PASSWORD_POLICY.check(&password_body.new_password, &[&user.username, &user.name]);
+  if !errors.is_empty() {
+    return Ok(validation_response(errors));
+  }
+
+  let result = update_user_password(&db, user.id, &password_body.new_password)
+    .and_then(|_| delete_other_sessions(&db, user.id, current.id));
+  match result {
+    Ok(revoked) => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&RevokeResponse { revoked }).unwrap()))
+        .unwrap(),
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Mails a reset token to the account's email address, only once that
+/// address is verified. Always answers `202 Accepted` so it cannot be used to
+/// find out which accounts exist.
+pub async fn request_password_reset(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let ip_key = client_ip(&req).map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+  if let Err(retry_after) = RESET_REQUESTS_PER_IP.check(&ip_key) {
+    return Ok(too_many_requests(retry_after));
+  }
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let reset_body = serde_json::from_slice(&body);
+  if let Err(err) = reset_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let reset_body: ResetRequestBody = reset_body.unwrap();
+
+  let mail = {
+    let db = DB.lock().await;
+    let user = match find_user_by_login(&db, &reset_body.username) {
+      Ok(user) => user,
+      Err(_) => return respond!(StatusCode::ACCEPTED, ""),
+    };
+    let email = match &user.email {
+      Some(email) if user.is_email_verified() => email.clone(),
+      _ => return respond!(StatusCode::ACCEPTED, ""),
+    };
+
+    if RESETS_PER_ACCOUNT.check(&user.id.to_string()).is_err() {
+      return respond!(StatusCode::ACCEPTED, "");
+    }
+
+    match create_password_reset(&db, user.id, chrono::Duration::seconds(RESET_TTL_SECS)) {
+      Ok(token) => reset_mail(&user, email, &token),
+      Err(err) => {
+        error!("{}", err.to_string());
+        return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+      },
+    }
+  };
+
+  send_in_background(mail);
+  respond!(StatusCode::ACCEPTED, "")
+}
+
+fn reset_mail(user: &User, to: String, token: &str) -> Mail {
+  Mail {
+    to,
+    subject: "Reset your password".to_string(),
+    body: format!(
+      "A password reset was requested for {}. Use this token within an hour to choose a new password:\n\n{}\n\nIf you did not ask for this, you can ignore this email.",
+      user.username, token
+    ),
+  }
+}
+
+/// Sets a new password with a mailed reset token. Every session is logged out
+/// and any other outstanding reset tokens are discarded.
+pub async fn reset_password(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let reset_body = serde_json::from_slice(&body);
+  if let Err(err) = reset_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let reset_body: ResetPasswordBody = reset_body.unwrap();
+
+  // Check the new password first so a rejected one does not burn the token
+  let db = DB.lock().await;
+  let user = match find_password_reset_user(&db, &reset_body.token) {
+    Ok(user) => user,
+    Err(_) => return respond_error!(StatusCode::BAD_REQUEST, "invalid_reset_token", "Invalid or expired reset token"),
+  };
+  let errors = PASSWORD_POLICY.check(&reset_body.new_password, &[&user.username, &user.name]);
+  if !errors.is_empty() {
+    return Ok(validation_response(errors));
+  }
+
+  let reset = match take_password_reset(&db, &reset_body.token) {
+    Ok(reset) => reset,
+    Err(_) => return respond_error!(StatusCode::BAD_REQUEST, "invalid_reset_token", "Invalid or expired reset token"),
+  };
+
+  let result = update_user_password(&db, reset.user_id, &reset_body.new_password)
+    .and_then(|_| delete_user_sessions(&db, reset.user_id))
+    .and_then(|_| delete_user_password_resets(&db, reset.user_id));
+  match result {
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::net::SocketAddr;
+
+  use hyper::{Method, StatusCode};
+
+  use super::{ChangePasswordBody, ResetPasswordBody, ResetRequestBody};
+  use crate::mailer::{sent_mail_to, wait_for_mail_to};
+  use crate::routes::handle_requests;
+  use crate::routes::test::{build_test_request, request};
+  use crate::policy::PasswordPolicy;
+  use crate::routes::users::sessions::RevokeResponse;
+  use crate::routes::users::test::{before_user_test, last_mailed_token, login, register_and_login, verify_email};
+  use crate::routes::util::ClientAddr;
+
+  async fn change_password(token: &str, current_password: &str, new_password: &str) -> (StatusCode, String) {
+    let body = ChangePasswordBody {
+      current_password: current_password.to_string(),
+      new_password: new_password.to_string(),
+    };
+    request(
+      Method::POST,
+      "/user/password",
+      serde_json::to_string(&body).unwrap(),
+      Some(token),
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn change_password_revokes_other_sessions() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let other = login("tester").await;
+
+    let (status, body) = change_password(&token, "testtesttest", "anotherpassword").await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    assert_eq!(serde_json::from_str::<RevokeResponse>(&body).unwrap().revoked, 1);
+
+    let (status, _) = request(Method::GET, "/user", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(Method::GET, "/user", String::new(), Some(&other)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let old = r#"{"username": "tester", "password": "testtesttest"}"#.to_string();
+    let (status, _) = request(Method::POST, "/login", old, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let new = r#"{"username": "tester", "password": "anotherpassword"}"#.to_string();
+    let (status, _) = request(Method::POST, "/login", new, None).await;
+    assert_eq!(status, StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn change_password_checks_passwords() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let (status, body) = change_password(&token, "wrongwrongwrong", "anotherpassword").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Invalid password"));
+
+    let (status, body) = change_password(&token, "testtesttest", "short").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(PasswordPolicy::TOO_SHORT));
+
+    let (status, body) = change_password(&token, "testtesttest", "password1234").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(PasswordPolicy::BREACHED));
+
+    let (status, body) = change_password(&token, "testtesttest", "testertestertester").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(PasswordPolicy::PERSONAL_INFO));
+  }
+
+  /// Requests a reset from `ip`, each test uses its own address so the per IP
+  /// limit does not carry over between tests.
+  async fn request_reset(username: &str, ip: [u8; 4]) -> StatusCode {
+    let body = serde_json::to_string(&ResetRequestBody {
+      username: username.to_string(),
+    })
+    .unwrap();
+    let mut req = build_test_request(Method::POST, "/password/reset", &body, None);
+    req.extensions_mut().insert(ClientAddr(SocketAddr::from((ip, 40000))));
+    handle_requests(req).await.unwrap().status()
+  }
+
+  async fn reset_password(token: &str, new_password: &str) -> (StatusCode, String) {
+    let body = ResetPasswordBody {
+      token: token.to_string(),
+      new_password: new_password.to_string(),
+    };
+    request(
+      Method::POST,
+      "/password/reset/confirm",
+      serde_json::to_string(&body).unwrap(),
+      None,
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn reset_password_flow() {
+    before_user_test().await;
+    let token = register_and_login("resetflow").await;
+    verify_email("resetflow").await;
+
+    let sent_before = sent_mail_to("resetflow@example.com").len();
+    assert_eq!(request_reset("resetflow", [10, 1, 0, 1]).await, StatusCode::ACCEPTED);
+    wait_for_mail_to("resetflow@example.com", sent_before + 1).await;
+    let reset_token = last_mailed_token("resetflow@example.com");
+
+    let (status, body) = reset_password(&reset_token, "short").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains(PasswordPolicy::TOO_SHORT));
+
+    let (status, body) = reset_password(&reset_token, "anotherpassword").await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+
+    // Every session is logged out and the token is spent
+    let (status, _) = request(Method::GET, "/user", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = reset_password(&reset_token, "yetanotherpassword").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let new = r#"{"username": "resetflow", "password": "anotherpassword"}"#.to_string();
+    let (status, _) = request(Method::POST, "/login", new, None).await;
+    assert_eq!(status, StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn reset_requires_verified_email() {
+    before_user_test().await;
+    register_and_login("unverified").await;
+    let sent_before = sent_mail_to("unverified@example.com").len();
+
+    // Neither unknown accounts nor unverified addresses are revealed
+    assert_eq!(request_reset("nobody", [10, 1, 0, 2]).await, StatusCode::ACCEPTED);
+    assert_eq!(request_reset("unverified", [10, 1, 0, 2]).await, StatusCode::ACCEPTED);
+    assert_eq!(sent_mail_to("unverified@example.com").len(), sent_before);
+  }
+
+  #[tokio::test]
+  async fn reset_requests_are_rate_limited() {
+    before_user_test().await;
+    register_and_login("resetlimit").await;
+    verify_email("resetlimit").await;
+
+    // Mail stops after a few resets per account, without telling the client
+    let sent_before = sent_mail_to("resetlimit@example.com").len();
+    for _ in 0..4 {
+      assert_eq!(request_reset("resetlimit", [10, 1, 0, 3]).await, StatusCode::ACCEPTED);
+    }
+    let sent = wait_for_mail_to("resetlimit@example.com", sent_before + 3).await;
+    assert_eq!(sent.len() - sent_before, 3);
+
+    assert_eq!(request_reset("resetlimit", [10, 1, 0, 3]).await, StatusCode::ACCEPTED);
+    let mut req = build_test_request(Method::POST, "/password/reset", r#"{"username": "resetlimit"}"#, None);
+    req
+      .extensions_mut()
+      .insert(ClientAddr(SocketAddr::from(([10, 1, 0, 3], 40000))));
+    let res = handle_requests(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key("Retry-After"));
+  }
+}
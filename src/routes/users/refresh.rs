@@ -0,0 +1,278 @@
+use std::convert::Infallible;
+use std::env;
+
+use diesel::result::Error;
+use diesel::{PgConnection, QueryDsl, RunQueryDsl};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::jwt::{TokenSigner, ACCESS_TOKENS};
+use crate::models::{
+  create_refresh_token, delete_session, find_refresh_token, get_session, update_session_last_used, use_refresh_token,
+  Session, User,
+};
+use crate::respond_error;
+use crate::routes::DB;
+use crate::routes::error::ErrorBody;
+use crate::session::SESSION_POLICY;
+
+const DEFAULT_REFRESH_TOKEN_TTL_SECS: i64 = 60 * 60 * 24 * 30;
+
+lazy_static::lazy_static! {
+  static ref REFRESH_TOKEN_TTL: chrono::Duration = chrono::Duration::seconds(
+    env::var("REFRESH_TOKEN_TTL")
+      .ok()
+      .map(|secs| secs.parse().expect("Failed to parse REFRESH_TOKEN_TTL as seconds"))
+      .unwrap_or(DEFAULT_REFRESH_TOKEN_TTL_SECS)
+  );
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct RefreshBody {
+  pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct TokenResponse {
+  pub access_token: String,
+  pub token_type: String,
+  pub expires_in: i64,
+  pub refresh_token: String,
+}
+
+/// Signs a new access token for `session` and mints the next refresh token in
+/// its family.
+pub fn issue_token_pair(
+  db: &PgConnection,
+  signer: &TokenSigner,
+  user: &User,
+  session: &Session,
+) -> Result<TokenResponse, Error> {
+  let (refresh_token, _) = create_refresh_token(db, session, *REFRESH_TOKEN_TTL)?;
+  let (access_token, expires_in) = signer.issue_access_token(user, session);
+
+  Ok(TokenResponse {
+    access_token,
+    token_type: "Bearer".to_string(),
+    expires_in,
+    refresh_token: refresh_token.to_string(),
+  })
+}
+
+pub async fn refresh(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  use crate::schema::users;
+
+  let signer = match ACCESS_TOKENS.as_ref() {
+    Some(signer) => signer,
+    None => return respond_error!(
+      StatusCode::NOT_FOUND,
+      ErrorBody::NOT_ENABLED,
+      "Signed access tokens are not enabled"
+    ),
+  };
+
+  // Parse Refresh Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let refresh_body = serde_json::from_slice(&body);
+  if let Err(err) = refresh_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let refresh_body: RefreshBody = refresh_body.unwrap();
+
+  let db = DB.lock().await;
+  let refresh_token = match find_refresh_token(&db, &refresh_body.refresh_token) {
+    Ok(refresh_token) => refresh_token,
+    Err(Error::NotFound) => return respond_error!(
+      StatusCode::UNAUTHORIZED,
+      "invalid_refresh_token",
+      "Invalid refresh token"
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  };
+
+  // A spent token being presented again means it leaked, so the whole family
+  // is revoked along with the session it belongs to.
+  match use_refresh_token(&db, refresh_token.id) {
+    Ok(true) => {},
+    Ok(false) => {
+      warn!(
+        "Refresh token reuse detected, revoking session {}",
+        refresh_token.session_id
+      );
+      if let Err(err) = delete_session(&db, refresh_token.session_id) {
+        error!("Failed to revoke session {}", err.to_string());
+      }
+      return respond_error!(StatusCode::UNAUTHORIZED, "refresh_token_reused", "Refresh token reuse detected");
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  }
+
+  let now = chrono::Utc::now().naive_utc();
+  if refresh_token.expires_at < now {
+    return respond_error!(StatusCode::UNAUTHORIZED, "refresh_token_expired", "Refresh token expired");
+  }
+
+  let session = match get_session(&db, refresh_token.session_id) {
+    Ok(session) => session,
+    Err(_) => return respond_error!(StatusCode::UNAUTHORIZED, "invalid_refresh_token", "Invalid refresh token"),
+  };
+  if SESSION_POLICY.is_expired(&session, now) {
+    if let Err(err) = delete_session(&db, session.id) {
+      warn!("Failed to delete expired session {}", err.to_string());
+    }
+    return respond_error!(StatusCode::UNAUTHORIZED, "session_expired", "Session expired");
+  }
+  if let Err(err) = update_session_last_used(&db, session.id) {
+    warn!("Failed to update session last used token {}", err.to_string());
+  }
+
+  let user: User = match users::table.find(session.user_id).first(&*db) {
+    Ok(user) => user,
+    Err(_) => return respond_error!(StatusCode::UNAUTHORIZED, "invalid_refresh_token", "Invalid refresh token"),
+  };
+
+  match issue_token_pair(&db, signer, &user, &session) {
+    Ok(tokens) => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(serde_json::to_string(&tokens).unwrap()))
+        .unwrap(),
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+  use hyper::{Method, StatusCode};
+
+  use super::{RefreshBody, TokenResponse};
+  use crate::routes::error::test::error_code;
+  use crate::routes::handle_requests;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::login::LoginResponse;
+  use crate::routes::users::test::before_user_test;
+  use crate::routes::DB;
+  use crate::schema::refresh_tokens;
+
+  async fn login_with_refresh_token() -> LoginResponse {
+    let body = r#"{"name": "Tester McTester", "username": "tester", "password": "testtesttest"}"#;
+    handle_requests(build_test_request(Method::POST, "/register", body, None))
+      .await
+      .unwrap();
+    let res = handle_requests(build_test_request(Method::POST, "/login", body, None))
+      .await
+      .unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+  }
+
+  async fn refresh(refresh_token: &str) -> (StatusCode, String) {
+    let body = serde_json::to_string(&RefreshBody {
+      refresh_token: refresh_token.to_string(),
+    })
+    .unwrap();
+    let res = handle_requests(build_test_request(Method::POST, "/token/refresh", &body, None))
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    (status, body)
+  }
+
+  #[tokio::test]
+  async fn refresh_rotates_token() {
+    before_user_test().await;
+    let login = login_with_refresh_token().await;
+    let first = login.refresh_token.unwrap();
+
+    let (status, body) = refresh(&first).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let tokens: TokenResponse = serde_json::from_str(&body).unwrap();
+    assert_ne!(tokens.refresh_token, first);
+    assert_eq!(tokens.token_type, "Bearer");
+
+    let (status, body) = refresh(&tokens.refresh_token).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+  }
+
+  #[tokio::test]
+  async fn reused_refresh_token_revokes_family() {
+    before_user_test().await;
+    let login = login_with_refresh_token().await;
+    let first = login.refresh_token.unwrap();
+
+    let (_, body) = refresh(&first).await;
+    let tokens: TokenResponse = serde_json::from_str(&body).unwrap();
+
+    // Replaying the spent token kills the whole family
+    let (status, body) = refresh(&first).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), "refresh_token_reused");
+
+    let (status, _) = refresh(&tokens.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let res = handle_requests(build_test_request(Method::GET, "/user", "", Some(login.token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn expired_refresh_token_is_rejected() {
+    before_user_test().await;
+    let login = login_with_refresh_token().await;
+
+    {
+      let db = DB.lock().await;
+      diesel::update(refresh_tokens::table)
+        .set(refresh_tokens::expires_at.eq(chrono::Utc::now().naive_utc() - chrono::Duration::minutes(1)))
+        .execute(&*db)
+        .unwrap();
+    }
+
+    let (status, body) = refresh(&login.refresh_token.unwrap()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), "refresh_token_expired");
+  }
+
+  #[tokio::test]
+  async fn logout_revokes_refresh_tokens() {
+    before_user_test().await;
+    let login = login_with_refresh_token().await;
+
+    let res = handle_requests(build_test_request(Method::POST, "/logout", "", Some(login.token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let (status, _) = refresh(&login.refresh_token.unwrap()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let db = DB.lock().await;
+    let remaining: i64 = refresh_tokens::table.count().get_result(&*db).unwrap();
+    assert_eq!(remaining, 0);
+  }
+
+  #[tokio::test]
+  async fn unknown_refresh_token() {
+    before_user_test().await;
+    let (status, _) = refresh("8b8f4f2e-0000-4000-8000-000000000000").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+  }
+}
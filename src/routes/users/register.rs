@@ -1,14 +1,21 @@
 use std::convert::Infallible;
+use std::env;
 
+use diesel::result::Error;
+use diesel::PgConnection;
 use hyper::{Body, Request, Response, StatusCode};
-use serde::Deserialize;
-#[cfg(test)]
-use serde::Serialize;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
 use tracing::error;
 
-use crate::models::create_user;
-use crate::respond;
+use crate::mailer::MAILER;
+use crate::models::{create_user, find_similar_usernames, is_email_in_use};
+use crate::policy::{Violation, NAME_POLICY, PASSWORD_POLICY};
+use crate::router::PathParams;
+use crate::routes::error::{validation_response, ErrorBody};
+use crate::routes::users::email::verification_mail;
 use crate::routes::DB;
+use crate::{respond, respond_error};
 
 #[derive(Deserialize, Clone)]
 #[cfg_attr(test, derive(Serialize))]
@@ -16,45 +23,30 @@ pub struct UserBody {
   pub name: String,
   pub username: String,
   pub password: String,
+  /// Where password reset links are sent once verified. Mandatory when
+  /// `EMAIL_REQUIRED` is set.
+  #[serde(default)]
+  pub email: Option<String>,
 }
 
 impl UserBody {
-  pub const SHORT_USERNAME_ERR: &'static str = "Username must be at least 3 characters long";
-  pub const SHORT_PASSWORD_ERR: &'static str = "Password must be at least 12 characters long";
-  pub const LONG_USERNAME_ERR: &'static str = "Username must be at most 100 characters long";
-  pub const SHORT_COMPANY_ERR: &'static str = "Company Name must be at least 3 characters long";
-  pub const LONG_COMPANY_ERR: &'static str = "Company Name must be at most 100 characters long";
-  pub const USERNAME_NONALPHABETIC_ERR: &'static str = "Username must be alphabetic";
-
-  pub fn is_valid(&self) -> Result<(), Vec<String>> {
-    let mut errors = Vec::new();
-    if self.username.len() < 3 {
-      errors.push(Self::SHORT_USERNAME_ERR.to_string());
-    }
-
-    if self.username.len() > 100 {
-      errors.push(Self::LONG_USERNAME_ERR.to_string());
-    }
-
-    if self.name.len() < 3 {
-      errors.push(Self::SHORT_COMPANY_ERR.to_string());
-    }
-
-    if self.name.len() > 100 {
-      errors.push(Self::LONG_COMPANY_ERR.to_string());
+  pub const INVALID_EMAIL_ERR: &'static str = "Email must be a valid address of at most 254 characters";
+  pub const MISSING_EMAIL_ERR: &'static str = "Email is required";
+  pub const USERNAME_TAKEN_ERR: &'static str = "Username is already taken";
+  pub const USERNAME_CONFUSABLE_ERR: &'static str = "Username looks too much like an existing one";
+  pub const EMAIL_TAKEN_ERR: &'static str = "Email is already in use";
+
+  pub fn is_valid(&self) -> Result<(), Vec<Violation>> {
+    let mut errors = NAME_POLICY.check_username(&self.username);
+    errors.extend(NAME_POLICY.check_company_name(&self.name));
+    errors.extend(PASSWORD_POLICY.check(&self.password, &[&self.username, &self.name]));
+
+    match &self.email {
+      Some(email) if !is_valid_email(email) => errors.push(Violation::new("email_invalid", Self::INVALID_EMAIL_ERR)),
+      None if email_required() => errors.push(Violation::new("email_missing", Self::MISSING_EMAIL_ERR)),
+      _ => {},
     }
 
-    let alphabetic_check = self.username.chars().all(|c| c.is_ascii_alphanumeric());
-    if !alphabetic_check {
-      errors.push(Self::USERNAME_NONALPHABETIC_ERR.to_string());
-    }
-
-    if self.password.len() < 12 {
-      errors.push(Self::SHORT_PASSWORD_ERR.to_string());
-    }
-
-    // TODO Check if username is taken
-
     if errors.len() > 0 {
       Err(errors)
     } else {
@@ -63,41 +55,175 @@ impl UserBody {
   }
 }
 
+/// Why existing accounts keep `username` from being registered. Besides the
+/// name itself in any case, names that look like it are refused so nobody can
+/// pose as another player.
+fn username_conflict(conn: &PgConnection, username: &str) -> Result<Option<Violation>, Error> {
+  let similar = find_similar_usernames(conn, username)?;
+  if similar.iter().any(|existing| existing.to_lowercase() == username.to_lowercase()) {
+    Ok(Some(Violation::new("username_taken", UserBody::USERNAME_TAKEN_ERR)))
+  } else if !similar.is_empty() {
+    Ok(Some(Violation::new("username_confusable", UserBody::USERNAME_CONFUSABLE_ERR)))
+  } else {
+    Ok(None)
+  }
+}
+
+fn email_required() -> bool {
+  matches!(env::var("EMAIL_REQUIRED").as_deref(), Ok("true") | Ok("1"))
+}
+
+/// Loose shape check, whether the address exists is only known once mail
+/// reaches it.
+fn is_valid_email(email: &str) -> bool {
+  match email.split_once('@') {
+    Some((local, domain)) => {
+      email.len() <= 254
+        && !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+    },
+    None => false,
+  }
+}
+
 pub async fn register_user(req: Request<Body>) -> Result<Response<Body>, Infallible> {
   // Parse Body
   let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
   let user_body = serde_json::from_slice(&body);
   if let Err(err) = user_body {
-    return respond!(StatusCode::BAD_REQUEST, err.to_string());
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
   }
   let user_body: UserBody = user_body.unwrap();
 
   // Determine if user is valid
   let db = DB.lock().await;
   if let Err(errors) = user_body.is_valid() {
-    return respond!(StatusCode::BAD_REQUEST, serde_json::to_string(&errors).unwrap());
+    return Ok(validation_response(errors));
   }
 
-  // TODO Check if user already exists.
+  // Usernames may not look like existing ones, emails are unique regardless of case
+  match username_conflict(&db, &user_body.username) {
+    Ok(Some(violation)) => return conflict(violation),
+    Ok(None) => {},
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  }
+  let email = user_body.email.map(|email| email.trim().to_string());
+  if let Some(email) = &email {
+    match is_email_in_use(&db, email) {
+      Ok(true) => return conflict(Violation::new("email_taken", UserBody::EMAIL_TAKEN_ERR)),
+      Ok(false) => {},
+      Err(err) => {
+        error!("{}", err.to_string());
+        return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+      },
+    }
+  }
 
   // Create new user
-  let user = create_user(&db, user_body.name, user_body.username, user_body.password, 0);
+  let user = create_user(&db, user_body.name, user_body.username, email, user_body.password, 0);
   if let Err(err) = user {
     error!("{}", err.to_string());
-    return respond!(StatusCode::INTERNAL_SERVER_ERROR, "");
+    return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+  }
+  let user = user.unwrap();
+
+  // Ask the user to confirm their address
+  let mail = match verification_mail(&db, &user) {
+    Ok(mail) => mail,
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  };
+  drop(db);
+  if let Some(mail) = mail {
+    if let Err(err) = MAILER.send(&mail).await {
+      error!("{}", err.to_string());
+    }
   }
 
   respond!(StatusCode::OK, "")
 }
 
+/// Answers with the rule's own code, and repeats it in `details` like
+/// validation errors so clients can show it next to the field it concerns.
+fn conflict(violation: Violation) -> Result<Response<Body>, Infallible> {
+  Ok(
+    ErrorBody {
+      details: vec![violation.clone()],
+      ..ErrorBody::new(violation.code, violation.message)
+    }
+    .into_response(StatusCode::CONFLICT),
+  )
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct UsernameAvailability {
+  pub available: bool,
+  /// Code of the first rule the username breaks when it is not available.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub reason: Option<String>,
+}
+
+/// Lets registration forms check a username before submitting. Invalid,
+/// reserved, taken and confusable names are all unavailable. The name is
+/// percent-decoded, as clients encode it to put it in the path.
+pub async fn username_available(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let username = req
+    .extensions()
+    .get::<PathParams>()
+    .and_then(|params| params.get("name"))
+    .and_then(|username| percent_decode_str(username).decode_utf8().ok());
+  let username = match username {
+    Some(username) => username.to_string(),
+    None => return respond_error!(StatusCode::BAD_REQUEST, "invalid_username", "Invalid username"),
+  };
+
+  let db = DB.lock().await;
+  let violation = match NAME_POLICY.check_username(&username).into_iter().next() {
+    Some(violation) => Ok(Some(violation)),
+    None => username_conflict(&db, &username),
+  };
+  match violation {
+    Ok(violation) => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+          serde_json::to_string(&UsernameAvailability {
+            available: violation.is_none(),
+            reason: violation.map(|violation| violation.code.to_string()),
+          })
+          .unwrap(),
+        ))
+        .unwrap(),
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
 #[cfg(test)]
 mod test {
   use hyper::{Method, StatusCode};
 
-  use super::UserBody;
+  use super::{UserBody, UsernameAvailability};
+  use crate::policy::{NamePolicy, PasswordPolicy};
+  use crate::routes::error::test::error_code;
+  use crate::routes::error::ErrorBody;
   use crate::routes::handle_requests;
   use crate::routes::test::build_test_request;
-  use crate::routes::users::test::{before_user_test};
+  use crate::routes::users::test::{before_user_test, login, register_and_login};
 
   async fn assert_bad_registration(value: UserBody, contained_errors: Vec<String>) {
     before_user_test().await;
@@ -118,6 +244,7 @@ mod test {
       body
     );
 
+    assert_eq!(error_code(&body), ErrorBody::VALIDATION_FAILED);
     contained_errors.iter().for_each(|err| {
       assert!(body.contains(err), "Failed, error list did not contain \"{}\", only: {}", err, body);
     });
@@ -129,9 +256,34 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "tester".to_string(),
       password: "testtest".to_string(),
+      email: None,
     };
 
-    assert_bad_registration(value, vec![UserBody::SHORT_PASSWORD_ERR.to_string()]).await;
+    assert_bad_registration(value, vec![PasswordPolicy::TOO_SHORT.to_string()]).await;
+  }
+
+  #[tokio::test]
+  async fn invalid_user_password_contains_username() {
+    let value: UserBody = UserBody {
+      name: "Tester McTester".to_string(),
+      username: "tester".to_string(),
+      password: "iamthetester1".to_string(),
+      email: None,
+    };
+
+    assert_bad_registration(value, vec![PasswordPolicy::PERSONAL_INFO.to_string()]).await;
+  }
+
+  #[tokio::test]
+  async fn invalid_user_common_password() {
+    let value: UserBody = UserBody {
+      name: "Tester McTester".to_string(),
+      username: "tester".to_string(),
+      password: "qwertyuiop123".to_string(),
+      email: None,
+    };
+
+    assert_bad_registration(value, vec![PasswordPolicy::BREACHED.to_string()]).await;
   }
 
   #[tokio::test]
@@ -140,9 +292,10 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "aa".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
     };
 
-    assert_bad_registration(value, vec![UserBody::SHORT_USERNAME_ERR.to_string()]).await;
+    assert_bad_registration(value, vec![NamePolicy::USERNAME_TOO_SHORT.to_string()]).await;
   }
 
   #[tokio::test]
@@ -151,9 +304,10 @@ mod test {
       name: "Tester McTester".to_string(),
       username: String::from_utf8(vec![b'a'; 1000]).unwrap(),
       password: "testtesttest".to_string(),
+      email: None,
     };
 
-    assert_bad_registration(value, vec![UserBody::LONG_USERNAME_ERR.to_string()]).await;
+    assert_bad_registration(value, vec![NamePolicy::USERNAME_TOO_LONG.to_string()]).await;
   }
 
   #[tokio::test]
@@ -162,9 +316,10 @@ mod test {
       name: String::from_utf8(vec![b'a'; 1000]).unwrap(),
       username: "testtest".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
     };
 
-    assert_bad_registration(value, vec![UserBody::LONG_COMPANY_ERR.to_string()]).await;
+    assert_bad_registration(value, vec![NamePolicy::COMPANY_TOO_LONG.to_string()]).await;
   }
 
   #[tokio::test]
@@ -173,9 +328,40 @@ mod test {
       name: "a".to_string(),
       username: "testtest".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
+    };
+
+    assert_bad_registration(value, vec![NamePolicy::COMPANY_TOO_SHORT.to_string()]).await;
+  }
+
+  #[tokio::test]
+  async fn company_length_counts_characters() {
+    let value: UserBody = UserBody {
+      name: "Ünïcödé Ltd".repeat(9),
+      username: "acmeltd".to_string(),
+      password: "testtesttest".to_string(),
+      email: None,
+    };
+    assert!(value.name.len() > 100);
+    assert!(value.is_valid().is_ok());
+
+    let value = UserBody {
+      name: "Äö".to_string(),
+      ..value
+    };
+    assert_bad_registration(value, vec![NamePolicy::COMPANY_TOO_SHORT.to_string()]).await;
+  }
+
+  #[tokio::test]
+  async fn invalid_user_reserved_username() {
+    let value: UserBody = UserBody {
+      name: "Tester McTester".to_string(),
+      username: "Adm1n".to_string(),
+      password: "testtesttest".to_string(),
+      email: None,
     };
 
-    assert_bad_registration(value, vec![UserBody::SHORT_COMPANY_ERR.to_string()]).await;
+    assert_bad_registration(value, vec![NamePolicy::USERNAME_RESERVED.to_string()]).await;
   }
 
   #[tokio::test]
@@ -184,9 +370,92 @@ mod test {
       name: "Tester McTester".to_string(),
       username: "$$$$".to_string(),
       password: "testtesttest".to_string(),
+      email: None,
     };
 
-    assert_bad_registration(value, vec![UserBody::USERNAME_NONALPHABETIC_ERR.to_string()]).await;
+    assert_bad_registration(value, vec![NamePolicy::USERNAME_INVALID_CHARACTERS.to_string()]).await;
+  }
+
+  #[tokio::test]
+  async fn duplicate_username_conflicts() {
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    let value: UserBody = UserBody {
+      name: "Tester McTester".to_string(),
+      username: "Tester".to_string(),
+      password: "testtesttest".to_string(),
+      email: None,
+    };
+    let req = build_test_request(
+      Method::POST,
+      "/register",
+      serde_json::to_string(&value).unwrap().as_str(),
+      None,
+    );
+    let res = handle_requests(req).await.unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    assert_eq!(status, StatusCode::CONFLICT, "Test failed: {}", body);
+    assert_eq!(error_code(&body), "username_taken");
+    assert!(body.contains(r#""details":[{"code":"username_taken""#), "Test failed: {}", body);
+
+    // Logins ignore case too
+    login("TESTER").await;
+  }
+
+  #[tokio::test]
+  async fn confusable_username_conflicts() {
+    before_user_test().await;
+    register_and_login("modern").await;
+
+    let value: UserBody = UserBody {
+      name: "Tester McTester".to_string(),
+      username: "rnodern".to_string(),
+      password: "testtesttest".to_string(),
+      email: None,
+    };
+    let req = build_test_request(
+      Method::POST,
+      "/register",
+      serde_json::to_string(&value).unwrap().as_str(),
+      None,
+    );
+    let res = handle_requests(req).await.unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    assert_eq!(status, StatusCode::CONFLICT, "Test failed: {}", body);
+    assert_eq!(error_code(&body), "username_confusable");
+  }
+
+  async fn is_available(username: &str) -> bool {
+    let path = format!("/usernames/{}/available", username);
+    let res = handle_requests(build_test_request(Method::GET, &path, "", None))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    serde_json::from_slice::<UsernameAvailability>(&body).unwrap().available
+  }
+
+  #[tokio::test]
+  async fn username_availability() {
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    assert!(!is_available("tester").await);
+    assert!(!is_available("TeStEr").await);
+    assert!(!is_available("te5ter").await);
+    assert!(!is_available("root").await);
+    assert!(!is_available("no$").await);
+    assert!(is_available("testertwo").await);
+    assert!(is_available("%74estertwo").await);
+    assert!(!is_available("%54ester").await);
+
+    let res = handle_requests(build_test_request(Method::GET, "/usernames/%FF/available", "", None))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
   }
 
   #[tokio::test]
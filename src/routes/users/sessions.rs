@@ -0,0 +1,308 @@
+use std::convert::Infallible;
+
+use chrono::{DateTime, TimeZone, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+#[cfg(test)]
+use serde::Deserialize;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::models::{delete_other_sessions, delete_session, delete_user_session, get_user_sessions, Session};
+use crate::router::PathParams;
+use crate::routes::error::ErrorBody;
+use crate::routes::util::get_session_by_auth_header;
+use crate::routes::DB;
+use crate::session::SESSION_POLICY;
+use crate::{respond, respond_error};
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct SessionResult {
+  pub id: String,
+  pub created_at: DateTime<Utc>,
+  pub last_used: DateTime<Utc>,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub device_label: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub scopes: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub oauth_client_id: Option<String>,
+  pub current: bool,
+}
+
+impl SessionResult {
+  pub fn new(session: &Session, current: &Session) -> Self {
+    Self {
+      id: session.id.to_string(),
+      created_at: Utc.from_utc_datetime(&session.created_at),
+      last_used: Utc.from_utc_datetime(&session.last_used),
+      ip: session.ip.clone(),
+      user_agent: session.user_agent.clone(),
+      device_label: session.device_label.clone(),
+      scopes: session.scopes.clone(),
+      oauth_client_id: session.oauth_client_id.map(|client_id| client_id.to_string()),
+      current: session.id == current.id,
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct RevokeResponse {
+  pub revoked: usize,
+}
+
+pub async fn logout(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (_, session) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match delete_session(&db, session.id) {
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn list_sessions(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, current) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match get_user_sessions(&db, user.id) {
+    Ok(sessions) => {
+      let now = chrono::Utc::now().naive_utc();
+      let sessions: Vec<SessionResult> = sessions
+        .iter()
+        .filter(|session| !SESSION_POLICY.is_expired(session, now))
+        .map(|session| SessionResult::new(session, &current))
+        .collect();
+
+      Ok(
+        Response::builder()
+          .status(StatusCode::OK)
+          .header("Content-Type", "application/json")
+          .body(Body::from(serde_json::to_string(&sessions).unwrap()))
+          .unwrap(),
+      )
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn revoke_session(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  let session_id = req
+    .extensions()
+    .get::<PathParams>()
+    .and_then(|params| params.get("id"))
+    .map(Uuid::parse_str);
+  let session_id = match session_id {
+    Some(Ok(session_id)) => session_id,
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "invalid_session_id", "Invalid session id"),
+  };
+
+  match delete_user_session(&db, user.id, session_id) {
+    Ok(0) => respond_error!(StatusCode::NOT_FOUND, "session_not_found", "Session not found"),
+    Ok(_) => respond!(StatusCode::OK, ""),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn revoke_other_sessions(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, current) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  match delete_other_sessions(&db, user.id, current.id) {
+    Ok(revoked) => Ok(
+      Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&RevokeResponse { revoked }).unwrap()))
+        .unwrap(),
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::net::SocketAddr;
+
+  use hyper::{Body, Method, Request, StatusCode};
+
+  use super::{RevokeResponse, SessionResult};
+  use crate::routes::handle_requests;
+  use crate::routes::test::build_test_request;
+  use crate::routes::util::ClientAddr;
+  use crate::routes::users::test::{before_user_test, login, register_and_login};
+
+  async fn list(token: &str) -> Vec<SessionResult> {
+    let res = handle_requests(build_test_request(Method::GET, "/sessions", "", Some(token.to_string())))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+  }
+
+  async fn status_of(method: Method, path: &str, token: &str) -> StatusCode {
+    handle_requests(build_test_request(method, path, "", Some(token.to_string())))
+      .await
+      .unwrap()
+      .status()
+  }
+
+  #[tokio::test]
+  async fn logout_ends_session() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    assert_eq!(status_of(Method::POST, "/logout", &token).await, StatusCode::OK);
+    assert_eq!(status_of(Method::GET, "/user", &token).await, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn lists_sessions_with_current_marked() {
+    before_user_test().await;
+    let first = register_and_login("tester").await;
+    let second = login("tester").await;
+
+    let sessions = list(&second).await;
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.iter().filter(|session| session.current).count(), 1);
+    assert!(sessions.iter().all(|session| session.id != first && session.id != second));
+  }
+
+  #[tokio::test]
+  async fn revoke_single_session() {
+    before_user_test().await;
+    let first = register_and_login("tester").await;
+    let second = login("tester").await;
+
+    let other = list(&second).await.into_iter().find(|session| !session.current).unwrap();
+    let path = format!("/sessions/{}", other.id);
+    assert_eq!(status_of(Method::DELETE, &path, &second).await, StatusCode::OK);
+    assert_eq!(status_of(Method::DELETE, &path, &second).await, StatusCode::NOT_FOUND);
+
+    assert_eq!(status_of(Method::GET, "/user", &first).await, StatusCode::BAD_REQUEST);
+    assert_eq!(status_of(Method::GET, "/user", &second).await, StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn cannot_revoke_another_users_session() {
+    before_user_test().await;
+    let victim = register_and_login("tester").await;
+    let attacker = register_and_login("attacker").await;
+
+    let victim_session = list(&victim).await.pop().unwrap();
+    let path = format!("/sessions/{}", victim_session.id);
+    assert_eq!(status_of(Method::DELETE, &path, &attacker).await, StatusCode::NOT_FOUND);
+    assert_eq!(status_of(Method::GET, "/user", &victim).await, StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn revoke_all_other_sessions() {
+    before_user_test().await;
+    let first = register_and_login("tester").await;
+    let second = login("tester").await;
+    let current = login("tester").await;
+
+    let res = handle_requests(build_test_request(Method::DELETE, "/sessions", "", Some(current.clone())))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let response_obj: RevokeResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response_obj.revoked, 2);
+
+    assert_eq!(status_of(Method::GET, "/user", &first).await, StatusCode::BAD_REQUEST);
+    assert_eq!(status_of(Method::GET, "/user", &second).await, StatusCode::BAD_REQUEST);
+    assert_eq!(list(&current).await.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn sessions_record_device_metadata() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let mut req = Request::builder()
+      .method(Method::POST)
+      .uri("/login")
+      .header("User-Agent", "fizzbuzz-bot/1.0")
+      .body(Body::from(
+        r#"{"username": "tester", "password": "testtesttest", "device": "Build Server"}"#,
+      ))
+      .unwrap();
+    req
+      .extensions_mut()
+      .insert(ClientAddr(SocketAddr::from(([10, 0, 0, 7], 40000))));
+    let res = handle_requests(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let sessions = list(&token).await;
+    let labelled = sessions.iter().find(|session| !session.current).unwrap();
+    assert_eq!(labelled.device_label.as_deref(), Some("Build Server"));
+    assert_eq!(labelled.user_agent.as_deref(), Some("fizzbuzz-bot/1.0"));
+    assert_eq!(labelled.ip.as_deref(), Some("10.0.0.7"));
+
+    let unlabelled = sessions.iter().find(|session| session.current).unwrap();
+    assert_eq!(unlabelled.device_label, None);
+  }
+
+  #[tokio::test]
+  async fn long_device_label_is_rejected() {
+    before_user_test().await;
+    register_and_login("tester").await;
+
+    let body = serde_json::json!({
+      "username": "tester",
+      "password": "testtesttest",
+      "device": "a".repeat(101),
+    });
+    let res = handle_requests(build_test_request(Method::POST, "/login", &body.to_string(), None))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn malformed_session_id() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    assert_eq!(
+      status_of(Method::DELETE, "/sessions/1234", &token).await,
+      StatusCode::BAD_REQUEST
+    );
+  }
+}
@@ -0,0 +1,490 @@
+use std::convert::Infallible;
+
+use diesel::PgConnection;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::lockout::{ip_subject, second_factor_subject, LOCKOUT_POLICY};
+use crate::models::{
+  confirm_totp_credential, create_login_challenge, create_recovery_codes, create_totp_credential,
+  delete_login_challenge, delete_two_factor, find_login_challenge, get_totp_credential, record_login_challenge_failure,
+  use_recovery_code, use_totp_step, SessionMetadata, User,
+};
+use crate::routes::error::ErrorBody;
+use crate::routes::users::login::{record_login_failure, start_session};
+use crate::routes::util::{client_ip, get_session_by_auth_header, too_many_requests, user_agent};
+use crate::routes::DB;
+use crate::totp::{generate_totp_secret, otpauth_uri, verify_totp};
+use crate::{respond, respond_error};
+
+const LOGIN_CHALLENGE_TTL_SECS: i64 = 60 * 5;
+const LOGIN_CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+
+/// Returned by `/login` instead of a `LoginResponse` when the account has a
+/// second factor. The client answers it at `/login/2fa`.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct LoginChallengeResponse {
+  pub challenge: String,
+  pub methods: Vec<String>,
+  pub expires_in: i64,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct TotpEnrollment {
+  pub secret: String,
+  pub otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct TotpCodeBody {
+  pub code: String,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+pub struct RecoveryCodesResponse {
+  pub recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct DisableTwoFactorBody {
+  pub password: String,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+pub struct LoginChallengeBody {
+  pub challenge: String,
+  #[serde(default)]
+  pub code: Option<String>,
+  #[serde(default)]
+  pub recovery_code: Option<String>,
+}
+
+fn unix_now() -> u64 {
+  chrono::Utc::now().timestamp() as u64
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<Response<Body>, Infallible> {
+  Ok(
+    Response::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/json")
+      .header("Cache-Control", "no-store")
+      .body(Body::from(serde_json::to_string(body).unwrap()))
+      .unwrap(),
+  )
+}
+
+/// Second step of a password login for accounts with two factor
+/// authentication enabled.
+pub fn login_challenge(
+  db: &PgConnection,
+  user: &User,
+  device_label: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+  match create_login_challenge(
+    db,
+    user.id,
+    device_label,
+    chrono::Duration::seconds(LOGIN_CHALLENGE_TTL_SECS),
+  ) {
+    Ok(challenge) => Ok(
+      Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(
+          serde_json::to_string(&LoginChallengeResponse {
+            challenge,
+            methods: vec!["totp".to_string(), "recovery_code".to_string()],
+            expires_in: LOGIN_CHALLENGE_TTL_SECS,
+          })
+          .unwrap(),
+        ))
+        .unwrap(),
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Starts TOTP enrollment. The secret is not used for logins until it is
+/// confirmed with a code.
+pub async fn begin_totp(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  if matches!(get_totp_credential(&db, user.id), Ok(credential) if credential.is_confirmed()) {
+    return respond_error!(StatusCode::CONFLICT, "two_factor_enabled", "Two factor authentication is already enabled");
+  }
+
+  match create_totp_credential(&db, user.id, generate_totp_secret()) {
+    Ok(credential) => json_response(&TotpEnrollment {
+      otpauth_uri: otpauth_uri(&credential.secret, &user.username),
+      secret: credential.secret,
+    }),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Finishes enrollment with a code from the authenticator and hands out the
+/// recovery codes.
+pub async fn confirm_totp(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let code_body = serde_json::from_slice(&body);
+  if let Err(err) = code_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let code_body: TotpCodeBody = code_body.unwrap();
+
+  let credential = match get_totp_credential(&db, user.id) {
+    Ok(credential) if credential.is_confirmed() => {
+      return respond_error!(StatusCode::CONFLICT, "two_factor_enabled", "Two factor authentication is already enabled")
+    },
+    Ok(credential) => credential,
+    Err(_) => return respond_error!(
+      StatusCode::BAD_REQUEST,
+      "no_two_factor_enrollment",
+      "No two factor enrollment in progress"
+    ),
+  };
+
+  let step = match verify_totp(&credential.secret, code_body.code.trim(), unix_now()) {
+    Some(step) => step,
+    None => return respond_error!(StatusCode::BAD_REQUEST, "invalid_code", "Invalid code"),
+  };
+
+  let result = use_totp_step(&db, user.id, step)
+    .and_then(|_| confirm_totp_credential(&db, user.id))
+    .and_then(|_| create_recovery_codes(&db, user.id));
+  match result {
+    Ok(recovery_codes) => json_response(&RecoveryCodesResponse { recovery_codes }),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+pub async fn disable_totp(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let db = DB.lock().await;
+
+  let (user, _) = match get_session_by_auth_header(&db, &req) {
+    Ok(result) => result,
+    Err(res) => return Ok(res),
+  };
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let disable_body = serde_json::from_slice(&body);
+  if let Err(err) = disable_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let disable_body: DisableTwoFactorBody = disable_body.unwrap();
+
+  if !user.verify_password(&disable_body.password) {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::INVALID_PASSWORD, "Invalid password");
+  }
+
+  match delete_two_factor(&db, user.id) {
+    Ok(true) => respond!(StatusCode::OK, ""),
+    Ok(false) => respond_error!(
+      StatusCode::NOT_FOUND,
+      "two_factor_not_enabled",
+      "Two factor authentication is not enabled"
+    ),
+    Err(err) => {
+      error!("{}", err.to_string());
+      respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error")
+    },
+  }
+}
+
+/// Answers a login challenge with a TOTP code or a recovery code. Wrong codes
+/// count towards lockouts of the client address and of the account's second
+/// factor, which outlives the challenge so fetching new ones does not allow
+/// more guesses.
+pub async fn verify_login_challenge(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+  use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+  use crate::schema::users;
+
+  let client_ip = client_ip(&req);
+  let client_user_agent = user_agent(&req);
+
+  // Parse Body
+  let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+  let challenge_body = serde_json::from_slice(&body);
+  if let Err(err) = challenge_body {
+    return respond_error!(StatusCode::BAD_REQUEST, ErrorBody::MALFORMED_BODY, err.to_string());
+  }
+  let challenge_body: LoginChallengeBody = challenge_body.unwrap();
+
+  let db = DB.lock().await;
+  let now = chrono::Utc::now().naive_utc();
+  let challenge = match find_login_challenge(&db, &challenge_body.challenge) {
+    Ok(challenge) if challenge.expires_at > now => challenge,
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "invalid_challenge", "Invalid or expired challenge"),
+  };
+
+  let subject = second_factor_subject(challenge.user_id);
+  let ip_subject = client_ip.map(ip_subject);
+  let subjects: Vec<String> = std::iter::once(subject.clone()).chain(ip_subject.clone()).collect();
+  match LOCKOUT_POLICY.locked_for(&db, &subjects, now) {
+    Ok(Some(remaining)) => return Ok(too_many_requests(remaining.to_std().unwrap_or_default())),
+    Ok(None) => {},
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  }
+
+  let passed = match (&challenge_body.code, &challenge_body.recovery_code) {
+    (Some(code), None) => match get_totp_credential(&db, challenge.user_id) {
+      Ok(credential) => match verify_totp(&credential.secret, code.trim(), unix_now()) {
+        Some(step) => use_totp_step(&db, challenge.user_id, step),
+        None => Ok(false),
+      },
+      Err(_) => Ok(false),
+    },
+    (None, Some(recovery_code)) => use_recovery_code(&db, challenge.user_id, recovery_code),
+    _ => return respond_error!(StatusCode::BAD_REQUEST, "code_required", "Provide either a code or a recovery code"),
+  };
+
+  match passed {
+    Ok(true) => {
+      if let Err(err) = LOCKOUT_POLICY.clear_failures(&db, &subject) {
+        error!("{}", err.to_string());
+      }
+    },
+    Ok(false) => {
+      if let Err(err) = record_login_failure(&db, std::slice::from_ref(&subject), ip_subject.as_deref(), now) {
+        error!("{}", err.to_string());
+      }
+      match record_login_challenge_failure(&db, &challenge.token_hash) {
+        Ok(attempts) if attempts >= LOGIN_CHALLENGE_MAX_ATTEMPTS => {
+          if let Err(err) = delete_login_challenge(&db, &challenge.token_hash) {
+            error!("{}", err.to_string());
+          }
+        },
+        Ok(_) => {},
+        Err(err) => error!("{}", err.to_string()),
+      }
+      return respond_error!(StatusCode::BAD_REQUEST, "invalid_code", "Invalid code");
+    },
+    Err(err) => {
+      error!("{}", err.to_string());
+      return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+    },
+  }
+
+  if let Err(err) = delete_login_challenge(&db, &challenge.token_hash) {
+    error!("{}", err.to_string());
+    return respond_error!(StatusCode::INTERNAL_SERVER_ERROR, ErrorBody::INTERNAL, "Internal server error");
+  }
+
+  let user: User = match users::table.filter(users::id.eq(challenge.user_id)).first(&*db) {
+    Ok(user) => user,
+    Err(_) => return respond_error!(StatusCode::BAD_REQUEST, "invalid_challenge", "Invalid or expired challenge"),
+  };
+  let metadata = SessionMetadata {
+    ip: client_ip.map(|ip| ip.to_string()),
+    user_agent: client_user_agent,
+    device_label: challenge.device_label,
+    ..SessionMetadata::default()
+  };
+  start_session(&db, &user, metadata)
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::{
+    DisableTwoFactorBody, LoginChallengeBody, LoginChallengeResponse, RecoveryCodesResponse, TotpCodeBody,
+    TotpEnrollment,
+  };
+  use crate::lockout::LOCKOUT_POLICY;
+  use crate::routes::test::request;
+  use crate::routes::users::login::LoginResponse;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+  use crate::totp::totp_code;
+
+  fn now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+  }
+
+  /// Enrolls `token`'s account, returning the secret and recovery codes. The
+  /// confirmation code is taken from the previous step so a login in the same
+  /// step is not treated as a replay.
+  async fn enroll(token: &str) -> (String, Vec<String>) {
+    let (status, body) = request(Method::POST, "/2fa/totp", String::new(), Some(token)).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let enrollment: TotpEnrollment = serde_json::from_str(&body).unwrap();
+    assert!(enrollment.otpauth_uri.contains(&enrollment.secret));
+
+    let code = TotpCodeBody {
+      code: totp_code(&enrollment.secret, now() - 30),
+    };
+    let (status, body) = request(
+      Method::POST,
+      "/2fa/totp/confirm",
+      serde_json::to_string(&code).unwrap(),
+      Some(token),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let recovery: RecoveryCodesResponse = serde_json::from_str(&body).unwrap();
+    (enrollment.secret, recovery.recovery_codes)
+  }
+
+  async fn password_login() -> LoginChallengeResponse {
+    let body = r#"{"username": "tester", "password": "testtesttest"}"#.to_string();
+    let (status, body) = request(Method::POST, "/login", body, None).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "Request failed: {}", body);
+    serde_json::from_str(&body).unwrap()
+  }
+
+  async fn answer(challenge: &str, code: Option<String>, recovery_code: Option<String>) -> (StatusCode, String) {
+    let body = LoginChallengeBody {
+      challenge: challenge.to_string(),
+      code,
+      recovery_code,
+    };
+    request(Method::POST, "/login/2fa", serde_json::to_string(&body).unwrap(), None).await
+  }
+
+  #[tokio::test]
+  async fn login_requires_totp_once_enabled() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let (secret, _) = enroll(&token).await;
+
+    let challenge = password_login().await;
+    assert!(challenge.methods.contains(&"totp".to_string()));
+
+    let (status, _) = answer(&challenge.challenge, Some("000000".to_string()), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let code = totp_code(&secret, now());
+    let (status, body) = answer(&challenge.challenge, Some(code.clone()), None).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+    let login: LoginResponse = serde_json::from_str(&body).unwrap();
+    let (status, _) = request(Method::GET, "/user", String::new(), Some(&login.token)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Neither the challenge nor the code can be replayed
+    let (status, _) = answer(&challenge.challenge, Some(code.clone()), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = answer(&password_login().await.challenge, Some(code), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn recovery_codes_are_single_use() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let (_, recovery_codes) = enroll(&token).await;
+    assert_eq!(recovery_codes.len(), 10);
+
+    let recovery_code = recovery_codes[0].to_uppercase();
+    let (status, body) = answer(&password_login().await.challenge, None, Some(recovery_code.clone())).await;
+    assert_eq!(status, StatusCode::OK, "Request failed: {}", body);
+
+    let (status, _) = answer(&password_login().await.challenge, None, Some(recovery_code)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn challenge_is_dropped_after_too_many_attempts() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let (_, recovery_codes) = enroll(&token).await;
+
+    let challenge = password_login().await.challenge;
+    for _ in 0..5 {
+      let (status, _) = answer(&challenge, Some("000000".to_string()), None).await;
+      assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (status, body) = answer(&challenge, None, Some(recovery_codes[0].clone())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Invalid or expired challenge"));
+  }
+
+  #[tokio::test]
+  async fn fresh_challenges_do_not_allow_more_guesses() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    let (secret, _) = enroll(&token).await;
+
+    for _ in 0..LOCKOUT_POLICY.max_failures {
+      let (status, _) = answer(&password_login().await.challenge, Some("000000".to_string()), None).await;
+      assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    let code = totp_code(&secret, now());
+    let (status, body) = answer(&password_login().await.challenge, Some(code), None).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "Test failed: {}", body);
+  }
+
+  #[tokio::test]
+  async fn disabling_requires_password() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+    enroll(&token).await;
+
+    let (status, _) = request(Method::POST, "/2fa/totp", String::new(), Some(&token)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let wrong = DisableTwoFactorBody {
+      password: "wrongwrongwrong".to_string(),
+    };
+    let (status, _) = request(
+      Method::DELETE,
+      "/2fa/totp",
+      serde_json::to_string(&wrong).unwrap(),
+      Some(&token),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let right = DisableTwoFactorBody {
+      password: "testtesttest".to_string(),
+    };
+    let (status, _) = request(
+      Method::DELETE,
+      "/2fa/totp",
+      serde_json::to_string(&right).unwrap(),
+      Some(&token),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let body = r#"{"username": "tester", "password": "testtesttest"}"#.to_string();
+    let (status, _) = request(Method::POST, "/login", body, None).await;
+    assert_eq!(status, StatusCode::OK);
+  }
+}
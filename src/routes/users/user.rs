@@ -5,6 +5,7 @@ use serde::Serialize;
 
 use crate::routes::util::get_user_by_auth_header;
 use crate::routes::DB;
+use crate::scope::Scope;
 
 #[derive(Serialize)]
 pub struct UserResult {
@@ -12,12 +13,14 @@ pub struct UserResult {
   name: String,
   username: String,
   licensed: bool,
+  email: Option<String>,
+  email_verified: bool,
 }
 
 pub async fn get_user_by_token(req: Request<Body>) -> Result<Response<Body>, Infallible> {
   let db = DB.lock().await;
 
-  match get_user_by_auth_header(&db, &req) {
+  match get_user_by_auth_header(&db, &req, Scope::UserRead) {
     Ok((user, _)) => {
       Ok(
         Response::builder()
@@ -27,6 +30,8 @@ pub async fn get_user_by_token(req: Request<Body>) -> Result<Response<Body>, Inf
             serde_json::to_string(&UserResult {
               id: user.id.to_string(),
               licensed: user.is_licensed(),
+              email_verified: user.is_email_verified(),
+              email: user.email,
               name: user.name,
               username: user.username,
             })
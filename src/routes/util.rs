@@ -1,10 +1,20 @@
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+
 use diesel::{PgConnection, QueryDsl, RunQueryDsl};
 use hyper::{Body, Request, Response, StatusCode};
 use tracing::warn;
 use uuid::Uuid;
 
 use crate::diesel::ExpressionMethods;
-use crate::models::{update_session_last_used, Session, User};
+use crate::models::{
+  delete_session, find_api_key, update_api_key_last_used, update_session_last_used, ApiKey, Session, User,
+};
+use crate::routes::error::{error_response, ErrorBody};
+use crate::scope::Scope;
+use crate::session::SESSION_POLICY;
+use crate::tokens::{hash_token, is_api_key};
 
 #[macro_export]
 macro_rules! respond {
@@ -18,53 +28,358 @@ macro_rules! respond {
   };
 }
 
-pub fn get_user_by_auth(db: &PgConnection, user_token: Uuid) -> Result<(User, Session), String> {
-  use crate::schema::sessions::dsl::*;
-  use crate::schema::users::dsl::*;
+/// Responds with an [`ErrorBody`](crate::routes::error::ErrorBody) carrying a
+/// stable `code` and a human readable message.
+#[macro_export]
+macro_rules! respond_error {
+  ($response_code:expr, $code:expr, $message:expr) => {
+    Ok($crate::routes::error::error_response($response_code, $code, $message))
+  };
+}
+
+/// Address of the peer that opened the connection, inserted into the request
+/// extensions by the server.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+const MAX_USER_AGENT_LEN: usize = 512;
 
-  let result = sessions.filter(token.eq(user_token)).first(&*db);
-  if let Err(_) = result {
-    return Err("Invalid Token".to_string());
+/// Best guess at the client's IP. `X-Forwarded-For` is only honoured when
+/// `TRUST_FORWARDED_FOR` is set, as it is trivially spoofed without a proxy.
+pub fn client_ip(req: &Request<Body>) -> Option<IpAddr> {
+  let trust_forwarded = env::var("TRUST_FORWARDED_FOR");
+  if matches!(trust_forwarded.as_deref(), Ok("true") | Ok("1")) {
+    let forwarded = req
+      .headers()
+      .get("X-Forwarded-For")
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.split(',').next())
+      .and_then(|value| value.trim().parse().ok());
+    if forwarded.is_some() {
+      return forwarded;
+    }
   }
 
-  // TODO expire session token if it's been too long.
+  req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip())
+}
+
+pub fn user_agent(req: &Request<Body>) -> Option<String> {
+  req
+    .headers()
+    .get("User-Agent")
+    .and_then(|value| value.to_str().ok())
+    .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+  InvalidToken,
+  SessionExpired,
+  ApiKeyExpired,
+  AccountPendingDeletion,
+}
+
+impl AuthError {
+  pub fn code(&self) -> &'static str {
+    match self {
+      AuthError::InvalidToken => "invalid_token",
+      AuthError::SessionExpired => "session_expired",
+      AuthError::ApiKeyExpired => "api_key_expired",
+      AuthError::AccountPendingDeletion => "account_pending_deletion",
+    }
+  }
+}
+
+impl Display for AuthError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AuthError::InvalidToken => write!(f, "Invalid Token"),
+      AuthError::SessionExpired => write!(f, "Session expired"),
+      AuthError::ApiKeyExpired => write!(f, "API key expired"),
+      AuthError::AccountPendingDeletion => write!(f, "Account is scheduled for deletion"),
+    }
+  }
+}
+
+/// What the caller authenticated with.
+pub enum Credential {
+  Session(Session),
+  ApiKey(ApiKey),
+}
+
+impl Credential {
+  pub fn has_scope(&self, scope: Scope) -> bool {
+    match self {
+      Credential::Session(Session { scopes: None, .. }) => true,
+      Credential::Session(Session { scopes: Some(scopes), .. }) | Credential::ApiKey(ApiKey { scopes, .. }) => {
+        scopes.iter().any(|granted| granted == scope.as_str())
+      },
+    }
+  }
+}
+
+fn find_session(db: &PgConnection, user_token: Uuid) -> Result<Session, AuthError> {
+  use crate::schema::sessions;
 
-  let session: Session = result.unwrap();
-  if let Err(err) = update_session_last_used(&db, session.token) {
+  sessions::table
+    .filter(sessions::token_hash.eq(hash_token(&user_token.to_string())))
+    .first(db)
+    .map_err(|_| AuthError::InvalidToken)
+}
+
+/// Owner of a credential, as long as the account is not about to be deleted.
+fn credential_owner(db: &PgConnection, user_id: Uuid) -> Result<User, AuthError> {
+  use crate::schema::users;
+
+  let user: User = users::table
+    .filter(users::id.eq(user_id))
+    .first(db)
+    .map_err(|_| AuthError::InvalidToken)?;
+  if user.is_pending_deletion() {
+    return Err(AuthError::AccountPendingDeletion);
+  }
+
+  Ok(user)
+}
+
+pub fn get_user_by_auth(db: &PgConnection, user_token: Uuid) -> Result<(User, Session), AuthError> {
+  let session = find_session(db, user_token)?;
+  if SESSION_POLICY.is_expired(&session, chrono::Utc::now().naive_utc()) {
+    if let Err(err) = delete_session(db, session.id) {
+      warn!("Failed to delete expired session {}", err.to_string());
+    }
+    return Err(AuthError::SessionExpired);
+  }
+
+  if let Err(err) = update_session_last_used(&db, session.id) {
     warn!("Failed to update session last used token {}", err.to_string());
   }
 
-  let result = users.filter(id.eq(session.user_id)).first(&*db);
-  if result.is_err() {
-    return Err("Invalid Token".to_string());
+  Ok((credential_owner(db, session.user_id)?, session))
+}
+
+/// Like [`get_user_by_auth`], but leaves the session untouched. For looking
+/// at a token on behalf of someone else, such as during introspection.
+pub fn peek_user_by_auth(db: &PgConnection, user_token: Uuid) -> Result<(User, Session), AuthError> {
+  let session = find_session(db, user_token)?;
+  if SESSION_POLICY.is_expired(&session, chrono::Utc::now().naive_utc()) {
+    return Err(AuthError::SessionExpired);
   }
 
-  Ok((result.unwrap(), session))
+  Ok((credential_owner(db, session.user_id)?, session))
 }
 
-pub fn get_user_by_auth_header(db: &PgConnection, req: &Request<Body>) -> Result<(User, Session), Response<Body>> {
-  if req.headers().get("Authorization").is_none() {
-    return Err(
-      Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Body::from("No authorization header"))
-        .unwrap(),
-    );
+pub fn get_user_by_api_key(db: &PgConnection, key: &str) -> Result<(User, ApiKey), AuthError> {
+  let (user, api_key) = peek_user_by_api_key(db, key)?;
+
+  if let Err(err) = update_api_key_last_used(db, api_key.id) {
+    warn!("Failed to update API key last used {}", err.to_string());
   }
-  let user_token = Uuid::parse_str(req.headers().get("Authorization").unwrap().to_str().unwrap());
-  if user_token.is_err() {
-    return Err(
-      Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Body::from("Invalid authorization header"))
-        .unwrap(),
-    );
+
+  Ok((user, api_key))
+}
+
+/// Like [`get_user_by_api_key`], but does not record the key as used.
+pub fn peek_user_by_api_key(db: &PgConnection, key: &str) -> Result<(User, ApiKey), AuthError> {
+  let api_key = find_api_key(db, key).map_err(|_| AuthError::InvalidToken)?;
+  if api_key.is_expired(chrono::Utc::now().naive_utc()) {
+    return Err(AuthError::ApiKeyExpired);
   }
-  let user_token = user_token.unwrap();
 
-  get_user_by_auth(db, user_token).map_err(|e|
-    Response::builder()
-      .status(StatusCode::BAD_REQUEST)
-      .body(Body::from(e))
-      .unwrap())
+  Ok((credential_owner(db, api_key.user_id)?, api_key))
+}
+
+/// `429 Too Many Requests` telling the client when to try again.
+pub fn too_many_requests(retry_after: std::time::Duration) -> Response<Body> {
+  // Round up so clients never retry early
+  let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+  let mut res = error_response(
+    StatusCode::TOO_MANY_REQUESTS,
+    ErrorBody::TOO_MANY_REQUESTS,
+    "Too many requests, try again later",
+  );
+  res.headers_mut().insert("Retry-After", secs.into());
+  res
+}
+
+fn auth_error_response(e: AuthError) -> Response<Body> {
+  let status = match e {
+    AuthError::InvalidToken => StatusCode::BAD_REQUEST,
+    AuthError::SessionExpired | AuthError::ApiKeyExpired => StatusCode::UNAUTHORIZED,
+    AuthError::AccountPendingDeletion => StatusCode::FORBIDDEN,
+  };
+  error_response(status, e.code(), e.to_string())
+}
+
+/// Reads the credential from the `Authorization` header. OAuth clients send it
+/// with a `Bearer` prefix, first party clients send it bare.
+fn authorization_header(req: &Request<Body>) -> Result<&str, Response<Body>> {
+  match req.headers().get("Authorization") {
+    None => Err(error_response(
+      StatusCode::UNAUTHORIZED,
+      "missing_authorization",
+      "No authorization header",
+    )),
+    Some(header) => header
+      .to_str()
+      .map(|header| header.strip_prefix("Bearer ").unwrap_or(header))
+      .map_err(|_| {
+        error_response(
+          StatusCode::UNAUTHORIZED,
+          "invalid_authorization",
+          "Invalid authorization header",
+        )
+      }),
+  }
+}
+
+fn session_by_header(db: &PgConnection, header: &str) -> Result<(User, Session), Response<Body>> {
+  let user_token = Uuid::parse_str(header).map_err(|_| {
+    error_response(
+      StatusCode::UNAUTHORIZED,
+      "invalid_authorization",
+      "Invalid authorization header",
+    )
+  })?;
+
+  get_user_by_auth(db, user_token).map_err(auth_error_response)
+}
+
+/// Authenticates the request with either a session token or an API key that
+/// has been granted `scope`.
+pub fn get_user_by_auth_header(
+  db: &PgConnection,
+  req: &Request<Body>,
+  scope: Scope,
+) -> Result<(User, Credential), Response<Body>> {
+  let header = authorization_header(req)?;
+
+  let (user, credential) = if is_api_key(header) {
+    get_user_by_api_key(db, header)
+      .map(|(user, api_key)| (user, Credential::ApiKey(api_key)))
+      .map_err(auth_error_response)?
+  } else {
+    session_by_header(db, header).map(|(user, session)| (user, Credential::Session(session)))?
+  };
+
+  if !credential.has_scope(scope) {
+    return Err(error_response(
+      StatusCode::FORBIDDEN,
+      "missing_scope",
+      format!("Credential is missing scope {}", scope),
+    ));
+  }
+
+  Ok((user, credential))
+}
+
+/// Authenticates the request with a login session only, for endpoints that
+/// manage the account and must not be reachable with an API key or a token
+/// issued to an OAuth client.
+pub fn get_session_by_auth_header(db: &PgConnection, req: &Request<Body>) -> Result<(User, Session), Response<Body>> {
+  let header = authorization_header(req)?;
+  if is_api_key(header) {
+    return Err(error_response(
+      StatusCode::FORBIDDEN,
+      "api_key_not_allowed",
+      "API keys cannot be used for this endpoint",
+    ));
+  }
+
+  let (user, session) = session_by_header(db, header)?;
+  if session.oauth_client_id.is_some() {
+    return Err(error_response(
+      StatusCode::FORBIDDEN,
+      "oauth_token_not_allowed",
+      "OAuth tokens cannot be used for this endpoint",
+    ));
+  }
+
+  Ok((user, session))
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::Duration;
+  use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+  use hyper::{Method, StatusCode};
+
+  use crate::models::delete_expired_sessions;
+  use crate::routes::error::test::error_code;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::test::{before_user_test, register_and_login};
+  use crate::routes::{handle_requests, DB};
+  use crate::schema::sessions;
+  use crate::session::SESSION_POLICY;
+  use crate::tokens::hash_token;
+
+  async fn age_session(token: &str, idle: Duration, age: Duration) {
+    let now = chrono::Utc::now().naive_utc();
+    let db = DB.lock().await;
+    diesel::update(sessions::table.filter(sessions::token_hash.eq(hash_token(token))))
+      .set((sessions::last_used.eq(now - idle), sessions::created_at.eq(now - age)))
+      .execute(&*db)
+      .unwrap();
+  }
+
+  async fn assert_session_expired(token: String) {
+    let res = handle_requests(build_test_request(Method::GET, "/user", "", Some(token.clone())))
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "Test failed: {}", body);
+    assert_eq!(error_code(&body), "session_expired");
+
+    // Expired sessions are removed on first use
+    let res = handle_requests(build_test_request(Method::GET, "/user", "", Some(token)))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn idle_session_expires() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let idle = SESSION_POLICY.idle_timeout.unwrap() + Duration::minutes(1);
+    age_session(&token, idle, idle).await;
+
+    assert_session_expired(token).await;
+  }
+
+  #[tokio::test]
+  async fn old_session_expires_while_in_use() {
+    before_user_test().await;
+    let token = register_and_login("tester").await;
+
+    let age = SESSION_POLICY.max_lifetime.unwrap() + Duration::minutes(1);
+    age_session(&token, Duration::zero(), age).await;
+
+    assert_session_expired(token).await;
+  }
+
+  #[tokio::test]
+  async fn reaper_purges_only_expired_sessions() {
+    before_user_test().await;
+    let stale = register_and_login("tester").await;
+    let fresh = register_and_login("testertwo").await;
+
+    let idle = SESSION_POLICY.idle_timeout.unwrap() + Duration::minutes(1);
+    age_session(&stale, idle, idle).await;
+
+    let now = chrono::Utc::now().naive_utc();
+    let db = DB.lock().await;
+    let deleted = delete_expired_sessions(
+      &db,
+      SESSION_POLICY.idle_cutoff(now),
+      SESSION_POLICY.lifetime_cutoff(now),
+    )
+    .unwrap();
+    assert_eq!(deleted, 1);
+
+    let remaining: Vec<String> = sessions::table.select(sessions::token_hash).load(&*db).unwrap();
+    assert_eq!(remaining, vec![hash_token(&fresh)]);
+  }
 }
@@ -0,0 +1,163 @@
+use std::convert::Infallible;
+
+use futures::FutureExt;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::jwt::{JwkSet, ACCESS_TOKENS};
+use crate::respond_error;
+use crate::route_func;
+use crate::router::{Routable, RoutedFunction};
+use crate::routes::error::ErrorBody;
+use crate::scope::Scope;
+
+pub struct WellKnownRouter;
+
+impl Routable for WellKnownRouter {
+  fn routes(&self) -> Vec<RoutedFunction> {
+    vec![
+      route_func!(Method::GET, "/.well-known/jwks.json", get_jwks),
+      route_func!(
+        Method::GET,
+        "/.well-known/openid-configuration",
+        get_openid_configuration
+      ),
+    ]
+  }
+}
+
+pub async fn get_jwks(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let jwks = JwkSet {
+    keys: ACCESS_TOKENS.iter().map(|signer| signer.jwk()).collect(),
+  };
+
+  Ok(
+    Response::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/json")
+      .header("Cache-Control", "public, max-age=3600")
+      .body(Body::from(serde_json::to_string(&jwks).unwrap()))
+      .unwrap(),
+  )
+}
+
+/// OpenID Connect discovery document, see OpenID Connect Discovery 1.0
+/// section 3.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize))]
+pub struct OpenIdConfiguration {
+  pub issuer: String,
+  pub authorization_endpoint: String,
+  pub token_endpoint: String,
+  pub userinfo_endpoint: String,
+  pub jwks_uri: String,
+  pub scopes_supported: Vec<String>,
+  pub response_types_supported: Vec<String>,
+  pub grant_types_supported: Vec<String>,
+  pub subject_types_supported: Vec<String>,
+  pub id_token_signing_alg_values_supported: Vec<String>,
+  pub token_endpoint_auth_methods_supported: Vec<String>,
+  pub code_challenge_methods_supported: Vec<String>,
+  pub claims_supported: Vec<String>,
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+  values.iter().map(|value| value.to_string()).collect()
+}
+
+/// Only served when signed tokens are enabled, since `id_token`s are signed
+/// with the access token key.
+pub async fn get_openid_configuration(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+  let signer = match ACCESS_TOKENS.as_ref() {
+    Some(signer) => signer,
+    None => return respond_error!(StatusCode::NOT_FOUND, ErrorBody::NOT_ENABLED, "OpenID Connect is not enabled"),
+  };
+
+  let issuer = signer.issuer.trim_end_matches('/');
+  let configuration = OpenIdConfiguration {
+    issuer: signer.issuer.clone(),
+    authorization_endpoint: format!("{}/authorize", issuer),
+    token_endpoint: format!("{}/token", issuer),
+    userinfo_endpoint: format!("{}/userinfo", issuer),
+    jwks_uri: format!("{}/.well-known/jwks.json", issuer),
+    scopes_supported: Scope::ALL.iter().map(|scope| scope.to_string()).collect(),
+    response_types_supported: strings(&["code"]),
+    grant_types_supported: strings(&["authorization_code"]),
+    subject_types_supported: strings(&["public"]),
+    id_token_signing_alg_values_supported: strings(&["EdDSA"]),
+    token_endpoint_auth_methods_supported: strings(&["client_secret_basic", "client_secret_post", "none"]),
+    code_challenge_methods_supported: strings(&["S256"]),
+    claims_supported: strings(&["sub", "name", "preferred_username", "licensed", "nonce"]),
+  };
+
+  Ok(
+    Response::builder()
+      .status(StatusCode::OK)
+      .header("Content-Type", "application/json")
+      .header("Cache-Control", "public, max-age=3600")
+      .body(Body::from(serde_json::to_string(&configuration).unwrap()))
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use hyper::{Method, StatusCode};
+
+  use super::OpenIdConfiguration;
+  use crate::jwt::{AccessClaims, JwkSet, ACCESS_TOKENS};
+  use crate::routes::handle_requests;
+  use crate::routes::test::build_test_request;
+  use crate::routes::users::login::LoginResponse;
+  use crate::routes::users::test::before_user_test;
+
+  #[tokio::test]
+  async fn login_issues_token_verifiable_with_jwks() {
+    before_user_test().await;
+    let body = r#"{"name": "Tester McTester", "username": "tester", "password": "testtesttest"}"#;
+    handle_requests(build_test_request(Method::POST, "/register", body, None))
+      .await
+      .unwrap();
+    let res = handle_requests(build_test_request(Method::POST, "/login", body, None))
+      .await
+      .unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let login: LoginResponse = serde_json::from_slice(&body).unwrap();
+
+    let res = handle_requests(build_test_request(Method::GET, "/.well-known/jwks.json", "", None))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let jwks: JwkSet = serde_json::from_slice(&body).unwrap();
+    assert_eq!(jwks.keys.len(), 1);
+    assert_eq!(jwks.keys[0].crv, "Ed25519");
+
+    let signer = ACCESS_TOKENS.as_ref().unwrap();
+    let claims: AccessClaims = signer.verify(&login.access_token.unwrap()).unwrap();
+    assert_eq!(claims.username, "tester");
+    assert!(!claims.licensed);
+    assert_eq!(login.expires_in, Some(signer.ttl.num_seconds()));
+  }
+
+  #[tokio::test]
+  async fn discovery_points_at_oauth_endpoints() {
+    let res = handle_requests(build_test_request(
+      Method::GET,
+      "/.well-known/openid-configuration",
+      "",
+      None,
+    ))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let configuration: OpenIdConfiguration = serde_json::from_slice(&body).unwrap();
+
+    let issuer = &ACCESS_TOKENS.as_ref().unwrap().issuer;
+    assert_eq!(&configuration.issuer, issuer);
+    assert!(configuration.token_endpoint.starts_with(issuer.as_str()));
+    assert!(configuration.jwks_uri.ends_with("/.well-known/jwks.json"));
+    assert!(configuration.scopes_supported.contains(&"openid".to_string()));
+  }
+}
@@ -1,15 +1,24 @@
+use std::cell::RefCell;
 use std::convert::Infallible;
+use std::rc::Rc;
 use std::{str::FromStr, net::SocketAddr};
 
 use capnp::{capability::Promise, Error};
 use capnp_rpc::pry;
 use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use chrono::{TimeZone, Utc};
 use futures::AsyncReadExt;
+use hashbrown::HashMap;
 use ipv8_proto_rust::user_auth::{Server, GetUserParams, GetUserResults, Client};
-use tracing::info;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::routes::{util::get_user_by_auth, DB};
+use crate::deletion::DELETED_ACCOUNTS;
+use crate::models::{Session, User};
+use crate::routes::util::{get_user_by_auth, AuthError};
+use crate::routes::DB;
+use crate::user_details_capnp::{subscription, user_details, user_listener};
 
 struct UserAuth;
 
@@ -31,42 +40,175 @@ impl Server for UserAuth {
 
     let db = DB.blocking_lock();
 
-    if let Ok((user, session)) = get_user_by_auth(&db, auth_token) {
-      let mut builder = results.get().init_user();
-      builder.set_auth_token(&session.token.to_string());
-      builder.set_id(&user.id.to_string());
-      builder.set_licensed(user.is_licensed());
-      builder.set_name(&user.name);
-      builder.set_username(&user.username);
-
-      Promise::ok(())
-    } else {
-      Promise::err(Error::failed("Could not find user with given auth token".to_string()))
+    match get_user_by_auth(&db, auth_token) {
+      Ok((user, _)) => {
+        let mut builder = results.get().init_user();
+        builder.set_auth_token(&auth_token.to_string());
+        builder.set_id(&user.id.to_string());
+        builder.set_licensed(user.is_licensed());
+        builder.set_name(&user.name);
+        builder.set_username(&user.username);
+        // The proto User struct has no email fields, UserDetails serves them
+
+        Promise::ok(())
+      },
+      Err(AuthError::InvalidToken) => {
+        Promise::err(Error::failed("Could not find user with given auth token".to_string()))
+      },
+      Err(err) => Promise::err(Error::failed(err.to_string())),
     }
   }
 }
 
-pub async fn start_rpc_server(addr: SocketAddr) -> Result<Infallible, std::io::Error> {
-  tokio::task::LocalSet::new().run_until(async move {
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    let client: Client = capnp_rpc::new_client(UserAuth);
+/// Listeners subscribed through `UserDetails`, by subscription.
+type Listeners = Rc<RefCell<HashMap<u64, user_listener::Client>>>;
+
+/// Serves what `UserAuth` cannot carry, see `schema/user_details.capnp`.
+struct UserDetails {
+  listeners: Listeners,
+  next_subscription: u64,
+}
 
-    info!("Started UserAuth RPC Server on TCP {}", addr);
-    loop {
-        let (stream, _) = listener.accept().await?;
-        stream.set_nodelay(true)?;
-        let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
-        let network = twoparty::VatNetwork::new(
-            reader,
-            writer,
-            rpc_twoparty_capnp::Side::Server,
-            Default::default(),
-        );
-
-        let rpc_system =
-            RpcSystem::new(Box::new(network), Some(client.clone().client));
-
-        tokio::task::spawn_local(rpc_system);
+impl UserDetails {
+  async fn authenticate(auth_token: &str) -> Result<(User, Session), Error> {
+    let auth_token = Uuid::from_str(auth_token).map_err(|err| Error::failed(err.to_string()))?;
+    let db = DB.lock().await;
+    get_user_by_auth(&db, auth_token).map_err(|err| match err {
+      AuthError::InvalidToken => Error::failed("Could not find user with given auth token".to_string()),
+      err => Error::failed(err.to_string()),
+    })
+  }
+}
+
+impl user_details::Server for UserDetails {
+  fn get_details(
+    &mut self,
+    params: user_details::GetDetailsParams,
+    mut results: user_details::GetDetailsResults,
+  ) -> Promise<(), Error> {
+    Promise::from_future(async move {
+      let (user, session) = Self::authenticate(params.get()?.get_auth_token()?).await?;
+
+      let mut details = results.get().init_details();
+      details.set_user_id(&user.id.to_string());
+      if let Some(email) = &user.email {
+        details.set_email(email);
+      }
+      details.set_email_verified(user.is_email_verified());
+      let mut builder = details.init_session();
+      builder.set_id(&session.id.to_string());
+      builder.set_created_at(Utc.from_utc_datetime(&session.created_at).timestamp());
+      builder.set_last_used(Utc.from_utc_datetime(&session.last_used).timestamp());
+      if let Some(ip) = &session.ip {
+        builder.set_ip(ip);
       }
+      if let Some(user_agent) = &session.user_agent {
+        builder.set_user_agent(user_agent);
+      }
+      if let Some(device_label) = &session.device_label {
+        builder.set_device_label(device_label);
+      }
+
+      Ok(())
+    })
+  }
+
+  fn subscribe(
+    &mut self,
+    params: user_details::SubscribeParams,
+    mut results: user_details::SubscribeResults,
+  ) -> Promise<(), Error> {
+    let listener = pry!(pry!(params.get()).get_listener());
+    let id = self.next_subscription;
+    self.next_subscription += 1;
+    self.listeners.borrow_mut().insert(id, listener);
+
+    results.get().set_subscription(capnp_rpc::new_client(Subscription {
+      id,
+      listeners: self.listeners.clone(),
+    }));
+    Promise::ok(())
+  }
+}
+
+/// Handed to subscribers, who stop being notified once they drop it.
+struct Subscription {
+  id: u64,
+  listeners: Listeners,
+}
+
+impl subscription::Server for Subscription {}
+
+impl Drop for Subscription {
+  fn drop(&mut self) {
+    self.listeners.borrow_mut().remove(&self.id);
+  }
+}
+
+/// Tells every subscribed listener about accounts purged after their
+/// deletion grace period. Listeners that fail to answer are dropped.
+async fn notify_deleted_accounts(listeners: Listeners) {
+  let mut deleted = DELETED_ACCOUNTS.subscribe();
+  loop {
+    let uid = match deleted.recv().await {
+      Ok(uid) => uid,
+      Err(RecvError::Lagged(missed)) => {
+        warn!("Missed {} deleted accounts while notifying RPC listeners", missed);
+        continue;
+      },
+      Err(RecvError::Closed) => return,
+    };
+
+    for (id, listener) in listeners.borrow().iter() {
+      let mut request = listener.user_deleted_request();
+      request.get().set_user_id(&uid.to_string());
+      let (id, listeners) = (*id, listeners.clone());
+      tokio::task::spawn_local(async move {
+        if let Err(err) = request.send().promise.await {
+          warn!("Dropping RPC listener that failed to take a deleted account: {}", err);
+          listeners.borrow_mut().remove(&id);
+        }
+      });
+    }
+  }
+}
+
+/// Serves `UserAuth` on `addr`, and `UserDetails` on `details_addr` when set.
+pub async fn start_rpc_server(addr: SocketAddr, details_addr: Option<SocketAddr>) -> Result<Infallible, std::io::Error> {
+  tokio::task::LocalSet::new().run_until(async move {
+    let client: Client = capnp_rpc::new_client(UserAuth);
+    let user_auth = serve(addr, client.client, "UserAuth");
+    match details_addr {
+      Some(details_addr) => {
+        let listeners = Listeners::default();
+        tokio::task::spawn_local(notify_deleted_accounts(listeners.clone()));
+        let client: user_details::Client = capnp_rpc::new_client(UserDetails {
+          listeners,
+          next_subscription: 0,
+        });
+        tokio::select! {
+          result = user_auth => result,
+          result = serve(details_addr, client.client, "UserDetails") => result,
+        }
+      },
+      None => user_auth.await,
+    }
   }).await
 }
+
+/// Accepts connections on `addr` with `client` as their bootstrap interface.
+async fn serve(addr: SocketAddr, client: capnp::capability::Client, name: &str) -> Result<Infallible, std::io::Error> {
+  let listener = tokio::net::TcpListener::bind(&addr).await?;
+
+  info!("Started {} RPC Server on TCP {}", name, addr);
+  loop {
+    let (stream, _) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+    let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+    let network = twoparty::VatNetwork::new(reader, writer, rpc_twoparty_capnp::Side::Server, Default::default());
+
+    let rpc_system = RpcSystem::new(Box::new(network), Some(client.clone()));
+
+    tokio::task::spawn_local(rpc_system);
+  }
+}
@@ -1,3 +1,27 @@
+table! {
+    api_keys (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        prefix -> Varchar,
+        key_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    email_verifications (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        email -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
 table! {
     games (user_id) {
         user_id -> Uuid,
@@ -10,10 +34,114 @@ table! {
 }
 
 table! {
-    sessions (token) {
-        token -> Uuid,
+    login_challenges (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        device_label -> Nullable<Varchar>,
+        attempts -> Int4,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    login_throttles (subject) {
+        subject -> Varchar,
+        failed_attempts -> Int4,
+        last_failed_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    oauth_authorization_codes (code_hash) {
+        code_hash -> Varchar,
+        client_id -> Uuid,
+        user_id -> Uuid,
+        redirect_uri -> Text,
+        scopes -> Array<Text>,
+        code_challenge -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        nonce -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    oauth_clients (id) {
+        id -> Uuid,
+        owner_id -> Uuid,
+        name -> Varchar,
+        secret_hash -> Nullable<Varchar>,
+        redirect_uris -> Array<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    passkeys (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        credential_id -> Bytea,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        name -> Varchar,
+        created_at -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_resets (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    recovery_codes (code_hash) {
+        code_hash -> Varchar,
+        user_id -> Uuid,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        session_id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Uuid,
+        token_hash -> Varchar,
         user_id -> Uuid,
         last_used -> Timestamp,
+        created_at -> Timestamp,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        device_label -> Nullable<Varchar>,
+        scopes -> Nullable<Array<Text>>,
+        oauth_client_id -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    totp_credentials (user_id) {
+        user_id -> Uuid,
+        secret -> Varchar,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamp,
     }
 }
 
@@ -22,16 +150,56 @@ table! {
         id -> Uuid,
         name -> Varchar,
         username -> Varchar,
-        password_digest -> Varchar,
+        password_digest -> Nullable<Varchar>,
         license_game_stage -> Int4,
+        email -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamp>,
+        username_skeleton -> Varchar,
+        deletion_due_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webauthn_challenges (id) {
+        id -> Uuid,
+        user_id -> Nullable<Uuid>,
+        challenge -> Varchar,
+        purpose -> Varchar,
+        expires_at -> Timestamp,
     }
 }
 
+joinable!(api_keys -> users (user_id));
+joinable!(email_verifications -> users (user_id));
 joinable!(games -> users (user_id));
+joinable!(login_challenges -> users (user_id));
+joinable!(oauth_authorization_codes -> oauth_clients (client_id));
+joinable!(oauth_authorization_codes -> users (user_id));
+joinable!(oauth_clients -> users (owner_id));
+joinable!(passkeys -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(refresh_tokens -> sessions (session_id));
+joinable!(refresh_tokens -> users (user_id));
+joinable!(sessions -> oauth_clients (oauth_client_id));
 joinable!(sessions -> users (user_id));
+joinable!(totp_credentials -> users (user_id));
+joinable!(webauthn_challenges -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_keys,
+    email_verifications,
     games,
+    login_challenges,
+    login_throttles,
+    oauth_authorization_codes,
+    oauth_clients,
+    passkeys,
+    password_resets,
+    recovery_codes,
+    refresh_tokens,
     sessions,
+    totp_credentials,
     users,
+    webauthn_challenges,
 );
@@ -0,0 +1,47 @@
+use std::fmt::Display;
+
+/// Permission a credential can be limited to. Sessions created by logging in
+/// hold every scope, API keys and OAuth tokens only those they were granted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+  UserRead,
+  GamePlay,
+  OpenId,
+}
+
+impl Scope {
+  pub const ALL: [Scope; 3] = [Scope::UserRead, Scope::GamePlay, Scope::OpenId];
+
+  /// `openid` only makes sense for OAuth clients signing a user in, so API
+  /// keys cannot be granted it.
+  pub fn grantable_to_api_keys(&self) -> bool {
+    !matches!(self, Scope::OpenId)
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Scope::UserRead => "user:read",
+      Scope::GamePlay => "game:play",
+      Scope::OpenId => "openid",
+    }
+  }
+
+  /// Shown to the user when an OAuth client asks for this scope.
+  pub fn description(&self) -> &'static str {
+    match self {
+      Scope::UserRead => "Read your username, company name and license status",
+      Scope::GamePlay => "Take the Operations License exam on your behalf",
+      Scope::OpenId => "Sign you in with your ipv8 account",
+    }
+  }
+
+  pub fn parse(scope: &str) -> Option<Scope> {
+    Self::ALL.iter().copied().find(|known| known.as_str() == scope)
+  }
+}
+
+impl Display for Scope {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.as_str())
+  }
+}
@@ -0,0 +1,147 @@
+use std::env;
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, NaiveDateTime};
+use tracing::{debug, error, info};
+
+use crate::deletion::DELETION_POLICY;
+use crate::lockout::LOCKOUT_POLICY;
+use crate::models::{
+  delete_expired_email_verifications, delete_expired_login_challenges, delete_expired_login_throttles,
+  delete_expired_oauth_authorization_codes, delete_expired_password_resets, delete_expired_refresh_tokens,
+  delete_expired_sessions, delete_expired_webauthn_challenges, Session,
+};
+use crate::routes::DB;
+
+const DEFAULT_IDLE_TIMEOUT_SECS: i64 = 60 * 60 * 24 * 7;
+const DEFAULT_MAX_LIFETIME_SECS: i64 = 60 * 60 * 24 * 30;
+const DEFAULT_REAP_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Lifetime rules applied to every session lookup.
+///
+/// Configured through `SESSION_IDLE_TIMEOUT` and `SESSION_MAX_LIFETIME`, both
+/// in seconds. A value of `0` disables that check.
+pub struct SessionPolicy {
+  pub idle_timeout: Option<Duration>,
+  pub max_lifetime: Option<Duration>,
+}
+
+impl SessionPolicy {
+  pub fn from_env() -> Self {
+    Self {
+      idle_timeout: duration_from_env("SESSION_IDLE_TIMEOUT", DEFAULT_IDLE_TIMEOUT_SECS),
+      max_lifetime: duration_from_env("SESSION_MAX_LIFETIME", DEFAULT_MAX_LIFETIME_SECS),
+    }
+  }
+
+  pub fn idle_cutoff(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    self.idle_timeout.map(|timeout| now - timeout)
+  }
+
+  pub fn lifetime_cutoff(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    self.max_lifetime.map(|lifetime| now - lifetime)
+  }
+
+  pub fn is_expired(&self, session: &Session, now: NaiveDateTime) -> bool {
+    let idle = matches!(self.idle_cutoff(now), Some(cutoff) if session.last_used < cutoff);
+    let too_old = matches!(self.lifetime_cutoff(now), Some(cutoff) if session.created_at < cutoff);
+    idle || too_old
+  }
+
+  /// When `session` expires if it is not used again after `last_used`.
+  pub fn expires_at(&self, session: &Session, last_used: NaiveDateTime) -> Option<NaiveDateTime> {
+    let idle = self.idle_timeout.map(|timeout| last_used + timeout);
+    let too_old = self.max_lifetime.map(|lifetime| session.created_at + lifetime);
+    idle.into_iter().chain(too_old).min()
+  }
+}
+
+fn duration_from_env(key: &str, default_secs: i64) -> Option<Duration> {
+  let secs = match env::var(key) {
+    Ok(value) => value
+      .parse::<i64>()
+      .unwrap_or_else(|_| panic!("Failed to parse {} as seconds. Found {}", key, value)),
+    Err(_) => default_secs,
+  };
+
+  if secs > 0 {
+    Some(Duration::seconds(secs))
+  } else {
+    None
+  }
+}
+
+lazy_static::lazy_static! {
+  pub static ref SESSION_POLICY: SessionPolicy = SessionPolicy::from_env();
+}
+
+/// Periodically purges expired sessions, refresh tokens, authorization codes,
+/// login challenges, email verifications, password resets, passkey
+/// challenges and stale login throttles so the tables do not grow forever.
+/// Accounts whose deletion grace period is over are deleted along the way.
+/// The interval is read from `SESSION_REAP_INTERVAL` in seconds.
+pub async fn start_session_reaper() {
+  let interval_secs = env::var("SESSION_REAP_INTERVAL")
+    .ok()
+    .and_then(|value| value.parse::<u64>().ok())
+    .filter(|secs| *secs > 0)
+    .unwrap_or(DEFAULT_REAP_INTERVAL_SECS);
+  let mut interval = tokio::time::interval(StdDuration::from_secs(interval_secs));
+
+  info!("Started session reaper, running every {}s", interval_secs);
+  loop {
+    interval.tick().await;
+
+    let now = chrono::Utc::now().naive_utc();
+    let db = DB.lock().await;
+    match delete_expired_sessions(
+      &db,
+      SESSION_POLICY.idle_cutoff(now),
+      SESSION_POLICY.lifetime_cutoff(now),
+    ) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired sessions", count),
+      Err(err) => error!("Failed to reap expired sessions {}", err.to_string()),
+    }
+    match delete_expired_refresh_tokens(&db, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired refresh tokens", count),
+      Err(err) => error!("Failed to reap expired refresh tokens {}", err.to_string()),
+    }
+    match delete_expired_oauth_authorization_codes(&db, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired authorization codes", count),
+      Err(err) => error!("Failed to reap expired authorization codes {}", err.to_string()),
+    }
+    match delete_expired_login_challenges(&db, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired login challenges", count),
+      Err(err) => error!("Failed to reap expired login challenges {}", err.to_string()),
+    }
+    match delete_expired_email_verifications(&db, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired email verifications", count),
+      Err(err) => error!("Failed to reap expired email verifications {}", err.to_string()),
+    }
+    match delete_expired_password_resets(&db, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired password resets", count),
+      Err(err) => error!("Failed to reap expired password resets {}", err.to_string()),
+    }
+    match delete_expired_webauthn_challenges(&db, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} expired passkey challenges", count),
+      Err(err) => error!("Failed to reap expired passkey challenges {}", err.to_string()),
+    }
+    match delete_expired_login_throttles(&db, now - LOCKOUT_POLICY.failure_window, now) {
+      Ok(0) => {},
+      Ok(count) => debug!("Reaped {} stale login throttles", count),
+      Err(err) => error!("Failed to reap stale login throttles {}", err.to_string()),
+    }
+    match DELETION_POLICY.purge_due_accounts(&db, now) {
+      Ok(0) => {},
+      Ok(count) => info!("Deleted {} accounts after their grace period", count),
+      Err(err) => error!("Failed to delete accounts due for deletion {}", err.to_string()),
+    }
+  }
+}
@@ -0,0 +1,95 @@
+use std::env;
+
+use hmac::{Hmac, Mac};
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::Rng;
+use sha2::Sha256;
+
+/// Marks a bearer token as a personal API key rather than a session token.
+pub const API_KEY_PREFIX: &str = "ipv8_";
+const API_KEY_ID_LEN: usize = 8;
+const API_KEY_SECRET_LEN: usize = 32;
+const SECRET_LEN: usize = 43;
+const RECOVERY_CODE_HALF_LEN: usize = 5;
+
+lazy_static::lazy_static! {
+  static ref TOKEN_KEY: Vec<u8> = env::var("SESSION_TOKEN_KEY")
+    .expect("Could not find the environment variable SESSION_TOKEN_KEY")
+    .into_bytes();
+}
+
+/// Keyed hash of a bearer token as stored in the database. Only the hash is
+/// persisted so a database dump does not hand out live credentials.
+pub fn hash_token(token: &str) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(&TOKEN_KEY).expect("HMAC accepts keys of any length");
+  mac.update(token.as_bytes());
+  hex::encode(mac.finalize().into_bytes())
+}
+
+fn random_string(len: usize) -> String {
+  OsRng.sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+/// Random opaque secret for client secrets, authorization codes and the like.
+pub fn generate_secret() -> String {
+  random_string(SECRET_LEN)
+}
+
+/// Generates a new API key of the form `ipv8_<prefix>_<secret>`, returning the
+/// displayable prefix along with the full key.
+pub fn generate_api_key() -> (String, String) {
+  let prefix = random_string(API_KEY_ID_LEN);
+  let key = format!("{}{}_{}", API_KEY_PREFIX, prefix, random_string(API_KEY_SECRET_LEN));
+  (prefix, key)
+}
+
+pub fn is_api_key(token: &str) -> bool {
+  token.starts_with(API_KEY_PREFIX)
+}
+
+/// Generates a two factor recovery code such as `k3x9a-7qmdz`.
+pub fn generate_recovery_code() -> String {
+  let half = || random_string(RECOVERY_CODE_HALF_LEN).to_lowercase();
+  format!("{}-{}", half(), half())
+}
+
+/// Canonical form of a recovery code as typed by a user, ignoring case,
+/// spaces and dashes.
+pub fn normalize_recovery_code(code: &str) -> String {
+  code
+    .chars()
+    .filter(|c| c.is_ascii_alphanumeric())
+    .map(|c| c.to_ascii_lowercase())
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::{generate_api_key, generate_recovery_code, hash_token, is_api_key, normalize_recovery_code};
+
+  #[test]
+  fn hash_is_stable_and_hex_encoded() {
+    dotenv::dotenv().ok();
+    let token = "22222222-2222-2222-2222-222222222222";
+    assert_eq!(hash_token(token), hash_token(token));
+    assert_eq!(hash_token(token).len(), 64);
+    assert_ne!(hash_token(token), hash_token("33333333-3333-3333-3333-333333333333"));
+  }
+
+  #[test]
+  fn api_keys_carry_their_prefix() {
+    let (prefix, key) = generate_api_key();
+    assert!(is_api_key(&key));
+    assert!(key.starts_with(&format!("ipv8_{}_", prefix)));
+    assert_ne!(generate_api_key().1, key);
+  }
+
+  #[test]
+  fn recovery_codes_normalize() {
+    let code = generate_recovery_code();
+    assert_eq!(code.len(), 11);
+    let typed = format!(" {} ", code.to_uppercase().replace('-', " "));
+    assert_eq!(normalize_recovery_code(&typed), code.replace('-', ""));
+  }
+}
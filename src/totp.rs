@@ -0,0 +1,86 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "ipv8";
+const SECRET_BYTES: usize = 20;
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+
+/// Random base32 encoded TOTP secret.
+pub fn generate_totp_secret() -> String {
+  let mut secret = [0u8; SECRET_BYTES];
+  OsRng.fill_bytes(&mut secret);
+  Secret::Raw(secret.to_vec()).to_encoded().to_string()
+}
+
+fn totp(secret: &str, username: &str) -> TOTP {
+  let secret = Secret::Encoded(secret.to_string())
+    .to_bytes()
+    .expect("TOTP secrets are stored base32 encoded");
+  TOTP::new_unchecked(
+    Algorithm::SHA1,
+    DIGITS,
+    0,
+    STEP_SECS,
+    secret,
+    Some(ISSUER.to_string()),
+    username.to_string(),
+  )
+}
+
+/// `otpauth://` URI for authenticator apps, usually shown as a QR code.
+pub fn otpauth_uri(secret: &str, username: &str) -> String {
+  totp(secret, username).get_url()
+}
+
+pub fn totp_code(secret: &str, time: u64) -> String {
+  totp(secret, "").generate(time)
+}
+
+/// Checks `code` against the steps around `time`, allowing one step of clock
+/// drift either way. Returns the matching step so callers can refuse to accept
+/// the same code twice. Every step is compared in constant time, so timing
+/// does not reveal how much of a guess was right.
+pub fn verify_totp(secret: &str, code: &str, time: u64) -> Option<i64> {
+  let totp = totp(secret, "");
+  let step = time / STEP_SECS;
+  let mut matched = None;
+  for step in [step - 1, step, step + 1] {
+    if bool::from(totp.generate(step * STEP_SECS).as_bytes().ct_eq(code.as_bytes())) {
+      matched = Some(step as i64);
+    }
+  }
+  matched
+}
+
+#[cfg(test)]
+mod test {
+  use super::{generate_totp_secret, otpauth_uri, totp_code, verify_totp};
+
+  #[test]
+  fn accepts_adjacent_steps_only() {
+    let secret = generate_totp_secret();
+    let now = 1_800_000_000;
+    assert_eq!(verify_totp(&secret, &totp_code(&secret, now), now), Some(60_000_000));
+    assert!(verify_totp(&secret, &totp_code(&secret, now - 30), now).is_some());
+    assert!(verify_totp(&secret, &totp_code(&secret, now + 30), now).is_some());
+    assert!(verify_totp(&secret, &totp_code(&secret, now - 90), now).is_none());
+  }
+
+  #[test]
+  fn rfc6238_test_vector() {
+    // "12345678901234567890" from RFC 6238 appendix B, truncated to 6 digits.
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    assert_eq!(totp_code(secret, 59), "287082");
+    assert_eq!(totp_code(secret, 1111111109), "081804");
+  }
+
+  #[test]
+  fn uri_names_issuer_and_account() {
+    let uri = otpauth_uri("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", "tester");
+    assert!(uri.starts_with("otpauth://totp/ipv8:tester?"));
+    assert!(uri.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+  }
+}
@@ -1,4 +1,5 @@
 use std::env;
+use std::str::FromStr;
 
 #[inline(always)]
 pub fn get_server_url() -> String {
@@ -9,6 +10,17 @@ pub fn get_server_url() -> String {
   )
 }
 
+/// Parses `name` from the environment, falling back to `default` when unset.
+pub fn parse_env<T: FromStr>(name: &str, default: T) -> T {
+  env::var(name)
+    .map(|value| {
+      value
+        .parse()
+        .unwrap_or_else(|_| panic!("Failed to parse {}. Found {}", name, value))
+    })
+    .unwrap_or(default)
+}
+
 pub fn external_url() -> String {
   env::var("EXTERNAL_URL").unwrap_or(get_server_url())
 }
@@ -0,0 +1,450 @@
+use std::env;
+use std::fmt::Display;
+
+use ciborium::value::{Integer, Value};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::EncodedPoint;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+const CHALLENGE_BYTES: usize = 32;
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256, the only
+/// algorithm accepted for passkeys.
+pub const COSE_ALG_ES256: i64 = -7;
+
+lazy_static::lazy_static! {
+  pub static ref RELYING_PARTY: RelyingParty = RelyingParty::from_env();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WebAuthnError {
+  Malformed,
+  WrongType,
+  ChallengeMismatch,
+  WrongOrigin,
+  WrongRelyingParty,
+  UserNotVerified,
+  UnsupportedAlgorithm,
+  BadSignature,
+  CounterRegression,
+}
+
+impl Display for WebAuthnError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      WebAuthnError::Malformed => write!(f, "Malformed WebAuthn response"),
+      WebAuthnError::WrongType => write!(f, "Unexpected WebAuthn ceremony type"),
+      WebAuthnError::ChallengeMismatch => write!(f, "WebAuthn challenge does not match"),
+      WebAuthnError::WrongOrigin => write!(f, "WebAuthn response came from another origin"),
+      WebAuthnError::WrongRelyingParty => write!(f, "WebAuthn response is for another relying party"),
+      WebAuthnError::UserNotVerified => write!(f, "Authenticator did not verify the user"),
+      WebAuthnError::UnsupportedAlgorithm => write!(f, "Only ES256 passkeys are supported"),
+      WebAuthnError::BadSignature => write!(f, "Invalid WebAuthn signature"),
+      WebAuthnError::CounterRegression => write!(f, "Authenticator sign count went backwards"),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+  #[serde(rename = "type")]
+  kind: String,
+  challenge: String,
+  origin: String,
+}
+
+struct AuthenticatorData<'a> {
+  flags: u8,
+  sign_count: u32,
+  /// Attested credential data and extensions, present during registration.
+  rest: &'a [u8],
+}
+
+/// A credential that passed registration, ready to be stored.
+pub struct RegisteredCredential {
+  pub credential_id: Vec<u8>,
+  /// SEC1 encoded P-256 public key.
+  pub public_key: Vec<u8>,
+  pub sign_count: u32,
+}
+
+pub fn b64(data: &[u8]) -> String {
+  base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn b64_decode(data: &str) -> Result<Vec<u8>, WebAuthnError> {
+  base64::decode_config(data, base64::URL_SAFE_NO_PAD).map_err(|_| WebAuthnError::Malformed)
+}
+
+/// Random base64url challenge for a registration or authentication ceremony.
+pub fn generate_challenge() -> String {
+  let mut challenge = [0u8; CHALLENGE_BYTES];
+  OsRng.fill_bytes(&mut challenge);
+  b64(&challenge)
+}
+
+/// The site passkeys are bound to.
+///
+/// Configured through `WEBAUTHN_RP_ID`, the domain passkeys are scoped to, and
+/// `WEBAUTHN_ORIGIN`, the origin of the frontend running the ceremonies.
+pub struct RelyingParty {
+  pub id: String,
+  pub name: String,
+  pub origin: String,
+}
+
+impl RelyingParty {
+  fn from_env() -> Self {
+    let id = env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string());
+    let origin = env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| {
+      format!(
+        "http://localhost:{}",
+        env::var("SERVER_PORT").unwrap_or_else(|_| "3000".to_string())
+      )
+    });
+    Self::new(id, origin)
+  }
+
+  pub fn new(id: String, origin: String) -> Self {
+    Self {
+      id,
+      name: "ipv8".to_string(),
+      origin,
+    }
+  }
+
+  fn check_client_data(&self, client_data_json: &[u8], kind: &str, challenge: &str) -> Result<(), WebAuthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| WebAuthnError::Malformed)?;
+    if client_data.kind != kind {
+      return Err(WebAuthnError::WrongType);
+    }
+    if client_data.challenge != challenge {
+      return Err(WebAuthnError::ChallengeMismatch);
+    }
+    if client_data.origin != self.origin {
+      return Err(WebAuthnError::WrongOrigin);
+    }
+    Ok(())
+  }
+
+  /// Parses authenticator data, requiring it to be scoped to this relying
+  /// party and the user to have been verified.
+  fn parse_authenticator_data<'a>(&self, data: &'a [u8]) -> Result<AuthenticatorData<'a>, WebAuthnError> {
+    if data.len() < 37 {
+      return Err(WebAuthnError::Malformed);
+    }
+    if data[..32] != Sha256::digest(self.id.as_bytes())[..] {
+      return Err(WebAuthnError::WrongRelyingParty);
+    }
+
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+      return Err(WebAuthnError::UserNotVerified);
+    }
+
+    Ok(AuthenticatorData {
+      flags,
+      sign_count: u32::from_be_bytes(data[33..37].try_into().unwrap()),
+      rest: &data[37..],
+    })
+  }
+
+  /// Verifies the response to a registration ceremony. Attestation statements
+  /// are not checked since registration asks for `none` attestation.
+  pub fn verify_registration(
+    &self,
+    challenge: &str,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+  ) -> Result<RegisteredCredential, WebAuthnError> {
+    self.check_client_data(client_data_json, "webauthn.create", challenge)?;
+
+    let attestation: Value = ciborium::de::from_reader(attestation_object).map_err(|_| WebAuthnError::Malformed)?;
+    let auth_data = attestation
+      .as_map()
+      .and_then(|map| map.iter().find(|(key, _)| key.as_text() == Some("authData")))
+      .and_then(|(_, value)| value.as_bytes())
+      .ok_or(WebAuthnError::Malformed)?;
+
+    let auth_data = self.parse_authenticator_data(auth_data)?;
+    if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 || auth_data.rest.len() < 18 {
+      return Err(WebAuthnError::Malformed);
+    }
+
+    // AAGUID (16 bytes), credential id length (2 bytes), credential id, then
+    // the COSE encoded public key.
+    let id_len = u16::from_be_bytes([auth_data.rest[16], auth_data.rest[17]]) as usize;
+    let credential_id = auth_data
+      .rest
+      .get(18..18 + id_len)
+      .ok_or(WebAuthnError::Malformed)?
+      .to_vec();
+    let mut cose_key = &auth_data.rest[18 + id_len..];
+    let cose_key: Value = ciborium::de::from_reader(&mut cose_key).map_err(|_| WebAuthnError::Malformed)?;
+
+    Ok(RegisteredCredential {
+      credential_id,
+      public_key: cose_to_sec1(&cose_key)?,
+      sign_count: auth_data.sign_count,
+    })
+  }
+
+  /// Verifies the response to an authentication ceremony against a stored
+  /// credential, returning the new sign count to store.
+  pub fn verify_assertion(
+    &self,
+    challenge: &str,
+    public_key: &[u8],
+    stored_sign_count: u32,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+  ) -> Result<u32, WebAuthnError> {
+    self.check_client_data(client_data_json, "webauthn.get", challenge)?;
+    let auth_data = self.parse_authenticator_data(authenticator_data)?;
+
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| WebAuthnError::Malformed)?;
+    let signature = Signature::from_der(signature).map_err(|_| WebAuthnError::Malformed)?;
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    key
+      .verify(&signed, &signature)
+      .map_err(|_| WebAuthnError::BadSignature)?;
+
+    // Authenticators that do not keep a counter always report 0. Otherwise the
+    // counter must grow, or the credential may have been cloned.
+    if (auth_data.sign_count != 0 || stored_sign_count != 0) && auth_data.sign_count <= stored_sign_count {
+      return Err(WebAuthnError::CounterRegression);
+    }
+
+    Ok(auth_data.sign_count)
+  }
+}
+
+fn cose_field(map: &[(Value, Value)], label: i64) -> Option<&Value> {
+  map
+    .iter()
+    .find(|(key, _)| key.as_integer() == Some(Integer::from(label)))
+    .map(|(_, value)| value)
+}
+
+/// Converts an EC2 COSE key to SEC1 form.
+fn cose_to_sec1(cose_key: &Value) -> Result<Vec<u8>, WebAuthnError> {
+  let map = cose_key.as_map().ok_or(WebAuthnError::Malformed)?;
+  let alg = cose_field(map, 3).and_then(Value::as_integer);
+  if alg != Some(Integer::from(COSE_ALG_ES256)) {
+    return Err(WebAuthnError::UnsupportedAlgorithm);
+  }
+
+  let x = cose_field(map, -2).and_then(Value::as_bytes);
+  let y = cose_field(map, -3).and_then(Value::as_bytes);
+  match (x, y) {
+    (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => {
+      let point = EncodedPoint::from_affine_coordinates(x.as_slice().into(), y.as_slice().into(), false);
+      VerifyingKey::from_encoded_point(&point)
+        .map(|key| key.to_encoded_point(false).as_bytes().to_vec())
+        .map_err(|_| WebAuthnError::Malformed)
+    },
+    _ => Err(WebAuthnError::Malformed),
+  }
+}
+
+/// In memory authenticator used to drive ceremonies in tests.
+#[cfg(test)]
+pub mod soft_authenticator {
+  use ciborium::value::Value;
+  use p256::ecdsa::signature::Signer;
+  use p256::ecdsa::{Signature, SigningKey};
+  use rand::rngs::OsRng;
+  use rand::RngCore;
+  use sha2::{Digest, Sha256};
+
+  use super::{RelyingParty, COSE_ALG_ES256};
+
+  pub struct SoftAuthenticator {
+    key: SigningKey,
+    pub credential_id: Vec<u8>,
+    pub sign_count: u32,
+    rp_id: String,
+    origin: String,
+  }
+
+  /// Client data and authenticator output for one ceremony.
+  pub struct Response {
+    pub client_data_json: Vec<u8>,
+    pub attestation_object: Vec<u8>,
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+  }
+
+  impl SoftAuthenticator {
+    pub fn new(rp: &RelyingParty) -> Self {
+      let mut credential_id = vec![0u8; 16];
+      OsRng.fill_bytes(&mut credential_id);
+      Self {
+        key: SigningKey::random(&mut OsRng),
+        credential_id,
+        sign_count: 0,
+        rp_id: rp.id.clone(),
+        origin: rp.origin.clone(),
+      }
+    }
+
+    fn client_data(&self, kind: &str, challenge: &str) -> Vec<u8> {
+      serde_json::to_vec(&serde_json::json!({
+        "type": kind,
+        "challenge": challenge,
+        "origin": self.origin,
+        "crossOrigin": false,
+      }))
+      .unwrap()
+    }
+
+    fn authenticator_data(&mut self, attested: bool) -> Vec<u8> {
+      self.sign_count += 1;
+      let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+      data.push(if attested { 0x45 } else { 0x05 });
+      data.extend_from_slice(&self.sign_count.to_be_bytes());
+      if attested {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = Value::Map(vec![
+          (Value::from(1), Value::from(2)),
+          (Value::from(3), Value::from(COSE_ALG_ES256)),
+          (Value::from(-1), Value::from(1)),
+          (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+          (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        data.extend_from_slice(&[0u8; 16]);
+        data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.credential_id);
+        ciborium::ser::into_writer(&cose_key, &mut data).unwrap();
+      }
+      data
+    }
+
+    pub fn register(&mut self, challenge: &str) -> Response {
+      let client_data_json = self.client_data("webauthn.create", challenge);
+      let attestation = Value::Map(vec![
+        (Value::from("fmt"), Value::from("none")),
+        (Value::from("attStmt"), Value::Map(vec![])),
+        (Value::from("authData"), Value::Bytes(self.authenticator_data(true))),
+      ]);
+      let mut attestation_object = Vec::new();
+      ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+      Response {
+        client_data_json,
+        attestation_object,
+        authenticator_data: Vec::new(),
+        signature: Vec::new(),
+      }
+    }
+
+    pub fn assert(&mut self, challenge: &str) -> Response {
+      let client_data_json = self.client_data("webauthn.get", challenge);
+      let authenticator_data = self.authenticator_data(false);
+      let mut signed = authenticator_data.clone();
+      signed.extend_from_slice(&Sha256::digest(&client_data_json));
+      let signature: Signature = self.key.sign(&signed);
+      Response {
+        client_data_json,
+        attestation_object: Vec::new(),
+        authenticator_data,
+        signature: signature.to_der().as_bytes().to_vec(),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::soft_authenticator::SoftAuthenticator;
+  use super::{generate_challenge, RelyingParty, WebAuthnError};
+
+  fn rp() -> RelyingParty {
+    RelyingParty::new("auth.example".to_string(), "https://auth.example".to_string())
+  }
+
+  #[test]
+  fn registers_and_authenticates() {
+    let rp = rp();
+    let mut authenticator = SoftAuthenticator::new(&rp);
+    let challenge = generate_challenge();
+    let response = authenticator.register(&challenge);
+    let credential = rp
+      .verify_registration(&challenge, &response.client_data_json, &response.attestation_object)
+      .unwrap();
+    assert_eq!(credential.credential_id, authenticator.credential_id);
+    assert_eq!(credential.sign_count, 1);
+
+    let challenge = generate_challenge();
+    let response = authenticator.assert(&challenge);
+    let sign_count = rp
+      .verify_assertion(
+        &challenge,
+        &credential.public_key,
+        credential.sign_count,
+        &response.client_data_json,
+        &response.authenticator_data,
+        &response.signature,
+      )
+      .unwrap();
+    assert_eq!(sign_count, 2);
+  }
+
+  #[test]
+  fn rejects_other_challenge_and_origin() {
+    let rp = rp();
+    let mut authenticator = SoftAuthenticator::new(&rp);
+    let response = authenticator.register(&generate_challenge());
+    assert_eq!(
+      rp.verify_registration(
+        &generate_challenge(),
+        &response.client_data_json,
+        &response.attestation_object
+      )
+      .err(),
+      Some(WebAuthnError::ChallengeMismatch)
+    );
+
+    let phishing = RelyingParty::new("auth.example".to_string(), "https://auth.example.evil".to_string());
+    let challenge = generate_challenge();
+    let response = SoftAuthenticator::new(&phishing).register(&challenge);
+    assert_eq!(
+      rp.verify_registration(&challenge, &response.client_data_json, &response.attestation_object)
+        .err(),
+      Some(WebAuthnError::WrongOrigin)
+    );
+  }
+
+  #[test]
+  fn rejects_sign_count_regression() {
+    let rp = rp();
+    let mut authenticator = SoftAuthenticator::new(&rp);
+    let challenge = generate_challenge();
+    let response = authenticator.register(&challenge);
+    let credential = rp
+      .verify_registration(&challenge, &response.client_data_json, &response.attestation_object)
+      .unwrap();
+
+    let challenge = generate_challenge();
+    let response = authenticator.assert(&challenge);
+    assert_eq!(
+      rp.verify_assertion(
+        &challenge,
+        &credential.public_key,
+        10,
+        &response.client_data_json,
+        &response.authenticator_data,
+        &response.signature,
+      ),
+      Err(WebAuthnError::CounterRegression)
+    );
+  }
+}